@@ -0,0 +1,488 @@
+// Alert rules: conditions on market data, positions and message flow declared in
+// a JSON file, evaluated against the client state and dispatched when they fire.
+
+use crate::state::{field, ClientState, SharedState, POSITION_ID_FIELDS, POSITION_SIZE_FIELDS};
+use crate::webhook::Webhooks;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+// How often the rules are evaluated
+const EVALUATION_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Figure a rule watches
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    BestBid,
+    BestAsk,
+    Mid,
+    /// Best ask minus best bid, in basis points of the mid price
+    SpreadBps,
+    /// Signed size of the position in the symbol
+    PositionSize,
+    /// Absolute notional of the position in the symbol, or of all positions without a symbol
+    PositionNotional,
+    /// Seconds since the last message was received, or since the client started
+    SilenceSecs,
+}
+
+impl Metric {
+    fn needs_symbol(self) -> bool {
+        !matches!(self, Metric::PositionNotional | Metric::SilenceSecs)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Above,
+    Below,
+}
+
+/// Where a firing or resolved alert is sent
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Log,
+    Webhook,
+    /// Control socket connections running `watch-alerts`
+    Control,
+}
+
+fn default_targets() -> Vec<Target> {
+    vec![Target::Log, Target::Control]
+}
+
+/// One declared condition, e.g. `{"name": "btc-bid", "metric": "best_bid", "symbol": "BTC-USD-PERPETUAL",
+/// "condition": "below", "threshold": 40000}`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub metric: Metric,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    pub condition: Condition,
+    pub threshold: f64,
+    /// How long the condition must hold before the alert fires
+    #[serde(default)]
+    pub for_secs: u64,
+    /// Least time between two firings of the alert
+    #[serde(default)]
+    pub cooldown_secs: u64,
+    /// How far back past the threshold the value must go for a firing alert to resolve
+    #[serde(default)]
+    pub hysteresis: f64,
+    #[serde(default = "default_targets")]
+    pub notify: Vec<Target>,
+}
+
+impl Rule {
+    // Current value of the watched figure, `None` while it is unknown
+    fn value(&self, state: &ClientState, now: DateTime<Utc>) -> Option<f64> {
+        let price = |level: Option<&crate::state::Level>| level.and_then(|level| level.price.parse::<f64>().ok());
+        let book = self.symbol.as_ref().and_then(|symbol| state.books.get(symbol));
+        let top = book.map(|book| (price(book.buy.first()), price(book.sell.first())));
+        match self.metric {
+            Metric::BestBid => top?.0,
+            Metric::BestAsk => top?.1,
+            Metric::Mid => state.mid(self.symbol.as_deref()?),
+            Metric::SpreadBps => {
+                let (bid, ask) = (top?.0?, top?.1?);
+                Some((ask - bid) / ((ask + bid) / 2.0) * 10_000.0)
+            }
+            // Holding no position in the symbol counts as a size of zero
+            Metric::PositionSize => Some(state.position_sizes()?.get(self.symbol.as_deref()?).copied().unwrap_or(0.0)),
+            Metric::PositionNotional => {
+                let positions = state.positions.as_ref()?.items.as_array()?;
+                positions.iter()
+                    .filter(|p| self.symbol.is_none() || field(p, &POSITION_ID_FIELDS) == self.symbol)
+                    .map(|p| {
+                        if let Some(notional) = field(p, &["notional"]).and_then(|n| n.parse::<f64>().ok()) {
+                            return Some(notional.abs());
+                        }
+                        let size = field(p, &POSITION_SIZE_FIELDS)?.parse::<f64>().ok()?;
+                        let price = field(p, &["mark_price"]).and_then(|m| m.parse().ok())
+                            .or_else(|| state.mid(&field(p, &POSITION_ID_FIELDS)?))?;
+                        Some((size * price).abs())
+                    })
+                    .sum()
+            }
+            Metric::SilenceSecs => {
+                let since = state.last_message_at.unwrap_or(state.started_at);
+                Some((now - since).num_milliseconds() as f64 / 1000.0)
+            }
+        }
+    }
+
+    fn breached(&self, value: f64) -> bool {
+        match self.condition {
+            Condition::Above => value > self.threshold,
+            Condition::Below => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f64) -> bool {
+        match self.condition {
+            Condition::Above => value <= self.threshold - self.hysteresis,
+            Condition::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+}
+
+/// Read and check the rules of a JSON file holding an array of rules
+pub fn load_rules(path: &Path) -> Result<Vec<Rule>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read alert rules {}: {}", path.display(), e))?;
+    let rules: Vec<Rule> = serde_json::from_str(&text).map_err(|e| format!("Invalid alert rules {}: {}", path.display(), e))?;
+    let mut names = HashSet::new();
+    for rule in &rules {
+        if !names.insert(rule.name.as_str()) {
+            return Err(format!("Alert rule '{}' is declared twice", rule.name));
+        }
+        if rule.metric.needs_symbol() && rule.symbol.is_none() {
+            return Err(format!("Alert rule '{}' needs a symbol for {}", rule.name, rule.metric.name()));
+        }
+        if rule.hysteresis < 0.0 || !rule.threshold.is_finite() {
+            return Err(format!("Alert rule '{}' needs a finite threshold and a hysteresis of zero or more", rule.name));
+        }
+    }
+    Ok(rules)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Ok,
+    /// Condition holding since, waiting for `for_secs` or the cooldown
+    Pending(DateTime<Utc>),
+    Firing(DateTime<Utc>),
+}
+
+struct RuleState {
+    rule: Rule,
+    status: Status,
+    last_fired: Option<DateTime<Utc>>,
+    value: Option<f64>,
+}
+
+/// Evaluates the rules, remembering which alerts are pending or firing
+pub struct Engine {
+    rules: Vec<RuleState>,
+}
+
+impl Engine {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Engine { rules: rules.into_iter().map(|rule| RuleState { rule, status: Status::Ok, last_fired: None, value: None }).collect() }
+    }
+
+    /// Evaluate `rules` from now on, returns whether they differ from those in effect.
+    /// Unchanged rules keep their status, so a firing alert is not notified again.
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> bool {
+        if self.rules.iter().map(|entry| &entry.rule).eq(rules.iter()) {
+            return false;
+        }
+        let mut previous = std::mem::take(&mut self.rules);
+        self.rules = rules.into_iter().map(|rule| match previous.iter().position(|entry| entry.rule == rule) {
+            Some(index) => previous.swap_remove(index),
+            None => RuleState { rule, status: Status::Ok, last_fired: None, value: None },
+        }).collect();
+        true
+    }
+
+    /// Evaluate every rule at `now`, returning the alerts which fired or resolved
+    pub fn evaluate(&mut self, state: &ClientState, now: DateTime<Utc>) -> Vec<(Vec<Target>, Value)> {
+        let mut alerts = Vec::new();
+        for entry in &mut self.rules {
+            let rule = &entry.rule;
+            entry.value = rule.value(state, now);
+            let Some(value) = entry.value else {
+                // Unknown values neither start nor resolve an alert
+                if matches!(entry.status, Status::Pending(_)) {
+                    entry.status = Status::Ok;
+                }
+                continue;
+            };
+            let breached = rule.breached(value);
+            let alert = |status: &str, since: DateTime<Utc>| json!({
+                "rule": rule.name,
+                "status": status,
+                "metric": rule.metric.name(),
+                "symbol": rule.symbol,
+                "condition": rule.condition.name(),
+                "threshold": rule.threshold,
+                "value": value,
+                "since": since,
+                "at": now,
+            });
+
+            match entry.status {
+                Status::Ok if breached => entry.status = Status::Pending(now),
+                Status::Pending(_) if !breached => entry.status = Status::Ok,
+                Status::Firing(since) if rule.cleared(value) => {
+                    entry.status = Status::Ok;
+                    alerts.push((rule.notify.clone(), alert("resolved", since)));
+                }
+                _ => {}
+            }
+            if let Status::Pending(since) = entry.status {
+                let held = (now - since).num_seconds() >= rule.for_secs as i64;
+                let cooled = entry.last_fired.map_or(true, |fired| (now - fired).num_seconds() >= rule.cooldown_secs as i64);
+                if held && cooled {
+                    entry.status = Status::Firing(now);
+                    entry.last_fired = Some(now);
+                    alerts.push((rule.notify.clone(), alert("firing", since)));
+                }
+            }
+        }
+        alerts
+    }
+
+    /// Each rule with its status and the value last seen
+    pub fn status(&self) -> Value {
+        self.rules.iter().map(|entry| {
+            let (status, since) = match entry.status {
+                Status::Ok => ("ok", None),
+                Status::Pending(since) => ("pending", Some(since)),
+                Status::Firing(since) => ("firing", Some(since)),
+            };
+            json!({
+                "rule": entry.rule.name,
+                "status": status,
+                "since": since,
+                "value": entry.value,
+                "threshold": entry.rule.threshold,
+                "last_fired": entry.last_fired,
+            })
+        }).collect()
+    }
+}
+
+impl Metric {
+    fn name(self) -> &'static str {
+        match self {
+            Metric::BestBid => "best_bid",
+            Metric::BestAsk => "best_ask",
+            Metric::Mid => "mid",
+            Metric::SpreadBps => "spread_bps",
+            Metric::PositionSize => "position_size",
+            Metric::PositionNotional => "position_notional",
+            Metric::SilenceSecs => "silence_secs",
+        }
+    }
+}
+
+impl Condition {
+    fn name(self) -> &'static str {
+        match self {
+            Condition::Above => "above",
+            Condition::Below => "below",
+        }
+    }
+}
+
+/// Handle to the running rules: their status and subscriptions to their alerts, cheap to clone
+#[derive(Clone)]
+pub struct Alerts {
+    engine: Arc<Mutex<Engine>>,
+    subscribers: Arc<Mutex<Vec<Sender<Value>>>>,
+}
+
+impl Alerts {
+    /// Evaluate `rules` against `state` from a background thread
+    pub fn start(rules: Vec<Rule>, state: SharedState, webhooks: Option<Webhooks>) -> std::io::Result<Self> {
+        let count = rules.len();
+        let alerts = Alerts { engine: Arc::new(Mutex::new(Engine::new(rules))), subscribers: Arc::default() };
+        let running = alerts.clone();
+        thread::Builder::new().name("alerts".to_string()).spawn(move || loop {
+            let fired = running.engine.lock().unwrap().evaluate(&state.lock(), Utc::now());
+            for (targets, alert) in fired {
+                let text = |key: &str| alert[key].as_str().unwrap_or_default().to_string();
+                state.record_event(format!("Alert {} {}: {} is {}", text("rule"), text("status"), text("metric"), alert["value"]));
+                running.dispatch(&targets, alert, webhooks.as_ref());
+            }
+            thread::sleep(EVALUATION_INTERVAL);
+        })?;
+        info!(event = "alerts_started", rules = count, "Evaluating {} alert rules", count);
+        Ok(alerts)
+    }
+
+    /// Receive every alert dispatched to control subscribers from now on
+    pub fn subscribe(&self) -> Receiver<Value> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn status(&self) -> Value {
+        self.engine.lock().unwrap().status()
+    }
+
+    /// Evaluate `rules` from now on, see `Engine::replace_rules`
+    pub fn replace_rules(&self, rules: Vec<Rule>) -> bool {
+        let count = rules.len();
+        let replaced = self.engine.lock().unwrap().replace_rules(rules);
+        if replaced {
+            info!(event = "alerts_reloaded", rules = count, "Evaluating {} alert rules", count);
+        }
+        replaced
+    }
+
+    /// Names of the rules in effect
+    pub fn rule_names(&self) -> Vec<String> {
+        self.engine.lock().unwrap().rules.iter().map(|entry| entry.rule.name.clone()).collect()
+    }
+
+    fn dispatch(&self, targets: &[Target], alert: Value, webhooks: Option<&Webhooks>) {
+        for target in targets {
+            match target {
+                Target::Log if alert["status"] == "firing" => warn!(event = "alert_firing", rule = %alert["rule"],
+                    "Alert {} firing: {} {} is {} {}", alert["rule"], alert["metric"], alert["symbol"], alert["value"], alert["threshold"]),
+                Target::Log => info!(event = "alert_resolved", rule = %alert["rule"], "Alert {} resolved: {}", alert["rule"], alert),
+                Target::Webhook => match webhooks {
+                    Some(webhooks) => webhooks.alert(alert.clone()),
+                    None => warn!(event = "alert_undelivered", rule = %alert["rule"], "Alert {} names the webhook target but no PT_WEBHOOK_URL is set", alert["rule"]),
+                },
+                // Subscribers which went away are dropped
+                Target::Control => self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(alert.clone()).is_ok()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    fn rule(json: Value) -> Rule {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn book(state: &SharedState, bid: &str, ask: &str) {
+        state.record_message(&format!(
+            r#"{{"mbp_snapshot":{{"symbol":"BTC-USD","buy":[{{"price":"{}","quantity":"1"}}],"sell":[{{"price":"{}","quantity":"1"}}]}}}}"#,
+            bid, ask,
+        ));
+    }
+
+    fn statuses(alerts: &[(Vec<Target>, Value)]) -> Vec<&str> {
+        alerts.iter().map(|(_, alert)| alert["status"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_fires_after_holding_and_resolves_past_hysteresis() {
+        let state = SharedState::new();
+        let mut engine = Engine::new(vec![rule(json!({
+            "name": "wide", "metric": "spread_bps", "symbol": "BTC-USD", "condition": "above",
+            "threshold": 50, "for_secs": 10, "hysteresis": 20,
+        }))]);
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+
+        book(&state, "100", "100.1");
+        assert!(engine.evaluate(&state.lock(), at(0)).is_empty());
+        book(&state, "100", "101");
+        assert!(engine.evaluate(&state.lock(), at(1)).is_empty());
+        let fired = engine.evaluate(&state.lock(), at(11));
+        assert_eq!(statuses(&fired), ["firing"]);
+        assert_eq!(fired[0].1["metric"], "spread_bps");
+        assert_eq!(fired[0].1["since"], json!(at(1)));
+        assert_eq!(fired[0].0, [Target::Log, Target::Control]);
+        assert_eq!(engine.status()[0]["status"], "firing");
+
+        // 40 bps is back under the threshold but not past the hysteresis
+        book(&state, "100", "100.4");
+        assert!(engine.evaluate(&state.lock(), at(12)).is_empty());
+        book(&state, "100", "100.2");
+        assert_eq!(statuses(&engine.evaluate(&state.lock(), at(13))), ["resolved"]);
+        assert_eq!(engine.status()[0]["status"], "ok");
+    }
+
+    #[test]
+    fn test_cooldown_holds_back_refiring() {
+        let state = SharedState::new();
+        let mut engine = Engine::new(vec![rule(json!({
+            "name": "low-bid", "metric": "best_bid", "symbol": "BTC-USD", "condition": "below",
+            "threshold": 100, "cooldown_secs": 60, "notify": ["webhook"],
+        }))]);
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+
+        book(&state, "99", "101");
+        assert_eq!(statuses(&engine.evaluate(&state.lock(), at(0))), ["firing"]);
+        book(&state, "100", "101");
+        assert_eq!(statuses(&engine.evaluate(&state.lock(), at(1))), ["resolved"]);
+        book(&state, "99", "101");
+        assert!(engine.evaluate(&state.lock(), at(2)).is_empty());
+        assert_eq!(engine.status()[0]["status"], "pending");
+        let fired = engine.evaluate(&state.lock(), at(60));
+        assert_eq!(statuses(&fired), ["firing"]);
+        assert_eq!(fired[0].0, [Target::Webhook]);
+    }
+
+    #[test]
+    fn test_replaced_rules_keep_the_state_of_unchanged_ones() {
+        let state = SharedState::new();
+        let low = rule(json!({"name": "low-bid", "metric": "best_bid", "symbol": "BTC-USD", "condition": "below", "threshold": 100}));
+        let wide = rule(json!({"name": "wide", "metric": "spread_bps", "symbol": "BTC-USD", "condition": "above", "threshold": 50}));
+        let mut engine = Engine::new(vec![low.clone(), wide.clone()]);
+        let start = Utc::now();
+        book(&state, "99", "101");
+        assert_eq!(statuses(&engine.evaluate(&state.lock(), start)), ["firing", "firing"]);
+
+        assert!(!engine.replace_rules(vec![low.clone(), wide.clone()]));
+        let mut wider = wide;
+        wider.threshold = 500.0;
+        assert!(engine.replace_rules(vec![wider, low]));
+        assert_eq!(engine.status()[0]["status"], "ok");
+        assert_eq!(engine.status()[1]["status"], "firing");
+        assert!(engine.evaluate(&state.lock(), start + TimeDelta::seconds(1)).is_empty());
+    }
+
+    #[test]
+    fn test_position_and_silence_values() {
+        let state = SharedState::new();
+        book(&state, "99", "101");
+        state.record_message(r#"{"position_summary":{"positions":[
+            {"symbol":"BTC-USD","size":"-2"},
+            {"symbol":"ETH-USD","quantity":"3","mark_price":"10"},
+            {"symbol":"SOL-USD","size":"5","notional":"-7"}]}}"#);
+        let guard = state.lock();
+        let now = guard.last_message_at.unwrap() + TimeDelta::seconds(30);
+        let value = |json: Value| rule(json).value(&guard, now);
+
+        assert_eq!(value(json!({"name": "a", "metric": "position_size", "symbol": "BTC-USD", "condition": "below", "threshold": 0})), Some(-2.0));
+        assert_eq!(value(json!({"name": "a", "metric": "position_size", "symbol": "XRP-USD", "condition": "below", "threshold": 0})), Some(0.0));
+        assert_eq!(value(json!({"name": "a", "metric": "position_notional", "symbol": "BTC-USD", "condition": "above", "threshold": 0})), Some(200.0));
+        assert_eq!(value(json!({"name": "a", "metric": "position_notional", "condition": "above", "threshold": 0})), Some(237.0));
+        assert_eq!(value(json!({"name": "a", "metric": "mid", "symbol": "BTC-USD", "condition": "above", "threshold": 0})), Some(100.0));
+        assert_eq!(value(json!({"name": "a", "metric": "best_ask", "symbol": "ETH-USD", "condition": "above", "threshold": 0})), None);
+        assert_eq!(value(json!({"name": "a", "metric": "silence_secs", "condition": "above", "threshold": 0})), Some(30.0));
+    }
+
+    #[test]
+    fn test_load_rules_rejects_bad_files() {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-alerts-{}.json", std::process::id()));
+        let load = |text: &str| {
+            std::fs::write(&path, text).unwrap();
+            load_rules(&path)
+        };
+
+        let rules = load(r#"[{"name": "quiet", "metric": "silence_secs", "condition": "above", "threshold": 30}]"#).unwrap();
+        assert_eq!(rules[0].notify, [Target::Log, Target::Control]);
+        assert!(load(r#"[{"name": "bid", "metric": "best_bid", "condition": "below", "threshold": 1}]"#).unwrap_err().contains("needs a symbol"));
+        assert!(load(r#"[{"name": "q", "metric": "silence_secs", "condition": "above", "threshold": 1},
+            {"name": "q", "metric": "silence_secs", "condition": "above", "threshold": 2}]"#).unwrap_err().contains("declared twice"));
+        assert!(load(r#"[{"name": "q", "metric": "silence_secs", "condition": "above", "threshold": 1, "for": 3}]"#).is_err());
+        assert!(load(r#"[{"name": "q", "metric": "volume", "condition": "above", "threshold": 1}]"#).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,19 +1,178 @@
-use std::env::var;
-use log::info;
+use crate::limits::RunLimits;
+use crate::proxy::ProxyConfig;
+use crate::tls::TlsConfig;
+use crate::transport::TransportConfig;
+
+use chrono::Utc;
+use std::collections::HashMap;
+use std::env::{self, VarError};
+use tracing::info;
+use tungstenite::http::{HeaderName, HeaderValue};
+
+// Headers the client sets itself for the WebSocket upgrade and authentication
+const RESERVED_HEADERS: [&str; 9] = [
+    "host", "connection", "upgrade", "sec-websocket-key", "sec-websocket-version",
+    "sec-websocket-extensions", "x-power-trade", "x-correlation-id", "user-agent",
+];
+
+/// Configuration variables as the client sees them: the process environment with
+/// the environment file on top. Built at startup and again on each reload, so the
+/// process environment itself never changes while other threads run.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    values: HashMap<String, String>,
+}
+
+impl Settings {
+    /// The process environment, variables which are not valid Unicode left out
+    pub fn from_env() -> Self {
+        let values = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Settings { values }
+    }
+
+    /// Value of `name`, as `std::env::var` would return it
+    pub fn var(&self, name: &str) -> Result<String, VarError> {
+        self.values.get(name).cloned().ok_or(VarError::NotPresent)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.values.remove(name);
+    }
+
+    /// Value of `name`, unset when empty
+    pub fn value(&self, name: &str) -> Option<String> {
+        self.var(name).ok().filter(|v| !v.trim().is_empty())
+    }
+
+    /// Unsigned number held by `name`, `default` when unset
+    pub fn number(&self, name: &str, default: u64) -> Result<u64, String> {
+        self.value(name).map_or(Ok(default), |v| v.trim().parse::<u64>().map_err(|e| format!("Error parsing {}: {}", name, e)))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub server_url: String,
     pub api_key: String,
     pub api_secret: String,
-    pub epoch_count: u32,
+    pub limits: RunLimits,
     pub sleep_duration: u64,
     pub max_retries: u32,
+    pub ping_interval: u64,
+    pub ping_warn_ms: u64,
+    pub proxy: Option<ProxyConfig>,
+    pub tls: TlsConfig,
+    pub transport: TransportConfig,
+    pub user_agent: String,
+    /// Extra headers sent with the WebSocket handshake
+    pub headers: Vec<(String, String)>,
+    /// Prefix of the `X-Correlation-Id` sent with each connection
+    pub correlation_id: String,
 }
 
 impl Config {
+    /// User-Agent naming this client and its build
+    pub fn default_user_agent() -> String {
+        format!("client-rust-ws/{} (built {})", env!("CARGO_PKG_VERSION"), crate::build_date::build_date())
+    }
+
+    // Parse `Name: value` pairs separated by `;`
+    fn parse_headers(value: &str) -> Result<Vec<(String, String)>, String> {
+        value.split(';')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .map(|header| {
+                let (name, value) = header.split_once(':')
+                    .ok_or_else(|| format!("Invalid header {}: expected Name: value", header))?;
+                let (name, value) = (name.trim(), value.trim());
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+                HeaderValue::from_str(value)
+                    .map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+                if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+                    return Err(format!("Header {} is set by the client and cannot be overridden", name));
+                }
+                Ok((name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    /// Feeds subscribed to through the `type[]` parameters of the server URL
+    pub fn subscriptions(&self) -> Vec<String> {
+        Self::subscriptions_of(&self.server_url)
+    }
+
+    /// Feeds subscribed to by a server URL
+    pub fn subscriptions_of(url: &str) -> Vec<String> {
+        Self::query_parts(url).into_iter()
+            .filter(|(_, is_type)| *is_type)
+            .flat_map(|(part, _)| Self::feeds(part))
+            .collect()
+    }
+
+    /// Server URL with `feed` added to or removed from the subscribed feeds
+    pub fn with_subscription(&self, feed: &str, subscribe: bool) -> Result<String, String> {
+        if feed.is_empty() || !feed.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid feed name '{}'", feed));
+        }
+        let mut feeds = self.subscriptions();
+        match (subscribe, feeds.iter().any(|f| f == feed)) {
+            (true, true) => return Err(format!("Already subscribed to {}", feed)),
+            (false, false) => return Err(format!("Not subscribed to {}", feed)),
+            (true, false) => feeds.push(feed.to_string()),
+            (false, true) => feeds.retain(|f| f != feed),
+        }
+
+        // Other parameters keep their original form, the feeds go where the first type[] was
+        let (base, _) = self.server_url.split_once('?').unwrap_or((&self.server_url, ""));
+        let mut parts = Vec::new();
+        let mut feeds_added = false;
+        for (part, is_type) in Self::query_parts(&self.server_url) {
+            if !is_type {
+                parts.push(part.to_string());
+            } else if !feeds_added {
+                parts.extend(feeds.iter().map(|feed| format!("type[]={}", feed)));
+                feeds_added = true;
+            }
+        }
+        if !feeds_added {
+            parts.extend(feeds.iter().map(|feed| format!("type[]={}", feed)));
+        }
+        Ok(match parts.is_empty() {
+            true => base.to_string(),
+            false => format!("{}?{}", base, parts.join("&")),
+        })
+    }
+
+    // Raw `name=value` parts of the URL query, flagging the `type[]` ones
+    fn query_parts(url: &str) -> Vec<(&str, bool)> {
+        let query = url.split_once('?').map_or("", |(_, query)| query);
+        query.split('&')
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let name = part.split('=').next().unwrap_or_default();
+                let decoded = url::form_urlencoded::parse(name.as_bytes()).next().map(|(name, _)| name.into_owned());
+                (part, decoded.as_deref() == Some("type[]"))
+            })
+            .collect()
+    }
+
+    // Feeds of one `type[]=a,b` part
+    fn feeds(part: &str) -> Vec<String> {
+        url::form_urlencoded::parse(part.as_bytes())
+            .flat_map(|(_, value)| value.split(',').map(str::to_string).collect::<Vec<_>>())
+            .filter(|feed| !feed.is_empty())
+            .collect()
+    }
+
     /// Mask sensitive string for logging (show first 4 and last 4 characters)
-    fn mask_sensitive(value: &str) -> String {
+    pub fn mask_sensitive(value: &str) -> String {
         if value.len() <= 8 {
             "****".to_string()
         } else {
@@ -23,7 +182,9 @@ impl Config {
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, String> {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let var = |name: &str| settings.var(name);
+
         let server_url = var("PT_SERVER_URL")
             .map_err(|_| "PT_SERVER_URL must be set in the environment or .env file")?;
 
@@ -33,10 +194,7 @@ impl Config {
         let api_secret = var("PT_API_SECRET")
             .map_err(|_| "PT_API_SECRET must be set in the environment or .env file")?;
 
-        let epoch_count = var("PT_EPOCH_COUNT")
-            .map_err(|e| format!("Error loading PT_EPOCH_COUNT: {}", e))
-            .and_then(|v| v.parse::<u32>()
-                .map_err(|e| format!("Error parsing PT_EPOCH_COUNT: {}", e)))?;
+        let limits = RunLimits::from_settings(settings)?;
 
         let sleep_duration = var("PT_WS_SLEEP")
             .map_err(|e| format!("Error reading PT_WS_SLEEP: {}", e))
@@ -48,16 +206,52 @@ impl Config {
             .parse::<u32>()
             .unwrap_or(5);
 
-        info!("Configuration loaded: server={}, api_key={}, max_retries={}",
-              server_url, Self::mask_sensitive(&api_key), max_retries);
+        let ping_interval = var("PT_PING_INTERVAL")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<u64>()
+            .unwrap_or(30);
+
+        let ping_warn_ms = var("PT_PING_WARN_MS")
+            .unwrap_or_else(|_| "500".to_string())
+            .parse::<u64>()
+            .unwrap_or(500);
+
+        let proxy = ProxyConfig::from_settings(settings)?;
+        let tls = TlsConfig::from_settings(settings)?;
+        let transport = TransportConfig::from_settings(settings)?;
+
+        let user_agent = var("PT_USER_AGENT").unwrap_or_else(|_| Self::default_user_agent());
+        HeaderValue::from_str(&user_agent).map_err(|e| format!("Invalid PT_USER_AGENT: {}", e))?;
+
+        let headers = var("PT_WS_HEADERS")
+            .map_or(Ok(Vec::new()), |value| Self::parse_headers(&value))?;
+
+        // Defaults to an id unique to this run, connections append their own id
+        let correlation_id = var("PT_CORRELATION_ID").unwrap_or_else(|_| {
+            format!("{:x}-{:x}", Utc::now().timestamp_millis(), std::process::id())
+        });
+        HeaderValue::from_str(&correlation_id).map_err(|e| format!("Invalid PT_CORRELATION_ID: {}", e))?;
+
+        info!("Configuration loaded: server={}, api_key={}, max_retries={}, run_limits={}, proxy={}, tls_pins={}",
+              server_url, Self::mask_sensitive(&api_key), max_retries, limits,
+              proxy.as_ref().map_or("none".to_string(), |p| format!("{}:{}", p.host, p.port)),
+              tls.pins.len());
 
         Ok(Config {
             server_url,
             api_key,
             api_secret,
-            epoch_count,
+            limits,
             sleep_duration,
             max_retries,
+            ping_interval,
+            ping_warn_ms,
+            proxy,
+            tls,
+            transport,
+            user_agent,
+            headers,
+            correlation_id,
         })
     }
 }
@@ -65,7 +259,13 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transport::AddressFamily;
     use std::env;
+    use std::time::Duration;
+
+    fn from_env() -> Result<Config, String> {
+        Config::from_settings(&Settings::from_env())
+    }
 
     // Helper to set up test environment
     fn setup_test_env() {
@@ -82,9 +282,61 @@ mod tests {
         env::remove_var("PT_SERVER_URL");
         env::remove_var("PT_API_KEY");
         env::remove_var("PT_API_SECRET");
-        env::remove_var("PT_EPOCH_COUNT");
+        for name in crate::limits::LIMIT_VARS {
+            env::remove_var(name);
+        }
         env::remove_var("PT_WS_SLEEP");
         env::remove_var("PT_MAX_RETRIES");
+        env::remove_var("PT_PING_INTERVAL");
+        env::remove_var("PT_PING_WARN_MS");
+        env::remove_var("PT_PROXY");
+        env::remove_var("PT_NO_PROXY");
+        env::remove_var("PT_TLS_CA_FILE");
+        env::remove_var("PT_TLS_CLIENT_CERT");
+        env::remove_var("PT_TLS_CLIENT_KEY");
+        env::remove_var("PT_TLS_PINS");
+        env::remove_var("PT_CONNECT_TIMEOUT_MS");
+        env::remove_var("PT_ADDRESS_FAMILY");
+        env::remove_var("PT_TCP_KEEPALIVE");
+        env::remove_var("PT_WS_MAX_MESSAGE_SIZE");
+        env::remove_var("PT_USER_AGENT");
+        env::remove_var("PT_WS_HEADERS");
+        env::remove_var("PT_CORRELATION_ID");
+    }
+
+    #[test]
+    fn test_subscriptions_from_url_query() {
+        let mut config = crate::test_support::test_config(
+            "wss://example.com/v1/feeds/multi_leg?type[]=all_multi_leg,multi_leg_mbp_snapshot&mbp_period=1&type%5B%5D=cycle");
+        assert_eq!(config.subscriptions(), ["all_multi_leg", "multi_leg_mbp_snapshot", "cycle"]);
+
+        config.server_url = config.with_subscription("cycle", false).unwrap();
+        assert_eq!(config.server_url, "wss://example.com/v1/feeds/multi_leg?type[]=all_multi_leg&type[]=multi_leg_mbp_snapshot&mbp_period=1");
+        config.server_url = config.with_subscription("mbp_snapshot", true).unwrap();
+        assert_eq!(config.subscriptions(), ["all_multi_leg", "multi_leg_mbp_snapshot", "mbp_snapshot"]);
+
+        assert!(config.with_subscription("mbp_snapshot", true).unwrap_err().contains("Already subscribed"));
+        assert!(config.with_subscription("trades", false).unwrap_err().contains("Not subscribed"));
+        assert!(config.with_subscription("a&b", true).unwrap_err().contains("Invalid feed"));
+
+        let config = crate::test_support::test_config("wss://example.com/v1/position_summary");
+        assert!(config.subscriptions().is_empty());
+        assert_eq!(config.with_subscription("mbp_snapshot", true).unwrap(), "wss://example.com/v1/position_summary?type[]=mbp_snapshot");
+    }
+
+    #[test]
+    fn test_settings_values_and_numbers() {
+        let mut settings = Settings::default();
+        settings.set("PT_SETTINGS_TEST_BLANK", " ");
+        settings.set("PT_SETTINGS_TEST_COUNT", " 12 ");
+        settings.set("PT_SETTINGS_TEST_BAD", "twelve");
+        assert_eq!(settings.value("PT_SETTINGS_TEST_BLANK"), None);
+        assert_eq!(settings.var("PT_SETTINGS_TEST_BLANK").unwrap(), " ");
+        assert_eq!(settings.number("PT_SETTINGS_TEST_COUNT", 5), Ok(12));
+        assert_eq!(settings.number("PT_SETTINGS_TEST_BLANK", 5), Ok(5));
+        assert!(settings.number("PT_SETTINGS_TEST_BAD", 5).unwrap_err().contains("PT_SETTINGS_TEST_BAD"));
+        settings.remove("PT_SETTINGS_TEST_COUNT");
+        assert!(settings.var("PT_SETTINGS_TEST_COUNT").is_err());
     }
 
     #[test]
@@ -115,14 +367,14 @@ mod tests {
     fn test_from_env_success() {
         setup_test_env();
 
-        let config = Config::from_env();
+        let config = from_env();
         assert!(config.is_ok());
 
         let config = config.unwrap();
         assert_eq!(config.server_url, "wss://test.example.com");
         assert_eq!(config.api_key, "test_api_key_12345");
         assert_eq!(config.api_secret, "test_secret_67890");
-        assert_eq!(config.epoch_count, 10);
+        assert_eq!(config.limits.epochs, Some(10));
         assert_eq!(config.sleep_duration, 5);
         assert_eq!(config.max_retries, 3);
 
@@ -137,7 +389,7 @@ mod tests {
         env::set_var("PT_EPOCH_COUNT", "10");
         env::set_var("PT_WS_SLEEP", "5");
 
-        let result = Config::from_env();
+        let result = from_env();
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("PT_SERVER_URL"));
 
@@ -152,7 +404,7 @@ mod tests {
         env::set_var("PT_EPOCH_COUNT", "10");
         env::set_var("PT_WS_SLEEP", "5");
 
-        let result = Config::from_env();
+        let result = from_env();
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("PT_API_KEY"));
 
@@ -167,7 +419,7 @@ mod tests {
         env::set_var("PT_EPOCH_COUNT", "10");
         env::set_var("PT_WS_SLEEP", "5");
 
-        let result = Config::from_env();
+        let result = from_env();
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("PT_API_SECRET"));
 
@@ -179,7 +431,7 @@ mod tests {
         setup_test_env();
         env::set_var("PT_EPOCH_COUNT", "not_a_number");
 
-        let result = Config::from_env();
+        let result = from_env();
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("parsing PT_EPOCH_COUNT"));
 
@@ -191,7 +443,7 @@ mod tests {
         setup_test_env();
         env::set_var("PT_WS_SLEEP", "invalid");
 
-        let result = Config::from_env();
+        let result = from_env();
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("parsing PT_WS_SLEEP"));
 
@@ -208,7 +460,7 @@ mod tests {
         env::set_var("PT_WS_SLEEP", "5");
         // Don't set PT_MAX_RETRIES
 
-        let config = Config::from_env().unwrap();
+        let config = from_env().unwrap();
         assert_eq!(config.max_retries, 5); // Should default to 5
 
         cleanup_test_env();
@@ -219,9 +471,114 @@ mod tests {
         setup_test_env();
         env::set_var("PT_MAX_RETRIES", "not_a_number");
 
-        let config = Config::from_env().unwrap();
+        let config = from_env().unwrap();
         assert_eq!(config.max_retries, 5); // Should default to 5 on parse error
 
         cleanup_test_env();
     }
+
+    #[test]
+    fn test_from_env_ping_settings() {
+        setup_test_env();
+
+        let config = from_env().unwrap();
+        assert_eq!(config.ping_interval, 30); // Should default to 30 secs
+        assert_eq!(config.ping_warn_ms, 500); // Should default to 500ms
+
+        env::set_var("PT_PING_INTERVAL", "10");
+        env::set_var("PT_PING_WARN_MS", "250");
+        let config = from_env().unwrap();
+        assert_eq!(config.ping_interval, 10);
+        assert_eq!(config.ping_warn_ms, 250);
+
+        cleanup_test_env();
+    }
+
+    #[test]
+    fn test_from_env_proxy() {
+        setup_test_env();
+        env::set_var("PT_PROXY", "socks5://proxy.internal:1080");
+        env::set_var("PT_NO_PROXY", "localhost,.corp");
+
+        let config = from_env().unwrap();
+        let proxy = config.proxy.unwrap();
+        assert_eq!(proxy.host, "proxy.internal");
+        assert_eq!(proxy.port, 1080);
+        assert_eq!(proxy.no_proxy, vec!["localhost", ".corp"]);
+
+        env::set_var("PT_PROXY", "ftp://proxy.internal");
+        let result = from_env();
+        assert!(result.unwrap_err().contains("Unsupported proxy scheme"));
+
+        cleanup_test_env();
+    }
+
+    #[test]
+    fn test_from_env_tls() {
+        setup_test_env();
+        env::set_var("PT_TLS_CA_FILE", "/etc/pt/ca.pem");
+        env::set_var("PT_TLS_PINS", "10:72:B6:0A:5B:C8:94:F6:70:FB:76:95:3C:CA:C2:C5:7F:5A:AD:29:7B:73:4A:6A:C1:53:63:F4:B3:46:7F:3B");
+
+        let config = from_env().unwrap();
+        assert_eq!(config.tls.ca_file.unwrap().to_str(), Some("/etc/pt/ca.pem"));
+        assert_eq!(config.tls.pins.len(), 1);
+        assert_eq!(config.tls.pins[0][0], 0x10);
+
+        env::set_var("PT_TLS_CLIENT_CERT", "/etc/pt/client.pem");
+        let result = from_env();
+        assert!(result.unwrap_err().contains("must be set together"));
+
+        cleanup_test_env();
+    }
+
+    #[test]
+    fn test_from_env_transport() {
+        setup_test_env();
+        env::set_var("PT_CONNECT_TIMEOUT_MS", "2500");
+        env::set_var("PT_ADDRESS_FAMILY", "prefer-ipv4");
+        env::set_var("PT_TCP_KEEPALIVE", "0");
+        env::set_var("PT_WS_MAX_MESSAGE_SIZE", "0");
+
+        let transport = from_env().unwrap().transport;
+        assert_eq!(transport.connect_timeout, Duration::from_millis(2500));
+        assert_eq!(transport.handshake_timeout, TransportConfig::default().handshake_timeout);
+        assert_eq!(transport.address_family, AddressFamily::PreferIpv4);
+        assert_eq!(transport.keepalive, None);
+        assert_eq!(transport.max_message_size, None);
+
+        env::set_var("PT_ADDRESS_FAMILY", "ipx");
+        assert!(from_env().unwrap_err().contains("Invalid address family"));
+
+        cleanup_test_env();
+    }
+
+    #[test]
+    fn test_from_env_headers() {
+        setup_test_env();
+        let config = from_env().unwrap();
+        assert!(config.user_agent.starts_with(&format!("client-rust-ws/{}", env!("CARGO_PKG_VERSION"))));
+        assert!(config.headers.is_empty());
+        assert!(!config.correlation_id.is_empty());
+
+        env::set_var("PT_USER_AGENT", "desk-monitor/2.1");
+        env::set_var("PT_WS_HEADERS", "X-Desk: rates; X-Env:uat;");
+        env::set_var("PT_CORRELATION_ID", "nightly-recon");
+        let config = from_env().unwrap();
+        assert_eq!(config.user_agent, "desk-monitor/2.1");
+        assert_eq!(config.headers, vec![
+            ("X-Desk".to_string(), "rates".to_string()),
+            ("X-Env".to_string(), "uat".to_string()),
+        ]);
+        assert_eq!(config.correlation_id, "nightly-recon");
+
+        cleanup_test_env();
+    }
+
+    #[test]
+    fn test_parse_headers_rejects_invalid_and_reserved() {
+        assert!(Config::parse_headers("X-Desk rates").unwrap_err().contains("expected Name: value"));
+        assert!(Config::parse_headers("Bad Name: x").unwrap_err().contains("Invalid header name"));
+        assert!(Config::parse_headers("x-power-trade: token").unwrap_err().contains("cannot be overridden"));
+        assert!(Config::parse_headers("Sec-WebSocket-Key: abc").is_err());
+    }
 }
@@ -0,0 +1,262 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver};
+use tracing::{info, warn};
+
+/// Lifecycle state of the connection to Power.Trade
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    Connecting,
+    Authenticating,
+    Open,
+    /// Connected but unhealthy, e.g. ping round-trips above the warning threshold
+    Degraded,
+    Reconnecting,
+    Closing,
+    Closed,
+    Failed,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Authenticating => "authenticating",
+            ConnectionState::Open => "open",
+            ConnectionState::Degraded => "degraded",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Closing => "closing",
+            ConnectionState::Closed => "closed",
+            ConnectionState::Failed => "failed",
+        }
+    }
+
+    /// Whether moving from this state to `next` is a valid transition
+    pub fn can_transition_to(&self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (Closed, Connecting)
+                | (Failed, Connecting)
+                | (Failed, Reconnecting)
+                | (Failed, Closed)
+                | (Connecting, Authenticating)
+                | (Connecting, Reconnecting)
+                | (Connecting, Failed)
+                | (Connecting, Closed)
+                | (Authenticating, Open)
+                | (Authenticating, Reconnecting)
+                | (Authenticating, Failed)
+                | (Authenticating, Closed)
+                | (Open, Degraded)
+                | (Open, Reconnecting)
+                | (Open, Closing)
+                | (Open, Failed)
+                | (Degraded, Open)
+                | (Degraded, Reconnecting)
+                | (Degraded, Closing)
+                | (Degraded, Failed)
+                | (Reconnecting, Connecting)
+                | (Reconnecting, Failed)
+                | (Reconnecting, Closed)
+                | (Closing, Closed)
+                | (Closing, Failed)
+        )
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A single state transition with the time it happened and why
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateChange {
+    pub conn_id: u64,
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+/// Returned when a transition is not allowed from the current state
+#[derive(Debug, PartialEq)]
+pub struct InvalidTransition {
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid connection state transition {} -> {}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+type Listener = Box<dyn Fn(&StateChange) + Send>;
+
+/// Connection state machine which validates transitions and notifies listeners
+pub struct ConnectionLifecycle {
+    state: ConnectionState,
+    since: DateTime<Utc>,
+    listeners: Vec<Listener>,
+}
+
+impl ConnectionLifecycle {
+    pub fn new() -> Self {
+        ConnectionLifecycle {
+            state: ConnectionState::Closed,
+            since: Utc::now(),
+            listeners: Vec::new(),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// When the current state was entered
+    pub fn since(&self) -> DateTime<Utc> {
+        self.since
+    }
+
+    /// Call `listener` on every state change
+    pub fn on_change<F>(&mut self, listener: F)
+    where
+        F: Fn(&StateChange) + Send + 'static,
+    {
+        self.listeners.push(Box::new(listener));
+    }
+
+    /// Receive every state change on a channel
+    pub fn subscribe(&mut self) -> Receiver<StateChange> {
+        let (tx, rx) = channel();
+        self.on_change(move |change| {
+            // A dropped receiver just stops receiving
+            let _ = tx.send(change.clone());
+        });
+        rx
+    }
+
+    pub fn transition(&mut self, conn_id: u64, to: ConnectionState, reason: &str) -> Result<(), InvalidTransition> {
+        if !self.state.can_transition_to(to) {
+            return Err(InvalidTransition { from: self.state, to });
+        }
+
+        let change = StateChange {
+            conn_id,
+            from: self.state,
+            to,
+            at: Utc::now(),
+            reason: reason.to_string(),
+        };
+        info!(event = "state_change", from = %change.from, to = %change.to, reason,
+              "Connection state {} -> {}: {}", change.from, change.to, reason);
+
+        self.state = to;
+        self.since = change.at;
+        for listener in &self.listeners {
+            listener(&change);
+        }
+        Ok(())
+    }
+
+    /// Transition, logging rather than failing when the transition is not allowed
+    pub fn advance(&mut self, conn_id: u64, to: ConnectionState, reason: &str) {
+        if let Err(e) = self.transition(conn_id, to, reason) {
+            warn!(event = "invalid_state_change", "{} ({})", e, reason);
+        }
+    }
+}
+
+impl Default for ConnectionLifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_initial_state_is_closed() {
+        let lifecycle = ConnectionLifecycle::new();
+        assert_eq!(lifecycle.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_happy_path_transitions() {
+        let mut lifecycle = ConnectionLifecycle::new();
+        for state in [
+            ConnectionState::Connecting,
+            ConnectionState::Authenticating,
+            ConnectionState::Open,
+            ConnectionState::Degraded,
+            ConnectionState::Open,
+            ConnectionState::Reconnecting,
+            ConnectionState::Connecting,
+            ConnectionState::Authenticating,
+            ConnectionState::Open,
+            ConnectionState::Closing,
+            ConnectionState::Closed,
+        ] {
+            assert!(lifecycle.transition(1, state, "test").is_ok(), "transition to {}", state);
+        }
+        assert_eq!(lifecycle.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_invalid_transition_rejected() {
+        let mut lifecycle = ConnectionLifecycle::new();
+        let result = lifecycle.transition(1, ConnectionState::Open, "skip handshake");
+        assert_eq!(
+            result,
+            Err(InvalidTransition { from: ConnectionState::Closed, to: ConnectionState::Open })
+        );
+        assert_eq!(lifecycle.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_advance_ignores_invalid_transition() {
+        let mut lifecycle = ConnectionLifecycle::new();
+        lifecycle.advance(1, ConnectionState::Closing, "not open");
+        assert_eq!(lifecycle.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn test_subscribers_receive_changes() {
+        let mut lifecycle = ConnectionLifecycle::new();
+        let changes = lifecycle.subscribe();
+
+        lifecycle.transition(4, ConnectionState::Connecting, "start").unwrap();
+        lifecycle.transition(4, ConnectionState::Failed, "dns lookup failed").unwrap();
+
+        let received: Vec<StateChange> = changes.try_iter().collect();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].from, ConnectionState::Closed);
+        assert_eq!(received[0].to, ConnectionState::Connecting);
+        assert_eq!(received[1].to, ConnectionState::Failed);
+        assert_eq!(received[1].reason, "dns lookup failed");
+        assert_eq!(received[1].conn_id, 4);
+        assert!(received[1].at >= received[0].at);
+        assert_eq!(lifecycle.since(), received[1].at);
+    }
+
+    #[test]
+    fn test_callbacks_not_called_for_invalid_transition() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut lifecycle = ConnectionLifecycle::new();
+        let sink = seen.clone();
+        lifecycle.on_change(move |change| sink.lock().unwrap().push(change.to));
+
+        let _ = lifecycle.transition(1, ConnectionState::Degraded, "invalid");
+        lifecycle.transition(1, ConnectionState::Connecting, "valid").unwrap();
+
+        assert_eq!(*seen.lock().unwrap(), vec![ConnectionState::Connecting]);
+    }
+}
@@ -0,0 +1,529 @@
+// Local control socket: text commands to a running client over a Unix domain
+// socket, one per line, each answered with a single line of JSON.
+
+use crate::alerts::Alerts;
+use crate::config::Config;
+use crate::logging::LogFile;
+use crate::orders::{NewOrder, OrderRef, OrderRequest, Orders, Side, TimeInForce};
+use crate::state::SharedState;
+
+use serde_json::{json, Value};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use tracing::info;
+
+// How long a command waits for the run loop to carry out an action
+const ACTION_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HELP: &str = "status | pause | resume | book [symbol] | subscriptions | subscribe <feed> | \
+                    unsubscribe <feed> | reconnect | rotate-logs | alerts | watch-alerts | \
+                    order <buy|sell> <symbol> <quantity> [price] [gtc|ioc|fok] | cancel <symbol> <order> | \
+                    cancel-all [symbol] | amend <symbol> <order> [price=<price>] [quantity=<quantity>] | \
+                    orders [client_order_id] | risk | \
+                    kill-switch [on|off] | help";
+
+// Command which keeps the connection open, streaming alerts until the client hangs up
+const WATCH_ALERTS: &str = "watch-alerts";
+
+/// Action which only the run loop, owning the connection, can carry out
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlAction {
+    Reconnect,
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+/// An action waiting for the run loop, which answers it with `respond`
+pub struct ControlRequest {
+    pub action: ControlAction,
+    reply: Sender<Result<Value, String>>,
+}
+
+impl ControlRequest {
+    pub fn respond(self, result: Result<Value, String>) {
+        // The command may have timed out and gone away
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Executes control commands against the shared client state, passing
+/// connection actions on to the run loop
+#[derive(Clone)]
+pub struct Control {
+    state: SharedState,
+    log_file: Option<LogFile>,
+    actions: Sender<ControlRequest>,
+    alerts: Option<Alerts>,
+    orders: Option<Orders>,
+}
+
+impl Control {
+    pub fn new(state: SharedState, log_file: Option<LogFile>, actions: Sender<ControlRequest>) -> Self {
+        Control { state, log_file, actions, alerts: None, orders: None }
+    }
+
+    /// Answer the `alerts` and `watch-alerts` commands from `alerts`
+    pub fn with_alerts(mut self, alerts: Alerts) -> Self {
+        self.alerts = Some(alerts);
+        self
+    }
+
+    /// Place, amend and cancel orders with `order`, `amend`, `cancel` and `cancel-all`
+    pub fn with_orders(mut self, orders: Orders) -> Self {
+        self.orders = Some(orders);
+        self
+    }
+
+    /// Run one command line, e.g. `book BTC-USD-PERPETUAL`
+    pub fn execute(&self, line: &str) -> Result<Value, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default().to_lowercase();
+        if let Some(request) = order_request(&command, &words.clone().collect::<Vec<_>>()) {
+            let orders = self.orders()?;
+            let request = request.map_err(|e| format!("{}, expected one of: {}", e, HELP))?;
+            return orders.execute(request).map(|ack| ack.to_json()).map_err(|e| e.to_string());
+        }
+        let argument = words.next();
+        match (command.as_str(), argument) {
+            ("help", _) => Ok(json!(HELP)),
+            ("status", None) => Ok(self.state.status()),
+            ("pause", None) | ("resume", None) => {
+                let paused = command == "pause";
+                self.state.set_output_paused(paused);
+                info!(event = "output_paused", paused, "Console output of messages {}", if paused { "paused" } else { "resumed" });
+                Ok(json!({ "output_paused": paused }))
+            }
+            ("book", Some(symbol)) => self.state.book(symbol)
+                .map(|book| json!(book))
+                .ok_or_else(|| format!("No book received for {}", symbol)),
+            ("book", None) => Ok(self.state.lock().books.values().map(|book| book.top()).collect()),
+            ("subscriptions", None) => Ok(json!(Config::subscriptions_of(&self.state.lock().server_url))),
+            ("subscribe", Some(feed)) => self.run_action(ControlAction::Subscribe(feed.to_string())),
+            ("unsubscribe", Some(feed)) => self.run_action(ControlAction::Unsubscribe(feed.to_string())),
+            ("reconnect", None) => self.run_action(ControlAction::Reconnect),
+            ("rotate-logs", None) => {
+                let log_file = self.log_file.as_ref().ok_or("No log file to rotate")?;
+                log_file.rotate().map_err(|e| format!("Failed to rotate {}: {}", log_file.path().display(), e))?;
+                info!(event = "log_rotated", "Log file rotated on request");
+                Ok(json!({ "rotated": log_file.path() }))
+            }
+            ("alerts", None) => Ok(self.alerts()?.status()),
+            (WATCH_ALERTS, None) => Ok(json!({ "watching": self.alerts()?.status() })),
+            ("risk", None) => {
+                let orders = self.orders()?;
+                Ok(json!({ "kill_switch": orders.kill_switch_engaged(), "limits": orders.risk().map(|risk| risk.limits()) }))
+            }
+            ("kill-switch", None | Some("on")) => {
+                let orders = self.orders()?;
+                self.state.record_event("Kill switch engaged over the control socket");
+                let ack = orders.engage_kill_switch()
+                    .map_err(|e| format!("Kill switch engaged, but cancelling all orders failed: {}", e))?;
+                Ok(json!({ "kill_switch": true, "cancel_all": ack.to_json() }))
+            }
+            ("kill-switch", Some("off")) => {
+                self.orders()?.release_kill_switch();
+                self.state.record_event("Kill switch released over the control socket");
+                Ok(json!({ "kill_switch": false }))
+            }
+            ("orders", None) => Ok(self.orders()?.manager().status()),
+            ("orders", Some(client_order_id)) => self.orders()?.manager().order(client_order_id)
+                .map(|order| json!(order))
+                .ok_or_else(|| format!("No order {} placed by this client", client_order_id)),
+            ("", _) => Err(format!("Empty command, expected one of: {}", HELP)),
+            _ => Err(format!("Unknown command or wrong arguments '{}', expected one of: {}", line.trim(), HELP)),
+        }
+    }
+
+    fn alerts(&self) -> Result<&Alerts, String> {
+        self.alerts.as_ref().ok_or_else(|| "No alert rules are configured".to_string())
+    }
+
+    fn orders(&self) -> Result<&Orders, String> {
+        self.orders.as_ref().ok_or_else(|| "Order entry is not enabled, set PT_ORDER_ENTRY=true".to_string())
+    }
+
+    // Hand the action to the run loop and wait for its outcome
+    fn run_action(&self, action: ControlAction) -> Result<Value, String> {
+        let (reply, outcome) = channel();
+        self.actions.send(ControlRequest { action, reply })
+            .map_err(|_| "The client is shutting down".to_string())?;
+        outcome.recv_timeout(ACTION_TIMEOUT)
+            .map_err(|_| "Timed out waiting for the client, the action is still queued".to_string())?
+    }
+}
+
+// The order request of an order entry command, `None` for other commands
+fn order_request(command: &str, arguments: &[&str]) -> Option<Result<OrderRequest, String>> {
+    let request = match (command, arguments) {
+        ("order", [side, symbol, quantity, rest @ ..]) if rest.len() <= 2 => {
+            // A trailing word which is not a number is the time in force
+            let (price, time_in_force) = match rest {
+                [] => (None, None),
+                [word] if word.parse::<f64>().is_err() => (None, Some(*word)),
+                [price] => (Some(*price), None),
+                [price, time_in_force] => (Some(*price), Some(*time_in_force)),
+                _ => unreachable!(),
+            };
+            (|| {
+                let time_in_force = match (time_in_force, price) {
+                    (Some(word), _) => word.parse()?,
+                    (None, Some(_)) => TimeInForce::GoodTillCancelled,
+                    (None, None) => TimeInForce::ImmediateOrCancel,
+                };
+                Ok(OrderRequest::New(NewOrder {
+                    symbol: symbol.to_string(),
+                    side: side.parse::<Side>()?,
+                    quantity: quantity.to_string(),
+                    price: price.map(str::to_string),
+                    time_in_force,
+                    client_order_id: None,
+                }))
+            })()
+        }
+        ("cancel", [symbol, id]) => Ok(OrderRequest::Cancel { symbol: symbol.to_string(), order: order_ref(id) }),
+        ("cancel-all", []) => Ok(OrderRequest::CancelAll { symbol: None }),
+        ("cancel-all", [symbol]) => Ok(OrderRequest::CancelAll { symbol: Some(symbol.to_string()) }),
+        ("amend", [symbol, id, changes @ ..]) if !changes.is_empty() && changes.len() <= 2 => {
+            let (mut price, mut quantity) = (None, None);
+            for change in changes {
+                match change.split_once('=') {
+                    Some(("price", value)) => price = Some(value.to_string()),
+                    Some(("quantity", value)) => quantity = Some(value.to_string()),
+                    _ => return Some(Err(format!("Invalid change '{}', expected price=<price> or quantity=<quantity>", change))),
+                }
+            }
+            Ok(OrderRequest::Amend { symbol: symbol.to_string(), order: order_ref(id), price, quantity })
+        }
+        ("order" | "cancel" | "cancel-all" | "amend", _) => Err(format!("Wrong arguments for {}", command)),
+        _ => return None,
+    };
+    Some(request)
+}
+
+// Orders are named by client order id, or by server order id as `order_id=<id>`
+fn order_ref(id: &str) -> OrderRef {
+    match id.strip_prefix("order_id=") {
+        Some(id) => OrderRef::Server(id.to_string()),
+        None => OrderRef::Client(id.to_string()),
+    }
+}
+
+/// Listening control socket, removed again when dropped
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Listen on `path`, readable and writable by the current user only.
+    /// A socket file left behind by a client which is no longer running is replaced;
+    /// anything else at `path` is refused and left alone.
+    #[cfg(unix)]
+    pub fn start(path: &Path, control: Control) -> io::Result<Self> {
+        use std::fs;
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+        use std::os::unix::net::{UnixListener, UnixStream};
+        use std::thread;
+
+        // Not following symlinks, so a link is refused rather than replaced
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display())));
+            }
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another running client", path.display())));
+            }
+            fs::remove_file(path)?;
+        }
+        // Bound in a directory only the current user can enter, and moved into place once
+        // restricted, so the socket is never reachable with wider permissions
+        let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+            format!("{} does not name a socket file", path.display())))?;
+        let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let bound = private.join("socket");
+        let listener = UnixListener::bind(&bound)
+            .and_then(|listener| fs::set_permissions(&bound, fs::Permissions::from_mode(0o600)).map(|_| listener))
+            .and_then(|listener| fs::rename(&bound, path).map(|_| listener));
+        let _ = fs::remove_file(&bound);
+        let _ = fs::remove_dir(&private);
+        let listener = listener?;
+
+        thread::Builder::new().name("control".to_string()).spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let control = control.clone();
+                // Actions can wait on the run loop, so each connection gets its own thread
+                let _ = thread::Builder::new().name("control-conn".to_string())
+                    .spawn(move || serve_connection(stream, &control));
+            }
+        })?;
+        info!(event = "control_listening", path = %path.display(), "Control socket listening on {}", path.display());
+        Ok(ControlServer { path: path.to_path_buf() })
+    }
+
+    #[cfg(not(unix))]
+    pub fn start(_path: &Path, _control: Control) -> io::Result<Self> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The control socket needs Unix domain sockets"))
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn serve_connection(stream: std::os::unix::net::UnixStream, control: &Control) {
+    use std::io::{BufRead, BufReader, Write};
+
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if !matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+            return;
+        }
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        info!(event = "control_command", command = %command, "Control command: {}", command);
+        // Subscribe before answering so that no alert falls in between
+        let watching = match (command, &control.alerts) {
+            (WATCH_ALERTS, Some(alerts)) => Some(alerts.subscribe()),
+            _ => None,
+        };
+        let response = match control.execute(command) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(error) => json!({ "ok": false, "error": error }),
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+        if let Some(alerts) = watching {
+            return watch_alerts(reader.get_ref(), &mut writer, alerts);
+        }
+    }
+}
+
+// Write each alert as a line of JSON until the client hangs up
+#[cfg(unix)]
+fn watch_alerts(stream: &std::os::unix::net::UnixStream, writer: &mut std::os::unix::net::UnixStream, alerts: std::sync::mpsc::Receiver<Value>) {
+    use std::io::{Read, Write};
+    use std::sync::mpsc::RecvTimeoutError;
+
+    if stream.set_read_timeout(Some(Duration::from_millis(1))).is_err() {
+        return;
+    }
+    loop {
+        match alerts.recv_timeout(Duration::from_secs(1)) {
+            Ok(alert) => {
+                if writeln!(writer, "{}", alert).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                // End of input means the client has gone, anything else it sends is ignored
+                if matches!((&mut &*stream).read(&mut [0; 64]), Ok(0)) {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Send one command to the control socket at `path` and return its JSON response
+#[cfg(unix)]
+pub fn send_command(path: &Path, command: &str) -> io::Result<Value> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(unix))]
+pub fn send_command(_path: &Path, _command: &str) -> io::Result<Value> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "The control socket needs Unix domain sockets"))
+}
+
+/// Send `watch-alerts` to the control socket at `path`, passing each alert to `on_alert`
+/// until the client stops. Returns the response if the command is refused.
+#[cfg(unix)]
+pub fn watch_alerts_at(path: &Path, mut on_alert: impl FnMut(Value)) -> io::Result<Value> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", WATCH_ALERTS)?;
+    let mut lines = BufReader::new(stream).lines();
+    let parse = |line: String| serde_json::from_str::<Value>(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    let response = parse(lines.next().unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?)?;
+    if response["ok"] != true {
+        return Ok(response);
+    }
+    for line in lines {
+        on_alert(parse(line?)?);
+    }
+    Ok(response)
+}
+
+#[cfg(not(unix))]
+pub fn watch_alerts_at(_path: &Path, _on_alert: impl FnMut(Value)) -> io::Result<Value> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "The control socket needs Unix domain sockets"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    fn start_server(name: &str) -> (ControlServer, PathBuf, SharedState, Receiver<ControlRequest>) {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-{}-{}.sock", name, std::process::id()));
+        let state = SharedState::new();
+        let (actions, requests) = channel();
+        let server = ControlServer::start(&path, Control::new(state.clone(), None, actions)).unwrap();
+        (server, path, state, requests)
+    }
+
+    #[test]
+    fn test_status_and_output_commands() {
+        let (server, path, state, _requests) = start_server("status");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let private = path.with_file_name(format!(".{}.{}", path.file_name().unwrap().to_string_lossy(), std::process::id()));
+        assert!(!private.exists());
+        state.set_server_url("wss://example.com/v1/feeds/?type[]=mbp_snapshot&mbp_period=1");
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"ETH-USD","buy":[{"price":"2000","quantity":"1"}],"sell":[]}}"#);
+
+        let status = send_command(&path, "status").unwrap();
+        assert_eq!(status["ok"], true);
+        assert_eq!(status["result"]["messages_received"], 1);
+
+        assert_eq!(send_command(&path, "pause").unwrap()["result"]["output_paused"], true);
+        assert!(state.output_paused());
+        send_command(&path, "resume").unwrap();
+        assert!(!state.output_paused());
+
+        assert_eq!(send_command(&path, "subscriptions").unwrap()["result"], json!(["mbp_snapshot"]));
+        assert_eq!(send_command(&path, "book ETH-USD").unwrap()["result"]["buy"][0]["price"], "2000");
+        assert_eq!(send_command(&path, "book").unwrap()["result"][0]["symbol"], "ETH-USD");
+        assert_eq!(send_command(&path, "book BTC-USD").unwrap()["ok"], false);
+        assert!(send_command(&path, "launch").unwrap()["error"].as_str().unwrap().contains("Unknown command"));
+        assert!(send_command(&path, "rotate-logs").unwrap()["error"].as_str().unwrap().contains("No log file"));
+        assert_eq!(send_command(&path, "watch-alerts").unwrap()["error"], "No alert rules are configured");
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_actions_are_answered_by_the_run_loop() {
+        let (_server, path, _state, requests) = start_server("actions");
+        let run_loop = thread::spawn(move || {
+            let request = requests.recv().unwrap();
+            assert_eq!(request.action, ControlAction::Subscribe("mbp_snapshot".to_string()));
+            request.respond(Ok(json!({ "subscriptions": ["mbp_snapshot"] })));
+            let request = requests.recv().unwrap();
+            assert_eq!(request.action, ControlAction::Reconnect);
+            request.respond(Err("reconnect failed".to_string()));
+        });
+
+        let response = send_command(&path, "subscribe mbp_snapshot").unwrap();
+        assert_eq!(response["result"]["subscriptions"][0], "mbp_snapshot");
+        let response = send_command(&path, "reconnect").unwrap();
+        assert_eq!(response, json!({ "ok": false, "error": "reconnect failed" }));
+        run_loop.join().unwrap();
+    }
+
+    #[test]
+    fn test_order_commands_parse_to_requests() {
+        let parse = |line: &str| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            order_request(words[0], &words[1..])
+        };
+        match parse("order sell ETH-USD 2").unwrap().unwrap() {
+            OrderRequest::New(order) => {
+                assert_eq!((order.side, order.price, order.time_in_force), (Side::Sell, None, TimeInForce::ImmediateOrCancel));
+            }
+            other => panic!("expected a new order, got {:?}", other),
+        }
+        match parse("order buy ETH-USD 2 1999.5 fok").unwrap().unwrap() {
+            OrderRequest::New(order) => assert_eq!((order.price.as_deref(), order.time_in_force), (Some("1999.5"), TimeInForce::FillOrKill)),
+            other => panic!("expected a new order, got {:?}", other),
+        }
+        assert_eq!(parse("amend ETH-USD order_id=9 quantity=3").unwrap().unwrap(), OrderRequest::Amend {
+            symbol: "ETH-USD".to_string(),
+            order: OrderRef::Server("9".to_string()),
+            price: None,
+            quantity: Some("3".to_string()),
+        });
+        assert_eq!(parse("cancel-all").unwrap().unwrap(), OrderRequest::CancelAll { symbol: None });
+        assert!(parse("amend ETH-USD pt-1 size=3").unwrap().is_err());
+        assert!(parse("cancel ETH-USD").unwrap().is_err());
+        assert!(parse("status").is_none());
+
+        let (actions, _requests) = channel();
+        let control = Control::new(SharedState::new(), None, actions);
+        assert!(control.execute("cancel-all").unwrap_err().contains("PT_ORDER_ENTRY"));
+    }
+
+    #[test]
+    fn test_watch_alerts_streams_alerts() {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-alerts-{}.sock", std::process::id()));
+        let state = SharedState::new();
+        let rule = serde_json::from_value(json!({
+            "name": "quiet", "metric": "silence_secs", "condition": "above", "threshold": -1, "for_secs": 1,
+        })).unwrap();
+        let alerts = Alerts::start(vec![rule], state.clone(), None).unwrap();
+        let (actions, _requests) = channel();
+        let _server = ControlServer::start(&path, Control::new(state, None, actions).with_alerts(alerts)).unwrap();
+
+        assert_eq!(send_command(&path, "alerts").unwrap()["result"][0]["rule"], "quiet");
+        let (sender, received) = channel();
+        let watch_path = path.clone();
+        thread::spawn(move || watch_alerts_at(&watch_path, |alert| { let _ = sender.send(alert); }));
+        let alert = received.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(alert["rule"], "quiet");
+        assert_eq!(alert["status"], "firing");
+    }
+
+    #[test]
+    fn test_stale_socket_is_replaced_and_live_one_kept() {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-stale-{}.sock", std::process::id()));
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let (actions, _requests) = channel();
+        let control = Control::new(SharedState::new(), None, actions);
+        let server = ControlServer::start(&path, control.clone()).unwrap();
+
+        let err = ControlServer::start(&path, control).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(server);
+    }
+
+    #[test]
+    fn test_other_files_are_not_replaced() {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-file-{}.sock", std::process::id()));
+        std::fs::write(&path, "keep me").unwrap();
+        let (actions, _requests) = channel();
+        let control = Control::new(SharedState::new(), None, actions);
+
+        let err = ControlServer::start(&path, control).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
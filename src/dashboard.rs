@@ -0,0 +1,173 @@
+// Web dashboard: a page bundled into the binary, served by the HTTP API, which
+// follows the client state over a WebSocket on the same address.
+
+use crate::connection_state::ConnectionState;
+use crate::state::SharedState;
+
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::debug;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+/// The page, with its styles and script inline so it needs nothing from elsewhere
+pub const PAGE: &str = include_str!("dashboard.html");
+
+// How often the page gets a fresh snapshot
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+// Price levels sent for each side of a book
+const BOOK_DEPTH: usize = 10;
+
+// Trades and events sent, newest first
+const RECENT: usize = 50;
+
+/// Everything the page shows, as one JSON object
+pub fn snapshot(state: &SharedState) -> Value {
+    let status = state.status();
+    let state = state.lock();
+    let books: Vec<Value> = state.books.values().map(|book| json!({
+        "symbol": book.symbol,
+        "buy": &book.buy[..book.buy.len().min(BOOK_DEPTH)],
+        "sell": &book.sell[..book.sell.len().min(BOOK_DEPTH)],
+        "received_at": book.received_at,
+    })).collect();
+    json!({
+        "status": status,
+        "healthy": matches!(state.connection, ConnectionState::Open | ConnectionState::Degraded),
+        "positions": state.positions,
+        "balances": state.balances,
+        "books": books,
+        "trades": state.trades.iter().rev().take(RECENT).collect::<Vec<_>>(),
+        "events": state.events.iter().rev().take(RECENT).collect::<Vec<_>>(),
+    })
+}
+
+/// Whether a page from `origin` may follow the state: browsers send the page's origin,
+/// which must be this server's own `host`. Requests without one are not from a page.
+pub fn origin_allowed(origin: Option<&str>, host: Option<&str>) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    let (Ok(origin), Some(host)) = (Url::parse(origin), host) else {
+        return false;
+    };
+    match Url::parse(&format!("{}://{}", origin.scheme(), host)) {
+        Ok(host) => origin.host_str().is_some()
+            && origin.host_str() == host.host_str()
+            && origin.port_or_known_default() == host.port_or_known_default(),
+        Err(_) => false,
+    }
+}
+
+/// Complete the WebSocket handshake of a request carrying `key` as `Sec-WebSocket-Key`,
+/// then send snapshots until the page goes away
+pub fn serve(mut stream: TcpStream, key: &str, state: &SharedState) {
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if stream.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    // Short reads let the loop notice close frames between snapshots
+    if stream.set_read_timeout(Some(Duration::from_millis(10))).is_err() {
+        return;
+    }
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+    let result = send_snapshots(&mut socket, state);
+    debug!(event = "dashboard_closed", result = ?result, "Dashboard connection closed");
+}
+
+fn send_snapshots(socket: &mut WebSocket<TcpStream>, state: &SharedState) -> tungstenite::Result<()> {
+    loop {
+        socket.send(Message::text(snapshot(state).to_string()))?;
+        std::thread::sleep(UPDATE_INTERVAL);
+        // Pages only send close frames and pings, which tungstenite answers itself
+        loop {
+            match socket.read() {
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_api;
+    use tungstenite::client::IntoClientRequest;
+
+    #[test]
+    fn test_snapshot_limits_books_and_orders_recent_first() {
+        let state = SharedState::new();
+        let levels: Vec<String> = (0..BOOK_DEPTH + 5).map(|i| format!(r#"{{"price":"{}","quantity":"1"}}"#, 100 - i)).collect();
+        state.record_message(&format!(r#"{{"mbp_snapshot":{{"symbol":"BTC-USD","buy":[{}],"sell":[]}}}}"#, levels.join(",")));
+        state.record_message(r#"{"trade":{"symbol":"BTC-USD","price":"1","quantity":"1"}}"#);
+        state.record_message(r#"{"trade":{"symbol":"BTC-USD","price":"2","quantity":"1"}}"#);
+
+        let snapshot = snapshot(&state);
+        assert_eq!(snapshot["healthy"], false);
+        assert_eq!(snapshot["books"][0]["buy"].as_array().unwrap().len(), BOOK_DEPTH);
+        assert_eq!(snapshot["trades"][0]["price"], "2");
+        assert_eq!(snapshot["positions"], Value::Null);
+        assert_eq!(snapshot["status"]["messages_received"], 3);
+    }
+
+    #[test]
+    fn test_page_follows_state_over_websocket() {
+        // Nothing may be loaded from outside the client
+        assert!(!PAGE.contains("http://") && !PAGE.contains("https://") && !PAGE.contains(" src="));
+
+        let state = SharedState::new();
+        let addr = http_api::start("127.0.0.1:0", state.clone()).unwrap();
+        let (mut socket, response) = tungstenite::connect(format!("ws://{}/ws", addr)).unwrap();
+        assert_eq!(response.status(), 101);
+
+        let next = |socket: &mut WebSocket<_>| loop {
+            if let Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str::<Value>(&text).unwrap();
+            }
+        };
+        assert_eq!(next(&mut socket)["status"]["state"], "closed");
+        state.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"1"}]}}"#);
+        let snapshot = next(&mut socket);
+        assert_eq!(snapshot["positions"]["items"][0]["size"], "1");
+        socket.close(None).unwrap();
+    }
+
+    #[test]
+    fn test_origin_must_match_host() {
+        assert!(origin_allowed(None, Some("127.0.0.1:8080")));
+        assert!(origin_allowed(Some("http://127.0.0.1:8080"), Some("127.0.0.1:8080")));
+        assert!(origin_allowed(Some("http://LocalHost"), Some("localhost:80")));
+        assert!(origin_allowed(Some("http://[::1]:8080"), Some("[::1]:8080")));
+        assert!(!origin_allowed(Some("https://evil.example"), Some("127.0.0.1:8080")));
+        assert!(!origin_allowed(Some("http://127.0.0.1:9090"), Some("127.0.0.1:8080")));
+        assert!(!origin_allowed(Some("null"), Some("127.0.0.1:8080")));
+        assert!(!origin_allowed(Some("http://127.0.0.1:8080"), None));
+    }
+
+    #[test]
+    fn test_foreign_origin_is_refused() {
+        let addr = http_api::start("127.0.0.1:0", SharedState::new()).unwrap();
+        let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        request.headers_mut().insert("Origin", "https://evil.example".parse().unwrap());
+        match tungstenite::connect(request) {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+            other => panic!("expected the upgrade to be refused, got {:?}", other.map(|(_, response)| response.status())),
+        }
+
+        let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+        request.headers_mut().insert("Origin", format!("http://{}", addr).parse().unwrap());
+        let (mut socket, response) = tungstenite::connect(request).unwrap();
+        assert_eq!(response.status(), 101);
+        socket.close(None).unwrap();
+    }
+}
@@ -1,13 +1,112 @@
 use std::fmt;
 use std::error::Error;
+use std::io;
+
+use tungstenite::http::StatusCode;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
 
 #[derive(Debug)]
 pub enum AppError {
     Config(String),
     Connection(String),
-    Authentication(String),
+    /// Credentials could not be used locally, e.g. an invalid PEM key
+    Authentication { context: String, source: BoxError },
+    /// Server refused the handshake because of the credentials (HTTP 401/403)
+    AuthRejected { status: StatusCode },
+    /// Server answered the handshake with an unexpected HTTP status
+    Handshake { status: StatusCode },
+    /// Server closed the WebSocket with a close frame
+    ServerClosed { code: CloseCode, reason: String },
+    /// Proxy could not open a tunnel to the server
+    Proxy(String),
+    /// Proxy rejected the configured proxy credentials
+    ProxyAuth(String),
+    /// TLS handshake with the server failed, e.g. an untrusted or expired certificate,
+    /// or the connection was reset or closed part way through
+    Tls(BoxError),
+    /// CA bundle, client certificate or key could not be loaded
+    Certificate(String),
+    /// Server certificate does not match any configured pin
+    CertificatePin { host: String, fingerprint: String },
+    Dns { host: String, source: io::Error },
+    Timeout(String),
     WebSocket(tungstenite::Error),
-    Io(std::io::Error),
+    Io(io::Error),
+}
+
+impl AppError {
+    /// Short, stable name of the error category for structured logs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "config",
+            AppError::Connection(_) => "connection",
+            AppError::Authentication { .. } => "authentication",
+            AppError::AuthRejected { .. } => "auth_rejected",
+            AppError::Handshake { .. } => "handshake",
+            AppError::ServerClosed { .. } => "server_closed",
+            AppError::Proxy(_) => "proxy",
+            AppError::ProxyAuth(_) => "proxy_auth",
+            AppError::Tls(_) => "tls",
+            AppError::Certificate(_) => "certificate",
+            AppError::CertificatePin { .. } => "certificate_pin",
+            AppError::Dns { .. } => "dns",
+            AppError::Timeout(_) => "timeout",
+            AppError::WebSocket(_) => "websocket",
+            AppError::Io(_) => "io",
+        }
+    }
+
+    /// Whether trying again (reconnecting or re-running) may succeed.
+    /// Configuration, credential and certificate problems need an operator to fix them.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AppError::Config(_)
+            | AppError::Authentication { .. }
+            | AppError::AuthRejected { .. }
+            | AppError::ProxyAuth(_)
+            | AppError::Certificate(_)
+            | AppError::CertificatePin { .. } => false,
+            AppError::Tls(e) => tls_transport_failure(e.as_ref()),
+            AppError::Handshake { status } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            AppError::ServerClosed { code, .. } => !matches!(
+                code,
+                CloseCode::Policy | CloseCode::Unsupported | CloseCode::Invalid
+            ),
+            AppError::WebSocket(e) => !matches!(
+                e,
+                tungstenite::Error::Url(_) | tungstenite::Error::Capacity(_) | tungstenite::Error::Protocol(_)
+            ),
+            AppError::Connection(_)
+            | AppError::Proxy(_)
+            | AppError::Dns { .. }
+            | AppError::Timeout(_)
+            | AppError::Io(_) => true,
+        }
+    }
+
+    pub fn authentication<E>(context: &str, source: E) -> Self
+    where
+        E: Into<BoxError>,
+    {
+        AppError::Authentication { context: context.to_string(), source: source.into() }
+    }
+}
+
+/// Whether a TLS failure came from the connection under it rather than from the
+/// server's certificate: resets surface as I/O errors, early closes as "unexpected EOF"
+fn tls_transport_failure(error: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(e) = current {
+        if e.is::<io::Error>() || e.to_string().to_lowercase().contains("unexpected eof") {
+            return true;
+        }
+        current = e.source();
+    }
+    false
 }
 
 impl fmt::Display for AppError {
@@ -15,24 +114,62 @@ impl fmt::Display for AppError {
         match self {
             AppError::Config(msg) => write!(f, "Configuration error: {}", msg),
             AppError::Connection(msg) => write!(f, "Connection error: {}", msg),
-            AppError::Authentication(msg) => write!(f, "Authentication error: {}", msg),
+            AppError::Authentication { context, source } => write!(f, "Authentication error: {}: {}", context, source),
+            AppError::AuthRejected { status } => write!(f, "Authentication rejected by server: HTTP {}", status),
+            AppError::Handshake { status } => write!(f, "WebSocket handshake failed: HTTP {}", status),
+            AppError::ServerClosed { code, reason } => write!(f, "Server closed connection: {} {}", code, reason),
+            AppError::Proxy(msg) => write!(f, "Proxy error: {}", msg),
+            AppError::ProxyAuth(msg) => write!(f, "Proxy authentication error: {}", msg),
+            AppError::Tls(e) => write!(f, "TLS error: {}", e),
+            AppError::Certificate(msg) => write!(f, "Certificate error: {}", msg),
+            AppError::CertificatePin { host, fingerprint } => {
+                write!(f, "Certificate pin mismatch for {}: server presented sha256 {}", host, fingerprint)
+            }
+            AppError::Dns { host, source } => write!(f, "DNS lookup for {} failed: {}", host, source),
+            AppError::Timeout(msg) => write!(f, "Timed out: {}", msg),
             AppError::WebSocket(e) => write!(f, "WebSocket error: {}", e),
             AppError::Io(e) => write!(f, "IO error: {}", e),
         }
     }
 }
 
-impl Error for AppError {}
+impl Error for AppError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AppError::Authentication { source, .. } => Some(source.as_ref()),
+            AppError::Tls(e) => Some(e.as_ref()),
+            AppError::Dns { source, .. } => Some(source),
+            AppError::WebSocket(e) => Some(e),
+            AppError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<tungstenite::Error> for AppError {
     fn from(error: tungstenite::Error) -> Self {
-        AppError::WebSocket(error)
+        match error {
+            tungstenite::Error::Http(response) => {
+                let status = response.status();
+                if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                    AppError::AuthRejected { status }
+                } else {
+                    AppError::Handshake { status }
+                }
+            }
+            tungstenite::Error::Tls(e) => AppError::Tls(Box::new(e)),
+            tungstenite::Error::Io(e) => AppError::from(e),
+            other => AppError::WebSocket(other),
+        }
     }
 }
 
 impl From<std::io::Error> for AppError {
     fn from(error: std::io::Error) -> Self {
-        AppError::Io(error)
+        match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => AppError::Timeout(error.to_string()),
+            _ => AppError::Io(error),
+        }
     }
 }
 
@@ -41,3 +178,87 @@ impl From<String> for AppError {
         AppError::Config(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::http::Response;
+
+    fn http_error(status: u16) -> AppError {
+        let response = Response::builder().status(status).body(None).unwrap();
+        AppError::from(tungstenite::Error::Http(Box::new(response)))
+    }
+
+    #[test]
+    fn test_http_401_is_auth_rejection() {
+        let err = http_error(401);
+        assert!(matches!(err, AppError::AuthRejected { status } if status == StatusCode::UNAUTHORIZED));
+        assert!(!err.is_retryable());
+        assert_eq!(err.kind(), "auth_rejected");
+    }
+
+    #[test]
+    fn test_handshake_status_retryable_only_for_server_errors() {
+        assert!(http_error(503).is_retryable());
+        assert!(http_error(429).is_retryable());
+        assert!(!http_error(404).is_retryable());
+        assert!(matches!(http_error(404), AppError::Handshake { .. }));
+    }
+
+    #[test]
+    fn test_config_and_authentication_not_retryable() {
+        assert!(!AppError::Config("bad".to_string()).is_retryable());
+        let err = AppError::authentication("Failed to load private key", "bad pem".to_string());
+        assert!(!err.is_retryable());
+        assert_eq!(err.source().unwrap().to_string(), "bad pem");
+    }
+
+    #[test]
+    fn test_close_codes() {
+        let closed = |code| AppError::ServerClosed { code, reason: String::new() };
+        assert!(closed(CloseCode::Away).is_retryable());
+        assert!(closed(CloseCode::Restart).is_retryable());
+        assert!(!closed(CloseCode::Policy).is_retryable());
+    }
+
+    #[test]
+    fn test_io_timeout_classified() {
+        let err = AppError::from(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"));
+        assert!(matches!(err, AppError::Timeout(_)));
+        assert!(err.is_retryable());
+
+        let err = AppError::from(tungstenite::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")));
+        assert!(matches!(err, AppError::Io(_)));
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_certificate_errors_not_retryable() {
+        let err = AppError::CertificatePin { host: "api.wss.prod.power.trade".to_string(), fingerprint: "ab12".to_string() };
+        assert!(!err.is_retryable());
+        assert_eq!(err.kind(), "certificate_pin");
+        assert!(err.to_string().contains("api.wss.prod.power.trade"));
+        assert!(!AppError::Certificate("missing CA file".to_string()).is_retryable());
+        let err = AppError::Tls("certificate verify failed (unable to get local issuer certificate)".into());
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_tls_transport_failures_retryable() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer");
+        assert!(AppError::Tls(Box::new(reset)).is_retryable());
+        assert!(AppError::Tls("unexpected EOF".into()).is_retryable());
+        assert!(AppError::Tls("error:0A000126:SSL routines::unexpected eof while reading".into()).is_retryable());
+    }
+
+    #[test]
+    fn test_dns_error_retryable_with_source() {
+        let err = AppError::Dns {
+            host: "api.wss.test.power.trade".to_string(),
+            source: io::Error::other("failed to lookup address"),
+        };
+        assert!(err.is_retryable());
+        assert!(err.to_string().contains("api.wss.test.power.trade"));
+        assert!(err.source().is_some());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::error::AppError;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::process::ExitCode;
+
+/// Process exit statuses, following sysexits(3) where one fits, so supervisors
+/// can tell why the client stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitStatus {
+    /// Ran to completion
+    Success,
+    /// Unexpected failure, e.g. logging could not be initialized
+    Failure,
+    /// Remote service unavailable after all retries were used up
+    NetworkExhausted,
+    /// Server closed the connection and it could not be re-established
+    ServerClosed,
+    /// API key or secret unusable or rejected by the server
+    Credentials,
+    /// Missing environment file, invalid settings, proxy credentials, TLS certificates
+    /// or a server URL the handshake can never succeed with
+    Config,
+    /// Stopped by SIGINT/SIGTERM
+    Interrupted,
+}
+
+impl ExitStatus {
+    pub fn code(&self) -> u8 {
+        match self {
+            ExitStatus::Success => 0,
+            ExitStatus::Failure => 1,
+            ExitStatus::NetworkExhausted => 69, // EX_UNAVAILABLE
+            ExitStatus::ServerClosed => 75,     // EX_TEMPFAIL
+            ExitStatus::Credentials => 77,      // EX_NOPERM
+            ExitStatus::Config => 78,           // EX_CONFIG
+            ExitStatus::Interrupted => 130,     // 128 + SIGINT
+        }
+    }
+
+    /// Status for a run which ended with `error`
+    pub fn from_error(error: &AppError) -> Self {
+        match error {
+            // Resets and early closes during the TLS handshake are network trouble
+            AppError::Tls(_) if error.is_retryable() => ExitStatus::NetworkExhausted,
+            // Refused on the first attempt, e.g. a wrong path answered with 404 or an invalid URL
+            AppError::Handshake { .. } | AppError::WebSocket(_) if !error.is_retryable() => ExitStatus::Config,
+            AppError::Config(_)
+            | AppError::ProxyAuth(_)
+            | AppError::Tls(_)
+            | AppError::Certificate(_)
+            | AppError::CertificatePin { .. } => ExitStatus::Config,
+            AppError::Authentication { .. } | AppError::AuthRejected { .. } => ExitStatus::Credentials,
+            AppError::ServerClosed { .. } => ExitStatus::ServerClosed,
+            AppError::Connection(_)
+            | AppError::Proxy(_)
+            | AppError::Handshake { .. }
+            | AppError::Dns { .. }
+            | AppError::Timeout(_)
+            | AppError::WebSocket(_)
+            | AppError::Io(_) => ExitStatus::NetworkExhausted,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+/// Machine-readable record of a run, emitted as JSON on exit
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub status: ExitStatus,
+    pub exit_code: u8,
+    pub version: &'static str,
+    pub environment: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub attempts: u32,
+    pub reconnects: u32,
+    pub messages_received: u64,
+    pub epochs: u32,
+    /// The run limit which ended the run, e.g. `run duration 30m`
+    pub limit_reached: Option<String>,
+    pub error_kind: Option<&'static str>,
+    pub error: Option<String>,
+    /// What requested shutdown, e.g. `SIGTERM`
+    pub shutdown_reason: Option<String>,
+}
+
+/// Counters collected while the client runs, across retries
+#[derive(Debug, Default)]
+pub struct RunStats {
+    pub attempts: u32,
+    pub reconnects: u32,
+    pub messages_received: u64,
+    pub epochs: u32,
+    pub limit_reached: Option<String>,
+}
+
+impl RunSummary {
+    pub fn new(
+        status: ExitStatus,
+        environment: Option<String>,
+        started_at: DateTime<Utc>,
+        stats: &RunStats,
+        error: Option<&AppError>,
+    ) -> Self {
+        let finished_at = Utc::now();
+        RunSummary {
+            status,
+            exit_code: status.code(),
+            version: env!("CARGO_PKG_VERSION"),
+            environment,
+            started_at,
+            finished_at,
+            duration_secs: (finished_at - started_at).num_milliseconds() as f64 / 1000.0,
+            attempts: stats.attempts,
+            reconnects: stats.reconnects,
+            messages_received: stats.messages_received,
+            epochs: stats.epochs,
+            limit_reached: stats.limit_reached.clone(),
+            error_kind: error.map(|e| e.kind()),
+            error: error.map(|e| e.to_string()),
+            shutdown_reason: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tungstenite::http::StatusCode;
+    use tungstenite::protocol::frame::coding::CloseCode;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let statuses = [
+            ExitStatus::Success,
+            ExitStatus::Failure,
+            ExitStatus::NetworkExhausted,
+            ExitStatus::ServerClosed,
+            ExitStatus::Credentials,
+            ExitStatus::Config,
+            ExitStatus::Interrupted,
+        ];
+        let mut codes: Vec<u8> = statuses.iter().map(|s| s.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), statuses.len());
+    }
+
+    #[test]
+    fn test_status_from_error() {
+        assert_eq!(ExitStatus::from_error(&AppError::Config("missing".to_string())), ExitStatus::Config);
+        assert_eq!(
+            ExitStatus::from_error(&AppError::AuthRejected { status: StatusCode::UNAUTHORIZED }),
+            ExitStatus::Credentials
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::authentication("Failed to load private key", "bad".to_string())),
+            ExitStatus::Credentials
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::ServerClosed { code: CloseCode::Away, reason: String::new() }),
+            ExitStatus::ServerClosed
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::Timeout("connect".to_string())),
+            ExitStatus::NetworkExhausted
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::Handshake { status: StatusCode::SERVICE_UNAVAILABLE }),
+            ExitStatus::NetworkExhausted
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::Handshake { status: StatusCode::NOT_FOUND }),
+            ExitStatus::Config
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::WebSocket(tungstenite::Error::Url(tungstenite::error::UrlError::NoHostName))),
+            ExitStatus::Config
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::Tls("certificate verify failed".into())),
+            ExitStatus::Config
+        );
+        assert_eq!(
+            ExitStatus::from_error(&AppError::Tls(Box::new(std::io::Error::from(std::io::ErrorKind::ConnectionReset)))),
+            ExitStatus::NetworkExhausted
+        );
+    }
+
+    #[test]
+    fn test_summary_json() {
+        let stats = RunStats { attempts: 3, reconnects: 2, messages_received: 10, ..Default::default() };
+        let error = AppError::Timeout("connect".to_string());
+        let summary = RunSummary::new(
+            ExitStatus::from_error(&error),
+            Some("test".to_string()),
+            Utc::now(),
+            &stats,
+            Some(&error),
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&summary.to_json()).unwrap();
+        assert_eq!(json["status"], "network_exhausted");
+        assert_eq!(json["exit_code"], 69);
+        assert_eq!(json["environment"], "test");
+        assert_eq!(json["attempts"], 3);
+        assert_eq!(json["reconnects"], 2);
+        assert_eq!(json["messages_received"], 10);
+        assert_eq!(json["limit_reached"], serde_json::Value::Null);
+        assert_eq!(json["error_kind"], "timeout");
+    }
+}
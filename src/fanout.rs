@@ -0,0 +1,373 @@
+// Local WebSocket server re-broadcasting the messages received upstream, so
+// internal consumers share one authenticated connection instead of opening their own.
+
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::{Message, Utf8Bytes, WebSocket};
+
+// Message types replacing the previous one of their kind and symbol, replayed to consumers on join
+const SNAPSHOT_TYPES: [&str; 3] = ["mbp_snapshot", "multi_leg_mbp_snapshot", "position_summary"];
+
+// Messages queued for a consumer before it is considered too slow and dropped
+const CONSUMER_QUEUE: usize = 1024;
+
+// How long a consumer connection waits for messages before checking its socket
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Read timeout while a consumer has nothing to say, keeps broadcasts flowing
+const CONSUMER_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+// The opening handshake must complete promptly
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message types and symbols a consumer wants, an empty set matches everything
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub types: HashSet<String>,
+    pub symbols: HashSet<String>,
+}
+
+impl Filter {
+    /// Read `type` and `symbol` parameters from a query string, e.g.
+    /// `type=mbp_snapshot,trade&symbol=BTC-USD-PERPETUAL`. Parameters may repeat
+    /// and `type[]` is accepted as in the server URL.
+    pub fn from_query(query: &str) -> Self {
+        let mut filter = Filter::default();
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let set = match name.as_ref() {
+                "type" | "type[]" => &mut filter.types,
+                "symbol" | "symbol[]" => &mut filter.symbols,
+                _ => continue,
+            };
+            set.extend(value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string));
+        }
+        filter
+    }
+
+    /// Messages without a symbol, e.g. position summaries, pass a symbol filter
+    pub fn matches(&self, kind: &str, symbol: Option<&str>) -> bool {
+        (self.types.is_empty() || self.types.contains(kind))
+            && (self.symbols.is_empty() || symbol.map_or(true, |symbol| self.symbols.contains(symbol)))
+    }
+}
+
+// A received message with the type and symbol used for filtering
+#[derive(Clone)]
+struct Event {
+    kind: String,
+    symbol: Option<String>,
+    text: Utf8Bytes,
+}
+
+impl Event {
+    // Messages are objects keyed by their type, e.g. `{"mbp_snapshot": {...}}`
+    fn decode(text: &str) -> Option<Self> {
+        let Ok(Value::Object(message)) = serde_json::from_str::<Value>(text) else {
+            return None;
+        };
+        let (kind, body) = message.iter().next()?;
+        let symbol = body.get("symbol").or_else(|| body.get("tradeable_entity_id")).and_then(|symbol| match symbol {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        });
+        Some(Event { kind: kind.clone(), symbol, text: Utf8Bytes::from(text.to_string()) })
+    }
+
+    fn matches(&self, filter: &Filter) -> bool {
+        filter.matches(&self.kind, self.symbol.as_deref())
+    }
+}
+
+struct Consumer {
+    id: u64,
+    peer: SocketAddr,
+    filter: Filter,
+    events: SyncSender<Event>,
+}
+
+#[derive(Default)]
+struct Inner {
+    consumers: Vec<Consumer>,
+    /// Latest snapshot per message type and symbol
+    snapshots: BTreeMap<(String, String), Event>,
+    next_id: u64,
+}
+
+/// Broadcasts received messages to the connected consumers, cheap to clone
+#[derive(Clone, Default)]
+pub struct Fanout {
+    inner: Arc<Mutex<Inner>>,
+    allowed_origins: Arc<Vec<String>>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also accept browser pages from `origins`, e.g. `http://localhost:3000`.
+    /// Requests with any other `Origin` header are refused, those without one are tools.
+    pub fn with_allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.allowed_origins = Arc::new(origins.iter().map(|origin| normalize_origin(origin)).collect());
+        self
+    }
+
+    fn origin_allowed(&self, origin: Option<&str>) -> bool {
+        origin.map_or(true, |origin| self.allowed_origins.contains(&normalize_origin(origin)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Accept consumers on `addr` from a background thread, returning the bound address
+    pub fn start(&self, addr: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let fanout = self.clone();
+        thread::Builder::new().name("fanout".to_string()).spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let fanout = fanout.clone();
+                let _ = thread::Builder::new().name("fanout-conn".to_string())
+                    .spawn(move || fanout.serve_consumer(stream));
+            }
+        })?;
+        info!(event = "fanout_listening", addr = %local_addr, "Fan-out server listening on ws://{}", local_addr);
+        Ok(local_addr)
+    }
+
+    /// Pass a received text message on to every consumer whose filter it matches.
+    /// Consumers too slow to keep up are disconnected rather than holding up the client.
+    pub fn publish(&self, text: &str) {
+        let Some(event) = Event::decode(text) else {
+            return;
+        };
+        let mut inner = self.lock();
+        if SNAPSHOT_TYPES.contains(&event.kind.as_str()) {
+            let key = (event.kind.clone(), event.symbol.clone().unwrap_or_default());
+            inner.snapshots.insert(key, event.clone());
+        }
+        inner.consumers.retain(|consumer| {
+            if !event.matches(&consumer.filter) {
+                return true;
+            }
+            match consumer.events.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(event = "fanout_consumer_dropped", consumer = consumer.id, peer = %consumer.peer,
+                        "Dropping fan-out consumer {}: more than {} messages behind", consumer.peer, CONSUMER_QUEUE);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Number of connected consumers
+    pub fn consumers(&self) -> usize {
+        self.lock().consumers.len()
+    }
+
+    // Register a consumer and return the snapshots it starts with. Done under one
+    // lock so no message is missed or sent twice between snapshot and stream.
+    fn join(&self, peer: SocketAddr, filter: Filter) -> (u64, Vec<Event>, Receiver<Event>) {
+        let (events, received) = sync_channel(CONSUMER_QUEUE);
+        let mut inner = self.lock();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let snapshot = inner.snapshots.values().filter(|event| event.matches(&filter)).cloned().collect();
+        inner.consumers.push(Consumer { id, peer, filter, events });
+        (id, snapshot, received)
+    }
+
+    fn leave(&self, id: u64) {
+        self.lock().consumers.retain(|consumer| consumer.id != id);
+    }
+
+    fn serve_consumer(&self, stream: TcpStream) {
+        let Ok(peer) = stream.peer_addr() else { return };
+        let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+        let mut query = String::new();
+        #[allow(clippy::result_large_err)]
+        let accepted = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+            let origin = request.headers().get("origin").map(|origin| origin.to_str().unwrap_or_default());
+            if !self.origin_allowed(origin) {
+                warn!(event = "fanout_origin_rejected", peer = %peer, origin = origin.unwrap_or_default(),
+                    "Refused fan-out consumer {} from origin {}", peer, origin.unwrap_or_default());
+                let mut refused = ErrorResponse::new(Some("Connections from browser pages are not accepted".to_string()));
+                *refused.status_mut() = StatusCode::FORBIDDEN;
+                return Err(refused);
+            }
+            query = request.uri().query().unwrap_or_default().to_string();
+            Ok(response)
+        });
+        let mut socket = match accepted {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!(event = "fanout_handshake_failed", peer = %peer, "Fan-out handshake with {} failed: {}", peer, e);
+                return;
+            }
+        };
+        let _ = socket.get_ref().set_read_timeout(Some(CONSUMER_READ_TIMEOUT));
+
+        let filter = Filter::from_query(&query);
+        let (id, snapshot, events) = self.join(peer, filter.clone());
+        info!(event = "fanout_consumer_joined", consumer = id, peer = %peer, types = ?filter.types, symbols = ?filter.symbols,
+            "Fan-out consumer {} joined with {} snapshot messages, {} connected", peer, snapshot.len(), self.consumers());
+        let reason = match stream_events(&mut socket, snapshot, &events) {
+            Ok(()) => "dropped".to_string(),
+            Err(e) => e.to_string(),
+        };
+        self.leave(id);
+        info!(event = "fanout_consumer_left", consumer = id, peer = %peer, reason = %reason, "Fan-out consumer {} left: {}", peer, reason);
+    }
+}
+
+// Origins compare without case or a trailing slash
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_lowercase()
+}
+
+// Send the snapshot, then every message passed on by `publish`, until the consumer
+// goes away (`Err`) or is dropped for lagging (`Ok`)
+fn stream_events(socket: &mut WebSocket<TcpStream>, snapshot: Vec<Event>, events: &Receiver<Event>) -> tungstenite::Result<()> {
+    for event in snapshot {
+        socket.write(Message::Text(event.text))?;
+    }
+    socket.flush()?;
+    loop {
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                socket.write(Message::Text(event.text))?;
+                while let Ok(event) = events.try_recv() {
+                    socket.write(Message::Text(event.text))?;
+                }
+                socket.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                let _ = socket.close(None);
+                let _ = socket.flush();
+                return Ok(());
+            }
+        }
+        // Consumers only send pings and close frames, which tungstenite answers itself
+        match socket.read() {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn connect(addr: SocketAddr, query: &str) -> WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>> {
+        let (socket, _) = tungstenite::connect(format!("ws://{}/?{}", addr, query)).unwrap();
+        if let tungstenite::stream::MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        }
+        socket
+    }
+
+    fn next_text(socket: &mut WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>) -> Value {
+        loop {
+            if let Message::Text(text) = socket.read().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    fn wait_for_consumers(fanout: &Fanout, count: usize) {
+        let started = Instant::now();
+        while fanout.consumers() != count {
+            assert!(started.elapsed() < Duration::from_secs(5), "consumers did not connect");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_filter_from_query() {
+        let filter = Filter::from_query("type=mbp_snapshot,trade&symbol=BTC-USD&type[]=position_summary&other=1");
+        assert_eq!(filter.types.len(), 3);
+        assert!(filter.matches("trade", Some("BTC-USD")));
+        assert!(!filter.matches("trade", Some("ETH-USD")));
+        assert!(filter.matches("position_summary", None));
+        assert!(!filter.matches("heartbeat", Some("BTC-USD")));
+        assert!(Filter::from_query("").matches("anything", Some("X")));
+    }
+
+    #[test]
+    fn test_consumers_get_snapshot_then_filtered_stream() {
+        let fanout = Fanout::new();
+        let addr = fanout.start("127.0.0.1:0").unwrap();
+        fanout.publish(r#"{"mbp_snapshot":{"symbol":"BTC-USD","buy":[{"price":"1","quantity":"1"}],"sell":[]}}"#);
+        fanout.publish(r#"{"mbp_snapshot":{"symbol":"BTC-USD","buy":[{"price":"2","quantity":"1"}],"sell":[]}}"#);
+        fanout.publish(r#"{"mbp_snapshot":{"symbol":"ETH-USD","buy":[],"sell":[]}}"#);
+        fanout.publish("not json");
+
+        let mut btc = connect(addr, "symbol=BTC-USD");
+        let mut trades = connect(addr, "type=trade");
+        wait_for_consumers(&fanout, 2);
+
+        // Only the latest snapshot of the symbol is replayed
+        assert_eq!(next_text(&mut btc)["mbp_snapshot"]["buy"][0]["price"], "2");
+
+        fanout.publish(r#"{"trade":{"symbol":"ETH-USD","price":"3"}}"#);
+        fanout.publish(r#"{"trade":{"symbol":"BTC-USD","price":"4"}}"#);
+        assert_eq!(next_text(&mut btc)["trade"]["price"], "4");
+        assert_eq!(next_text(&mut trades)["trade"]["price"], "3");
+        assert_eq!(next_text(&mut trades)["trade"]["price"], "4");
+
+        btc.close(None).unwrap();
+        while btc.read().is_ok() {}
+        wait_for_consumers(&fanout, 1);
+    }
+
+    #[test]
+    fn test_browser_origins_need_allowing() {
+        use tungstenite::client::IntoClientRequest;
+
+        let fanout = Fanout::new().with_allowed_origins(vec!["http://localhost:3000/".to_string()]);
+        let addr = fanout.start("127.0.0.1:0").unwrap();
+        let connect_from = |origin: &str| {
+            let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+            request.headers_mut().insert("Origin", origin.parse().unwrap());
+            tungstenite::connect(request).map(|(socket, _)| socket)
+        };
+
+        match connect_from("https://evil.example") {
+            Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 403),
+            other => panic!("expected the consumer to be refused, got {:?}", other.map(|_| ())),
+        }
+        let mut page = connect_from("http://LOCALHOST:3000").unwrap();
+        let mut tool = connect(addr, "");
+        wait_for_consumers(&fanout, 2);
+        page.close(None).unwrap();
+        tool.close(None).unwrap();
+    }
+
+    #[test]
+    fn test_lagging_consumer_is_dropped() {
+        let fanout = Fanout::new();
+        let (_id, _snapshot, events) = fanout.join("127.0.0.1:1".parse().unwrap(), Filter::default());
+        for _ in 0..=CONSUMER_QUEUE {
+            fanout.publish(r#"{"trade":{"symbol":"BTC-USD"}}"#);
+        }
+        assert_eq!(fanout.consumers(), 0);
+        assert_eq!(events.try_iter().count(), CONSUMER_QUEUE);
+    }
+}
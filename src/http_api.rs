@@ -0,0 +1,262 @@
+// Local HTTP API answering read-only queries about the running client in JSON,
+// for dashboards and health checks which should not open their own connections.
+
+use crate::connection_state::ConnectionState;
+use crate::dashboard;
+use crate::state::SharedState;
+
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+// Requests must arrive promptly, slow clients are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longest request head accepted, requests carry no body
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// A response status and JSON body
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response { status: 200, body }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response { status, body: json!({ "error": message }) }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Serve the API on `addr` from a background thread, returning the bound address
+pub fn start(addr: &str, state: SharedState) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::Builder::new().name("http-api".to_string()).spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let state = state.clone();
+            let _ = thread::Builder::new().name("http-conn".to_string())
+                .spawn(move || serve_connection(stream, &state));
+        }
+    })?;
+    info!(event = "http_listening", addr = %local_addr, "HTTP status API listening on http://{}", local_addr);
+    Ok(local_addr)
+}
+
+/// Answer `GET path` from the client state
+pub fn route(state: &SharedState, method: &str, path: &str) -> Response {
+    if method != "GET" && method != "HEAD" {
+        return Response::error(405, "Only GET is supported");
+    }
+    let path = path.split('?').next().unwrap_or_default();
+    match path.trim_end_matches('/') {
+        "/health" => {
+            let status = state.status();
+            let healthy = matches!(state.lock().connection, ConnectionState::Open | ConnectionState::Degraded);
+            let body = json!({
+                "healthy": healthy,
+                "state": status["state"],
+                "last_message_at": status["last_message_at"],
+            });
+            Response { status: if healthy { 200 } else { 503 }, body }
+        }
+        "/status" => Response::ok(state.status()),
+        "/positions" => match &state.lock().positions {
+            Some(positions) => Response::ok(json!(positions)),
+            None => Response::error(404, "No position summary received yet"),
+        },
+        "/balances" => match &state.lock().balances {
+            Some(balances) => Response::ok(json!(balances)),
+            None => Response::error(404, "No position summary received yet"),
+        },
+        "/book" => Response::ok(state.lock().books.values().map(|book| book.top()).collect()),
+        path => match path.strip_prefix("/book/").map(percent_decode) {
+            Some(Some(symbol)) => match state.book(&symbol) {
+                Some(book) => Response::ok(json!(book)),
+                None => Response::error(404, &format!("No book received for {}", symbol)),
+            },
+            Some(None) => Response::error(400, "Invalid symbol encoding"),
+            None => Response::error(404, "Not found, try /health, /status, /positions, /balances, /book/{symbol} or / for the dashboard"),
+        },
+    }
+}
+
+fn serve_connection(stream: TcpStream, state: &SharedState) {
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream).take(MAX_REQUEST_HEAD as u64);
+
+    // Request line, then headers up to the blank line
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut headers = Vec::new();
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|n| n > 0) && !header.trim().is_empty() {
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+        header.clear();
+    }
+    let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let page = path.split('?').next().unwrap_or_default();
+    if method == "GET" && page == "/ws" && header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) {
+        // Any page the browser has open could otherwise follow the state
+        if !dashboard::origin_allowed(header("origin"), header("host")) {
+            warn!(event = "dashboard_origin_rejected", origin = header("origin").unwrap_or_default(),
+                  "Refused dashboard connection from origin {}", header("origin").unwrap_or_default());
+            let response = Response::error(403, "Connections from other origins are not accepted");
+            return write_response(&mut writer, method, "403 Forbidden", "application/json", &response.body.to_string());
+        }
+        if let Some(key) = header("sec-websocket-key") {
+            debug!(event = "http_request", method, path, status = 101, "{} {} -> 101", method, path);
+            let _ = writer.set_read_timeout(None);
+            return dashboard::serve(writer, key, state);
+        }
+    }
+    if (method == "GET" || method == "HEAD") && (page == "/" || page == "/dashboard") {
+        debug!(event = "http_request", method, path, status = 200, "{} {} -> 200", method, path);
+        return write_response(&mut writer, method, "200 OK", "text/html; charset=utf-8", dashboard::PAGE);
+    }
+
+    let response = match path.starts_with('/') {
+        true => route(state, method, path),
+        false => Response::error(400, "Malformed request"),
+    };
+    debug!(event = "http_request", method, path, status = response.status, "{} {} -> {}", method, path, response.status);
+    let status = format!("{} {}", response.status, response.reason());
+    write_response(&mut writer, method, &status, "application/json", &response.body.to_string());
+}
+
+fn write_response(writer: &mut TcpStream, method: &str, status: &str, content_type: &str, body: &str) {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len()
+    );
+    let _ = writer.write_all(head.as_bytes());
+    if method != "HEAD" {
+        let _ = writer.write_all(body.as_bytes());
+    }
+}
+
+// Decode %XX escapes in a path segment
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_state::StateChange;
+    use chrono::Utc;
+
+    fn get(addr: SocketAddr, request: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Content-Type: application/json"));
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn test_endpoints_answer_from_state() {
+        let state = SharedState::new();
+        let addr = start("127.0.0.1:0", state.clone()).unwrap();
+        let request = |path: &str| get(addr, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path));
+
+        let (status, body) = request("/health");
+        assert_eq!(status, 503);
+        assert_eq!(body["healthy"], false);
+        assert_eq!(request("/positions").0, 404);
+
+        state.connection_changed(&StateChange {
+            conn_id: 1, from: ConnectionState::Connecting, to: ConnectionState::Open, at: Utc::now(), reason: String::new(),
+        });
+        state.set_token_expiry(Utc::now() + chrono::Duration::hours(5));
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"BTC-USD 1","buy":[{"price":"1","quantity":"2"}],"sell":[]}}"#);
+        state.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC"}],"balances":[{"currency":"USD"}]}}"#);
+
+        assert_eq!(request("/health"), (200, json!({ "healthy": true, "state": "open", "last_message_at": state.status()["last_message_at"] })));
+        let (status, body) = request("/status");
+        assert_eq!(status, 200);
+        assert_eq!(body["messages_received"], 2);
+        assert!(body["token_expires_in_secs"].as_i64().unwrap() > 17000);
+        assert_eq!(request("/positions").1["items"][0]["symbol"], "BTC");
+        assert_eq!(request("/balances/").1["items"][0]["currency"], "USD");
+        assert_eq!(request("/book/BTC-USD%201").1["buy"][0]["quantity"], "2");
+        assert_eq!(request("/book").1[0]["symbol"], "BTC-USD 1");
+        assert_eq!(request("/book/ETH").0, 404);
+        assert_eq!(request("/book/%zz").0, 400);
+        assert_eq!(request("/orders").0, 404);
+    }
+
+    #[test]
+    fn test_only_get_is_served() {
+        let state = SharedState::new();
+        assert_eq!(route(&state, "POST", "/status").status, 405);
+        assert_eq!(route(&state, "GET", "/status?pretty=1").status, 200);
+
+        let addr = start("127.0.0.1:0", state).unwrap();
+        assert_eq!(get(addr, "garbage\r\n\r\n").0, 400);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"HEAD /status HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_dashboard_page_is_served() {
+        let addr = start("127.0.0.1:0", SharedState::new()).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/html"));
+        assert!(response.ends_with(dashboard::PAGE));
+    }
+}
@@ -0,0 +1,238 @@
+// Run-length limits: how long the client runs before exiting on its own.
+
+use crate::config::Settings;
+use crate::exit::RunStats;
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::time::Duration;
+
+// Variables holding the limits, the command line flags override all of them together
+pub const LIMIT_VARS: [&str; 5] = ["PT_EPOCH_COUNT", "PT_RUN_DURATION", "PT_MAX_MESSAGES", "PT_RUN_UNTIL", "PT_RUN_UNLIMITED"];
+
+/// When the client stops by itself. The first limit reached ends the run;
+/// with none set the client runs until it is stopped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunLimits {
+    /// Read iterations, the original `PT_EPOCH_COUNT` semantics
+    pub epochs: Option<u32>,
+    /// Wall-clock time since the client started
+    pub duration: Option<Duration>,
+    /// Messages received across all connections
+    pub messages: Option<u64>,
+    /// Point in time to stop at
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl RunLimits {
+    /// Read the limits from the settings. Empty variables count as unset.
+    /// One limit, or `PT_RUN_UNLIMITED=true`, is required so a missing setting
+    /// does not silently turn into a client which never exits.
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let limits = RunLimits {
+            epochs: settings.value("PT_EPOCH_COUNT")
+                .map(|v| v.trim().parse::<u32>().map_err(|e| format!("Error parsing PT_EPOCH_COUNT: {}", e)))
+                .transpose()?,
+            duration: settings.value("PT_RUN_DURATION")
+                .map(|v| parse_duration(&v).map_err(|e| format!("Error parsing PT_RUN_DURATION: {}", e)))
+                .transpose()?,
+            messages: settings.value("PT_MAX_MESSAGES")
+                .map(|v| v.trim().parse::<u64>().map_err(|e| format!("Error parsing PT_MAX_MESSAGES: {}", e)))
+                .transpose()?,
+            until: settings.value("PT_RUN_UNTIL")
+                .map(|v| DateTime::parse_from_rfc3339(v.trim())
+                    .map(|until| until.with_timezone(&Utc))
+                    .map_err(|e| format!("Error parsing PT_RUN_UNTIL (expected RFC 3339, e.g. 2025-01-31T17:00:00Z): {}", e)))
+                .transpose()?,
+        };
+        let unlimited = settings.value("PT_RUN_UNLIMITED")
+            .map(|v| v.trim().parse::<bool>().map_err(|e| format!("Error parsing PT_RUN_UNLIMITED: {}", e)))
+            .transpose()?
+            .unwrap_or(false);
+
+        match (unlimited, limits.is_unlimited()) {
+            (true, false) => Err("PT_RUN_UNLIMITED cannot be combined with other run limits".to_string()),
+            (false, true) => Err("Set a run limit (PT_EPOCH_COUNT, PT_RUN_DURATION, PT_MAX_MESSAGES or PT_RUN_UNTIL) \
+                                  or PT_RUN_UNLIMITED=true".to_string()),
+            _ => Ok(limits),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == RunLimits::default()
+    }
+
+    /// The earliest point in time a duration or timestamp limit ends the run
+    pub fn deadline(&self, started_at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let after_duration = self.duration
+            .and_then(|duration| chrono::Duration::from_std(duration).ok())
+            .and_then(|duration| started_at.checked_add_signed(duration));
+        match (after_duration, self.until) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Time left until the deadline, if there is one
+    pub fn remaining(&self, started_at: DateTime<Utc>) -> Option<Duration> {
+        self.deadline(started_at).map(|deadline| (deadline - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    /// Describe the first limit reached by a run started at `started_at`, if any
+    pub fn reached(&self, started_at: DateTime<Utc>, stats: &RunStats) -> Option<String> {
+        if let Some(epochs) = self.epochs.filter(|epochs| stats.epochs >= *epochs) {
+            return Some(format!("{} epochs", epochs));
+        }
+        if let Some(messages) = self.messages.filter(|messages| stats.messages_received >= *messages) {
+            return Some(format!("{} messages", messages));
+        }
+        match self.deadline(started_at) {
+            Some(deadline) if Utc::now() >= deadline => match self.duration {
+                Some(duration) if self.until.map_or(true, |until| deadline < until) =>
+                    Some(format!("run duration {}", format_duration(duration))),
+                _ => Some(format!("run until {}", deadline.to_rfc3339())),
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RunLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unlimited() {
+            return write!(f, "unlimited");
+        }
+        let mut parts = Vec::new();
+        if let Some(epochs) = self.epochs {
+            parts.push(format!("{} epochs", epochs));
+        }
+        if let Some(duration) = self.duration {
+            parts.push(format!("for {}", format_duration(duration)));
+        }
+        if let Some(messages) = self.messages {
+            parts.push(format!("{} messages", messages));
+        }
+        if let Some(until) = self.until {
+            parts.push(format!("until {}", until.to_rfc3339()));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Parse a duration such as `90`, `90s`, `15m`, `2h`, `1d` or `1h30m` (plain numbers are seconds)
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+
+    let mut total = 0u64;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(format!("invalid duration '{}': unknown unit '{}'", value, c)),
+        };
+        let amount = digits.parse::<u64>()
+            .map_err(|_| format!("invalid duration '{}': expected a number before '{}'", value, c))?;
+        total = amount.checked_mul(unit).and_then(|secs| total.checked_add(secs))
+            .ok_or_else(|| format!("invalid duration '{}': too long", value))?;
+        digits.clear();
+    }
+    if !digits.is_empty() || value.is_empty() {
+        return Err(format!("invalid duration '{}': expected e.g. 90s, 15m or 2h", value));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0 => "0s".to_string(),
+        _ if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        _ if secs % 60 == 0 => format!("{}m", secs / 60),
+        _ => format!("{}s", secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn clear_limit_vars() {
+        for name in LIMIT_VARS {
+            env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("1h30").is_err());
+        assert!(parse_duration("m").is_err());
+    }
+
+    #[test]
+    fn test_from_env_requires_a_limit() {
+        clear_limit_vars();
+        assert!(RunLimits::from_settings(&Settings::from_env()).unwrap_err().contains("Set a run limit"));
+
+        env::set_var("PT_RUN_UNLIMITED", "true");
+        assert!(RunLimits::from_settings(&Settings::from_env()).unwrap().is_unlimited());
+
+        env::set_var("PT_EPOCH_COUNT", "10");
+        assert!(RunLimits::from_settings(&Settings::from_env()).unwrap_err().contains("cannot be combined"));
+
+        env::set_var("PT_RUN_UNLIMITED", "");
+        env::set_var("PT_RUN_DURATION", "30m");
+        env::set_var("PT_RUN_UNTIL", "2030-01-31T17:00:00+01:00");
+        let limits = RunLimits::from_settings(&Settings::from_env()).unwrap();
+        assert_eq!(limits.epochs, Some(10));
+        assert_eq!(limits.duration, Some(Duration::from_secs(1800)));
+        assert_eq!(limits.until.unwrap().to_rfc3339(), "2030-01-31T16:00:00+00:00");
+        assert_eq!(limits.to_string(), "10 epochs, for 30m, until 2030-01-31T16:00:00+00:00");
+
+        env::set_var("PT_RUN_UNTIL", "tomorrow");
+        assert!(RunLimits::from_settings(&Settings::from_env()).unwrap_err().contains("PT_RUN_UNTIL"));
+        clear_limit_vars();
+    }
+
+    #[test]
+    fn test_reached_reports_first_limit() {
+        let started_at = Utc::now();
+        let mut stats = RunStats::default();
+        let limits = RunLimits { epochs: Some(3), messages: Some(5), ..Default::default() };
+        assert_eq!(limits.reached(started_at, &stats), None);
+
+        stats.messages_received = 5;
+        assert_eq!(limits.reached(started_at, &stats).as_deref(), Some("5 messages"));
+        stats.epochs = 3;
+        assert_eq!(limits.reached(started_at, &stats).as_deref(), Some("3 epochs"));
+
+        let stats = RunStats::default();
+        let limits = RunLimits { duration: Some(Duration::from_secs(60)), ..Default::default() };
+        assert_eq!(limits.reached(started_at, &stats), None);
+        assert!(limits.remaining(started_at).unwrap() <= Duration::from_secs(60));
+        let long_ago = started_at - chrono::Duration::minutes(2);
+        assert_eq!(limits.reached(long_ago, &stats).as_deref(), Some("run duration 1m"));
+
+        let limits = RunLimits { duration: Some(Duration::from_secs(3600)), until: Some(started_at), ..Default::default() };
+        assert_eq!(limits.deadline(started_at), Some(started_at));
+        assert!(limits.reached(started_at, &stats).unwrap().starts_with("run until"));
+        assert!(RunLimits::default().reached(long_ago, &stats).is_none());
+    }
+}
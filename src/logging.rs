@@ -1,34 +1,595 @@
-use log::{LevelFilter, info};
-use simplelog::{CombinedLogger, Config, WriteLogger, TermLogger, TerminalMode, ColorChoice};
-use std::fs::File;
-use std::path::Path;
-
-pub fn setup_logging(log_file: &str, level: LevelFilter) -> Result<(), Box<dyn std::error::Error>> {
-    let log_path = Path::new(log_file);
-    
-    // Create log directory if it doesn't exist
-    if let Some(parent) = log_path.parent() {
-        if !parent.exists() {
-            std::fs::create_dir_all(parent)?;
-        }
-    }
-    
-    CombinedLogger::init(vec![
+use crate::console;
+
+use chrono::{Local, NaiveDate};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{info, Subscriber};
+use tracing_log::LogTracer;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+// Layers are boxed so optional ones (such as OTLP export) can be added at runtime
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Format used for records written to the log file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format '{}' (expected text or json)", other)),
+        }
+    }
+}
+
+/// When the log file is rolled over to an archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Daily,
+    Size(u64),
+}
+
+/// Options controlling where and how log records are written
+#[derive(Clone, Debug)]
+pub struct LogOptions {
+    pub file: PathBuf,
+    pub console_level: LevelFilter,
+    pub file_level: LevelFilter,
+    pub format: LogFormat,
+    pub rotation: Rotation,
+    pub max_files: usize,
+    pub compress: bool,
+    /// OTLP/HTTP collector base URL (e.g. http://localhost:4318) to export spans to
+    pub otlp_endpoint: Option<String>,
+}
+
+impl LogOptions {
+    pub fn new(file: &str, level: LevelFilter) -> Self {
+        LogOptions {
+            file: PathBuf::from(file),
+            console_level: level,
+            file_level: level,
+            format: LogFormat::Text,
+            rotation: Rotation::Never,
+            max_files: 5,
+            compress: false,
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Parse a level name, falling back to `INFO` for unknown values
+pub fn parse_level(level: &str) -> LevelFilter {
+    match level.to_lowercase().as_str() {
+        "off" => LevelFilter::OFF,
+        "error" => LevelFilter::ERROR,
+        "warn" => LevelFilter::WARN,
+        "info" => LevelFilter::INFO,
+        "debug" => LevelFilter::DEBUG,
+        "trace" => LevelFilter::TRACE,
+        _ => LevelFilter::INFO,
+    }
+}
+
+/// Changes the console and file log levels of the running subscriber
+#[derive(Clone)]
+pub struct LogLevels {
+    console: reload::Handle<LevelFilter, Registry>,
+    file: reload::Handle<LevelFilter, Registry>,
+}
+
+impl LogLevels {
+    pub fn current(&self) -> (LevelFilter, LevelFilter) {
+        let level = |handle: &reload::Handle<LevelFilter, Registry>| handle.clone_current().unwrap_or(LevelFilter::OFF);
+        (level(&self.console), level(&self.file))
+    }
+
+    pub fn set(&self, console: LevelFilter, file: LevelFilter) -> Result<(), reload::Error> {
+        self.console.reload(console)?;
+        self.file.reload(file)
+    }
+}
+
+/// Keeps span exporters alive for the life of the process and flushes them when dropped
+pub struct LoggingGuard {
+    levels: LogLevels,
+    file: LogFile,
+    #[cfg(feature = "otlp")]
+    tracer_provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl LoggingGuard {
+    /// Handle for changing log levels while running
+    pub fn levels(&self) -> LogLevels {
+        self.levels.clone()
+    }
+
+    /// Handle to the log file, e.g. for rotating it on request
+    pub fn file(&self) -> LogFile {
+        self.file.clone()
+    }
+}
+
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.tracer_provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+pub fn setup_logging(options: &LogOptions) -> Result<LoggingGuard, Box<dyn std::error::Error>> {
+    // Route records from crates using the `log` facade (tungstenite, native-tls, ...) into tracing
+    LogTracer::init()?;
+
+    let writer = LogFile::new(RotatingFile::open(
+        &options.file,
+        options.rotation,
+        options.max_files,
+        options.compress,
+    )?);
+
+    // Levels sit behind reload layers so they can change without restarting
+    let (console_level, console_handle) = reload::Layer::new(options.console_level);
+    let (file_level, file_handle) = reload::Layer::new(options.file_level);
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+    let mut layers: Vec<BoxedLayer> = vec![
         // Log to terminal with colors
-        TermLogger::new(
-            level,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        ),
-        // Log to file
-        WriteLogger::new(
-            level,
-            Config::default(),
-            File::create(log_path)?,
-        ),
-    ])?;
-    
-    info!("Logging initialized at level {:?} to {}", level, log_file);
-    Ok(())
-}
\ No newline at end of file
+        fmt::layer().with_target(false).with_writer(|| console::Stdout).with_filter(console_level).boxed(),
+        // Log to (rotating) file
+        file_layer(options.format, {
+            let writer = writer.clone();
+            move || writer.clone()
+        }).with_filter(file_level).boxed(),
+    ];
+
+    #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+    let mut guard = LoggingGuard {
+        levels: LogLevels { console: console_handle, file: file_handle },
+        file: writer,
+        #[cfg(feature = "otlp")]
+        tracer_provider: None,
+    };
+
+    if let Some(endpoint) = &options.otlp_endpoint {
+        #[cfg(feature = "otlp")]
+        {
+            let (layer, provider) = otlp::layer(endpoint)?;
+            layers.push(layer);
+            guard.tracer_provider = Some(provider);
+        }
+        #[cfg(not(feature = "otlp"))]
+        eprintln!("Ignoring OTLP endpoint {}: client was built without the 'otlp' feature", endpoint);
+    }
+
+    tracing::subscriber::set_global_default(Registry::default().with(layers))?;
+
+    info!(event = "logging_initialized", "Logging initialized (console {}, file {} as {:?}) to {}",
+          options.console_level, options.file_level, options.format, options.file.display());
+    Ok(guard)
+}
+
+// Text or JSON formatting layer for the log file. JSON records carry the event
+// fields at the top level plus the current span and its parents, so the
+// connection id recorded on the `connection` span appears on every line.
+fn file_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_ansi(false).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::Layer;
+
+    use super::BoxedLayer;
+
+    /// Build a layer exporting spans to `<endpoint>/v1/traces` over OTLP/HTTP
+    pub fn layer(endpoint: &str) -> Result<(BoxedLayer, TracerProvider), Box<dyn std::error::Error>> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()?;
+
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter)
+            .with_resource(Resource::new(vec![KeyValue::new("service.name", env!("CARGO_PKG_NAME"))]))
+            .build();
+
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        Ok((tracing_opentelemetry::layer().with_tracer(tracer).boxed(), provider))
+    }
+}
+
+/// Append-only log file which rolls over by size or day into numbered archives
+/// (`app.log.1`, `app.log.2`, ...), optionally gzip compressed, keeping at most
+/// `max_files` archives.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+    rotation: Rotation,
+    max_files: usize,
+    compress: bool,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation, max_files: usize, compress: bool) -> io::Result<Self> {
+        // Create log directory if it doesn't exist
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let opened_on = metadata
+            .modified()
+            .map(|t| chrono::DateTime::<Local>::from(t).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            opened_on,
+            rotation,
+            max_files,
+            compress,
+        })
+    }
+
+    fn archive_path(&self, index: usize) -> PathBuf {
+        let suffix = if self.compress { ".gz" } else { "" };
+        PathBuf::from(format!("{}.{}{}", self.path.display(), index, suffix))
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Daily => Local::now().date_naive() != self.opened_on,
+            Rotation::Size(limit) => self.size > 0 && self.size + incoming as u64 > limit,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Drop the oldest archive and shift the rest up by one
+            let oldest = self.archive_path(self.max_files);
+            if oldest.exists() {
+                fs::remove_file(&oldest)?;
+            }
+            for index in (1..self.max_files).rev() {
+                let from = self.archive_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.archive_path(index + 1))?;
+                }
+            }
+
+            if self.compress {
+                let mut input = File::open(&self.path)?;
+                let mut encoder = GzEncoder::new(File::create(self.archive_path(1))?, Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?;
+                fs::remove_file(&self.path)?;
+            } else {
+                fs::rename(&self.path, self.archive_path(1))?;
+            }
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+/// Log file shared between the logging layer and code which rotates it on demand.
+/// Each record is written under the lock, so a rotation never splits a line.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<RotatingFile>>);
+
+impl LogFile {
+    pub fn new(file: RotatingFile) -> Self {
+        LogFile(Arc::new(Mutex::new(file)))
+    }
+
+    /// Roll the current file over to the first archive now, whatever the rotation policy
+    pub fn rotate(&self) -> io::Result<()> {
+        self.0.lock().unwrap().rotate()
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.0.lock().unwrap().path.clone()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    // Helper to create an empty scratch directory for a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("client-rust-ws-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("TEXT".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_parse_level_defaults_to_info() {
+        assert_eq!(parse_level("debug"), LevelFilter::DEBUG);
+        assert_eq!(parse_level("nonsense"), LevelFilter::INFO);
+    }
+
+    #[test]
+    fn test_rotating_file_appends_on_reopen() {
+        let dir = temp_dir("append");
+        let path = dir.join("app.log");
+
+        let mut file = RotatingFile::open(&path, Rotation::Never, 3, false).unwrap();
+        writeln!(file, "first").unwrap();
+        drop(file);
+        let mut file = RotatingFile::open(&path, Rotation::Never, 3, false).unwrap();
+        writeln!(file, "second").unwrap();
+        drop(file);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rotating_file_size_rotation_and_retention() {
+        let dir = temp_dir("size");
+        let path = dir.join("app.log");
+
+        let mut file = RotatingFile::open(&path, Rotation::Size(10), 2, false).unwrap();
+        for line in ["aaaaaaaa", "bbbbbbbb", "cccccccc", "dddddddd"] {
+            writeln!(file, "{}", line).unwrap();
+        }
+        drop(file);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(fs::read_to_string(dir.join("app.log.1")).unwrap(), "cccccccc\n");
+        assert_eq!(fs::read_to_string(dir.join("app.log.2")).unwrap(), "bbbbbbbb\n");
+        assert!(!dir.join("app.log.3").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_log_file_rotates_on_request() {
+        let dir = temp_dir("on-request");
+        let path = dir.join("app.log");
+
+        let mut file = LogFile::new(RotatingFile::open(&path, Rotation::Never, 2, false).unwrap());
+        writeln!(file, "before").unwrap();
+        file.clone().rotate().unwrap();
+        writeln!(file, "after").unwrap();
+
+        assert_eq!(file.path(), path);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        assert_eq!(fs::read_to_string(dir.join("app.log.1")).unwrap(), "before\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rotating_file_compresses_archives() {
+        let dir = temp_dir("gzip");
+        let path = dir.join("app.log");
+
+        let mut file = RotatingFile::open(&path, Rotation::Size(10), 2, true).unwrap();
+        writeln!(file, "aaaaaaaa").unwrap();
+        writeln!(file, "bbbbbbbb").unwrap();
+        drop(file);
+
+        let mut contents = String::new();
+        GzDecoder::new(File::open(dir.join("app.log.1.gz")).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "aaaaaaaa\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Shared in-memory writer standing in for the log file
+    #[derive(Clone, Default)]
+    struct Captured(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Captured {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_json_layer_includes_span_and_event_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = Registry::default().with(file_layer(LogFormat::Json, move || writer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("connection", conn_id = 7_u64);
+            let _entered = span.enter();
+            tracing::error!(event = "connect_failed", error_kind = "connection", "Connection failed");
+        });
+
+        let json: serde_json::Value = serde_json::from_str(captured.contents().trim()).unwrap();
+        assert_eq!(json["level"], "ERROR");
+        assert_eq!(json["message"], "Connection failed");
+        assert_eq!(json["event"], "connect_failed");
+        assert_eq!(json["error_kind"], "connection");
+        assert_eq!(json["span"]["name"], "connection");
+        assert_eq!(json["span"]["conn_id"], 7);
+    }
+
+    #[test]
+    fn test_text_layer_includes_span_fields() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = Registry::default().with(file_layer(LogFormat::Text, move || writer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("connection", conn_id = 3_u64);
+            let _entered = span.enter();
+            tracing::info!("Connected");
+        });
+
+        let line = captured.contents();
+        assert!(line.contains("connection{conn_id=3}"));
+        assert!(line.contains("Connected"));
+    }
+
+    #[test]
+    fn test_log_levels_change_while_running() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+        let (_, file_handle) = reload::Layer::<LevelFilter, Registry>::new(LevelFilter::WARN);
+        let levels = LogLevels { console: handle, file: file_handle };
+        let subscriber = Registry::default().with(file_layer(LogFormat::Text, move || writer.clone()).with_filter(level));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("before reload");
+            assert_eq!(levels.current().0, LevelFilter::INFO);
+            levels.console.reload(LevelFilter::DEBUG).unwrap();
+            tracing::debug!("after reload");
+        });
+
+        let contents = captured.contents();
+        assert!(!contents.contains("before reload"));
+        assert!(contents.contains("after reload"));
+        // The file layer was dropped, so changing both levels reports it
+        assert!(levels.set(LevelFilter::TRACE, LevelFilter::TRACE).is_err());
+    }
+
+    #[test]
+    fn test_log_records_are_bridged() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = Registry::default().with(file_layer(LogFormat::Text, move || writer.clone()));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let logger = LogTracer::new();
+            let record = tracing_log::log::Record::builder()
+                .args(format_args!("from the log facade"))
+                .level(tracing_log::log::Level::Warn)
+                .target("tungstenite")
+                .build();
+            tracing_log::log::Log::log(&logger, &record);
+        });
+
+        assert!(captured.contents().contains("from the log facade"));
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_otlp_layer_exports_spans_to_collector() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        // Minimal stand-in for an OTLP/HTTP collector accepting a single export
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 8192];
+            let n = stream.read(&mut buf).unwrap();
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let (layer, provider) = otlp::layer(&endpoint).unwrap();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let _span = tracing::info_span!("connection", conn_id = 1_u64).entered();
+        });
+
+        let request = collector.join().unwrap();
+        assert!(request.starts_with("POST /v1/traces"));
+        assert!(request.to_lowercase().contains("application/x-protobuf"));
+        let _ = provider.shutdown();
+    }
+}
@@ -1,101 +1,523 @@
+mod alerts;
 mod config;
+mod console;
+mod connection_state;
+mod control;
+mod dashboard;
 mod error;
+mod exit;
+mod fanout;
+mod http_api;
+mod limits;
 mod logging;
+mod metrics;
+mod order_manager;
+mod orders;
+mod ping;
+mod proxy;
+mod reload;
+mod risk;
+mod shutdown;
+mod sink;
+mod state;
+mod tls;
+mod transport;
+mod tui;
 mod utils;
+mod webhook;
 mod websocket;
 
+#[cfg(test)]
+mod test_support;
+
+use std::io::Write;
 use std::process::ExitCode;
 use std::time::Duration;
-use std::thread::sleep;
 use std::env::var;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
 
-use clap::{value_parser, ValueEnum, Arg, ArgAction, Command};
-use log::{error, info};
+use chrono::{DateTime, Utc};
+use clap::{parser::ValueSource, value_parser, ValueEnum, Arg, ArgAction, ArgMatches, Command};
+use serde_json::json;
+use tracing::{error, info, info_span, warn};
 use tungstenite::Message;
+use tungstenite::protocol::frame::coding::CloseCode;
 
-use config::Config;
+use alerts::Alerts;
+use config::{Config, Settings};
+use console::console;
+use connection_state::{ConnectionLifecycle, ConnectionState, StateChange};
+use control::{Control, ControlAction, ControlRequest, ControlServer};
 use error::AppError;
-use logging::setup_logging;
+use exit::{ExitStatus, RunStats, RunSummary};
+use fanout::Fanout;
+use logging::{parse_level, setup_logging, LogFormat, LogOptions, Rotation};
+use orders::{OrderConfig, Orders};
+use reload::{EnvFile, Reloader};
+use risk::RiskGate;
+use shutdown::Shutdown;
+use sink::{Sink, SinkConfig};
+use state::SharedState;
+use tui::Tui;
+use webhook::{WebhookConfig, Webhooks};
 use websocket::WebSocketClient;
 
 mod build_date {
     include!(concat!(env!("OUT_DIR"), "/build_date.rs"));
 }
 
-fn run(shutdown: Arc<AtomicBool>) -> Result<(), AppError> {
-    // Load configuration from environment
-    let config = Config::from_env()?;
+// How long messages still queued for the message bus may take to publish on exit
+const SINK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long queued webhook notifications may take to deliver on exit
+const WEBHOOK_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Process-wide handles shared by every run of the client
+struct RunContext {
+    shutdown: Shutdown,
+    reloader: Reloader,
+    /// Configuration variables each run starts from, replaced on reload
+    settings: Settings,
+    started_at: DateTime<Utc>,
+    state: SharedState,
+    /// Actions requested over the control socket
+    control: Receiver<ControlRequest>,
+    /// Re-broadcasts received messages to local consumers, if enabled
+    fanout: Option<Fanout>,
+    /// Publishes received messages to a message bus, if enabled
+    sink: Option<Sink>,
+    /// Notifies webhook endpoints of connection and account events, if enabled
+    webhooks: Option<Webhooks>,
+    /// Writes order requests and matches their acknowledgements, if enabled
+    orders: Option<Orders>,
+}
+
+fn run(ctx: &mut RunContext, stats: &mut RunStats) -> Result<(), AppError> {
+    let _span = info_span!("run").entered();
+    stats.attempts += 1;
+
+    // Load configuration from the environment, or what was reloaded since
+    let mut config = Config::from_settings(&ctx.settings)?;
+    ctx.state.set_server_url(&config.server_url);
+
+    // Report connection state changes on the console and in the shared state
+    let mut lifecycle = ConnectionLifecycle::new();
+    let state_changes = lifecycle.subscribe();
+    let state = ctx.state.clone();
+    lifecycle.on_change(move |change| state.connection_changed(change));
+    if let Some(webhooks) = ctx.webhooks.clone() {
+        lifecycle.on_change(move |change| webhooks.connection_changed(change));
+    }
+    // Requests written on a connection which goes away are never answered
+    if let Some(orders) = ctx.orders.clone() {
+        lifecycle.on_change(move |change| {
+            let connected = |state: &ConnectionState| matches!(state, ConnectionState::Open | ConnectionState::Degraded);
+            if connected(&change.from) && !connected(&change.to) {
+                orders.connection_lost(&change.reason);
+            } else if !connected(&change.from) && change.to == ConnectionState::Open {
+                orders.connected();
+            }
+        });
+    }
 
     // Initialize WebSocket connection
-    let mut client = WebSocketClient::new(config.clone())?;
+    let client = WebSocketClient::new(connection_config(&config, ctx), lifecycle);
+    print_state_changes(&state_changes);
+    let mut client = client?;
 
     // Log the configuration info
     info!("{}", client.get_config_info());
+    let handshake = client.handshake_response();
+    console!("Connected: HTTP {} from {} (correlation id {})", handshake.status,
+             handshake.header("server").unwrap_or("unknown server"), handshake.correlation_id);
+
+    let result = process_messages(&mut client, &mut config, ctx, &state_changes, stats);
+
+    stats.reconnects += client.reconnect_count();
+    info!(parent: client.span(), event = "ping_rtt", rtt = %client.rtt_summary(), "Ping round-trip times");
+    let reason = match ctx.shutdown.reason() {
+        Some(reason) => format!("client shutting down ({})", reason),
+        None => "client shutting down".to_string(),
+    };
+    client.close(&reason);
+    print_state_changes(&state_changes);
+    result
+}
 
+fn process_messages(
+    client: &mut WebSocketClient,
+    config: &mut Config,
+    ctx: &mut RunContext,
+    state_changes: &Receiver<StateChange>,
+    stats: &mut RunStats,
+) -> Result<(), AppError> {
     // Send an initial ping to verify connection
     client.send_ping_with_retry(3)?;
 
-    let mut count = 0;
-
     // Main processing loop
     loop {
+        print_state_changes(state_changes);
+
         // Check for shutdown signal
-        if shutdown.load(Ordering::Relaxed) {
+        if ctx.shutdown.is_requested() {
             info!("Shutdown signal received, closing gracefully");
-            println!("Shutdown signal received, closing gracefully");
+            console!("Shutdown signal received, closing gracefully");
+            break;
+        }
+        if ctx.reloader.take_request() {
+            apply_reload(client, config, ctx)?;
+        }
+        handle_control_requests(client, config, ctx)?;
+        if let Some(orders) = &ctx.orders {
+            // The failed request is already answered with NotSent, the rest wait for the new connection
+            if let Err(e) = orders.send_queued(client) {
+                error!(parent: client.span(), event = "order_write_failed", error_kind = e.kind(), "Error sending order: {}", e);
+                if !e.is_retryable() {
+                    return Err(e);
+                }
+                if let Err(reconnect_err) = client.reconnect(&format!("order write failed: {}", e)) {
+                    error!("Failed to reconnect: {}", reconnect_err);
+                    return Err(reconnect_err);
+                }
+                info!(parent: client.span(), event = "reconnected", "Reconnected successfully");
+            }
+        }
+        // Reconnects authenticate with a new token
+        ctx.state.set_token_expiry(client.handshake_response().token_expires_at);
+        if run_limit_reached(config, ctx.started_at, stats) {
             break;
         }
         match client.read_message() {
-            Ok(msg) => {
+            // Nothing arrived yet, keep waiting for the next message
+            Ok(None) => {
+                send_due_ping(client);
+                continue;
+            }
+            Ok(Some(msg)) => {
+                let _span = info_span!(parent: client.span(), "process_message", bytes = msg.len()).entered();
                 if !msg.is_empty() {
+                    stats.messages_received += 1;
+                    if let Message::Text(text) = &msg {
+                        ctx.state.record_message(text);
+                        if let Some(fanout) = &ctx.fanout {
+                            fanout.publish(text);
+                        }
+                        if let Some(sink) = &ctx.sink {
+                            sink.publish(text);
+                        }
+                        if let Some(webhooks) = &ctx.webhooks {
+                            webhooks.record_message(text);
+                        }
+                        if let Some(orders) = &ctx.orders {
+                            orders.handle_message(text);
+                        }
+                    }
                     info!("Received msg: {}", msg);
-                    println!("Received message containing {:?} bytes", msg.len());
+                    if !ctx.state.output_paused() {
+                        console!("Received message containing {:?} bytes", msg.len());
+                    }
                     
+                    // A close frame from the server ends this connection
+                    if let Message::Close(frame) = &msg {
+                        let err = match frame {
+                            Some(frame) => AppError::ServerClosed { code: frame.code, reason: frame.reason.to_string() },
+                            None => AppError::ServerClosed { code: CloseCode::Status, reason: String::new() },
+                        };
+                        error!(parent: client.span(), event = "server_closed", error_kind = err.kind(), "{}", err);
+                        if !err.is_retryable() {
+                            return Err(err);
+                        }
+                        client.reconnect(&err.to_string())?;
+                        continue;
+                    }
+
+                    // Match pongs against the pings we sent to track round-trip time
+                    if let Message::Pong(payload) = &msg {
+                        client.handle_pong(payload);
+                    }
+
                     // Send a pong response if we received a ping
                     if msg.is_ping() {
                         info!("Received ping, sending pong");
                         if let Err(e) = client.write_message(Message::Pong(vec![].into())) {
                             error!("Failed to send pong: {}", e);
                             // Try to reconnect if sending fails
-                            if let Err(e) = client.reconnect() {
+                            if let Err(e) = client.reconnect(&format!("failed to send pong: {}", e)) {
                                 error!("Failed to reconnect: {}", e);
-                                break;
+                                return Err(e);
                             }
                         }
                     }
                 } else {
-                    println!("Received empty message");
+                    console!("Received empty message");
                 }
             },
             Err(e) => {
-                error!("Error reading message: {}", e);
+                error!(parent: client.span(), event = "read_failed", error_kind = e.kind(), "Error reading message: {}", e);
+                if !e.is_retryable() {
+                    return Err(e);
+                }
                 // Try to reconnect on error
-                if let Err(reconnect_err) = client.reconnect() {
+                if let Err(reconnect_err) = client.reconnect(&format!("read failed: {}", e)) {
                     error!("Failed to reconnect: {}", reconnect_err);
-                    break;
+                    return Err(reconnect_err);
                 }
-                info!("Reconnected successfully");
+                info!(parent: client.span(), event = "reconnected", "Reconnected successfully");
             }
         }
         
-        count += 1;
-        if count >= config.epoch_count {
-            println!("Power.Trade websocket client closing after {} epochs exceeded", count);
-            info!("Power.Trade websocket client closing after {} epochs exceeded", count);
+        send_due_ping(client);
+
+        stats.epochs += 1;
+        if run_limit_reached(config, ctx.started_at, stats) {
             break;
-        } else {
-            println!("Power.Trade websocket client sleeping for {} secs on iteration {} of {}", 
-                     config.sleep_duration, count, config.epoch_count);
         }
+        // Keep reading until the pong arrives, its round-trip time would include the sleep otherwise
+        if client.awaiting_pong() {
+            continue;
+        }
+        let of_epochs = config.limits.epochs.map_or(String::new(), |epochs| format!(" of {}", epochs));
+        console!("Power.Trade websocket client sleeping for {} secs on iteration {}{} (state {} since {}, ping rtt {})", 
+                 config.sleep_duration, stats.epochs, of_epochs, client.state(),
+                 client.state_since().format("%H:%M:%S"), client.rtt_summary());
         
-        sleep(Duration::from_secs(config.sleep_duration));
+        // Wake up in time for a duration or timestamp limit, and at once for order requests to send or acknowledge
+        let sleep = Duration::from_secs(config.sleep_duration);
+        let orders_busy = || ctx.orders.as_ref().is_some_and(Orders::busy);
+        if ctx.shutdown.sleep_unless(config.limits.remaining(ctx.started_at).map_or(sleep, |remaining| remaining.min(sleep)), orders_busy) {
+            info!("Shutdown signal received, closing gracefully");
+            console!("Shutdown signal received, closing gracefully");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Check the run limits, recording and reporting the one which ends the run
+fn run_limit_reached(config: &Config, started_at: DateTime<Utc>, stats: &mut RunStats) -> bool {
+    match config.limits.reached(started_at, stats) {
+        Some(limit) => {
+            console!("Power.Trade websocket client closing, run limit of {} reached", limit);
+            info!(event = "run_limit_reached", limit = %limit, epochs = stats.epochs,
+                  messages = stats.messages_received, "Power.Trade websocket client closing, run limit of {} reached", limit);
+            stats.limit_reached = Some(limit);
+            true
+        }
+        None => false,
+    }
+}
+
+// Keep measuring round-trip time with periodic pings
+fn send_due_ping(client: &mut WebSocketClient) {
+    if client.ping_due() {
+        if let Err(e) = client.send_ping() {
+            error!(parent: client.span(), event = "ping_failed", error_kind = e.kind(), "Failed to send ping: {}", e);
+        }
+    }
+}
+
+// Configuration of the connection itself: `config` with what order entry asks for at login
+fn connection_config(config: &Config, ctx: &RunContext) -> Config {
+    let mut config = config.clone();
+    if let Some(orders) = &ctx.orders {
+        config.server_url = orders.login_url(&config.server_url);
+    }
+    config
+}
+
+// Reload the configuration and apply it, reconnecting when the URL or credentials changed.
+// A configuration which fails to load is reported and the current one kept.
+fn apply_reload(client: &mut WebSocketClient, config: &mut Config, ctx: &mut RunContext) -> Result<(), AppError> {
+    let reload = match ctx.reloader.reload(config, &ctx.settings) {
+        Ok(reload) => reload,
+        Err(e) => {
+            error!(event = "config_reload_failed", "Configuration reload failed, keeping the current configuration: {}", e);
+            console!("Configuration reload failed, keeping the current configuration: {}", e);
+            return Ok(());
+        }
+    };
+
+    let reconnect = reload.needs_reconnect();
+    info!(event = "config_reloaded", changes = reload.changes.len(), reconnect,
+          "Configuration reloaded with {} changes", reload.changes.len());
+    console!("Configuration reloaded: {} changes{}", reload.changes.len(), if reconnect { ", reconnecting" } else { "" });
+    for change in &reload.changes {
+        console!("  {}: {} -> {} ({})", change.setting, change.old, change.new, change.applies);
+    }
+    client.apply_config(connection_config(&reload.config, ctx));
+    *config = reload.config;
+    ctx.settings = reload.settings;
+    ctx.state.set_server_url(&config.server_url);
+    if reconnect {
+        client.reconnect("configuration changed")?;
     }
-    
     Ok(())
 }
 
+// Carry out the actions requested over the control socket. A failed reconnect
+// ends the run like any other, an invalid subscription change is only reported.
+fn handle_control_requests(client: &mut WebSocketClient, config: &mut Config, ctx: &mut RunContext) -> Result<(), AppError> {
+    while let Ok(request) = ctx.control.try_recv() {
+        let (feed, subscribe) = match &request.action {
+            ControlAction::Reconnect => (None, false),
+            ControlAction::Subscribe(feed) => (Some(feed.clone()), true),
+            ControlAction::Unsubscribe(feed) => (Some(feed.clone()), false),
+        };
+        let reason = match feed {
+            None => "reconnect requested over the control socket".to_string(),
+            Some(feed) => match config.with_subscription(&feed, subscribe) {
+                Ok(server_url) => {
+                    info!(event = "subscriptions_changed", feed = %feed, subscribe, "{} {} over the control socket",
+                          if subscribe { "Subscribing to" } else { "Unsubscribing from" }, feed);
+                    // Kept in the settings so retries use it, until the next configuration reload
+                    ctx.settings.set("PT_SERVER_URL", &server_url);
+                    config.server_url = server_url;
+                    client.apply_config(connection_config(config, ctx));
+                    ctx.state.set_server_url(&config.server_url);
+                    format!("subscriptions changed: {}", config.subscriptions().join(","))
+                }
+                Err(e) => {
+                    request.respond(Err(e));
+                    continue;
+                }
+            },
+        };
+        match client.reconnect(&reason) {
+            Ok(()) => request.respond(Ok(json!({
+                "conn_id": ctx.state.lock().conn_id,
+                "subscriptions": config.subscriptions(),
+            }))),
+            Err(e) => {
+                request.respond(Err(e.to_string()));
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Run the client, re-running it after retryable errors until retries are used up
+fn run_with_retries(ctx: &mut RunContext, stats: &mut RunStats) -> Result<(), AppError> {
+    let mut retries_used = 0;
+    loop {
+        match run(ctx, stats) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                error!(event = "run_failed", error_kind = e.kind(), "Error: {}", e);
+                ctx.state.record_event(format!("Run failed: {}", e));
+                if let Some(webhooks) = &ctx.webhooks {
+                    webhooks.run_failed(&e);
+                }
+                if !e.is_retryable() {
+                    error!(event = "run_aborted", error_kind = e.kind(),
+                           "Not retrying: {} errors will not be fixed by reconnecting. Exiting Power.Trade ws client", e.kind());
+                    return Err(e);
+                }
+                if ctx.shutdown.is_requested() {
+                    return Err(e);
+                }
+                // Read on every failure so a reloaded PT_MAX_RETRIES applies
+                let max_retries = ctx.settings.var("PT_MAX_RETRIES").unwrap_or_else(|_| "5".to_string())
+                    .parse::<u32>().unwrap_or(5);
+                if retries_used < max_retries {
+                    retries_used += 1;
+                    info!("Retrying... attempts left: {}", max_retries - retries_used);
+                    if ctx.shutdown.sleep(Duration::from_secs(5)) {
+                        return Err(e);
+                    }
+                } else {
+                    error!("Max connection retries reached. Exiting Power.Trade ws client");
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+// Emit the run summary on stdout (and to `summary_file` if given) and convert to an exit code
+fn finish(summary: RunSummary, summary_file: Option<&String>) -> ExitCode {
+    let json = summary.to_json();
+    info!(event = "run_summary", status = ?summary.status, exit_code = summary.exit_code, "Run summary: {}", json);
+    println!("{}", json);
+    if let Some(path) = summary_file {
+        if let Err(e) = std::fs::write(path, format!("{}\n", json)) {
+            eprintln!("Failed to write run summary to '{}': {}", path, e);
+        }
+    }
+    summary.status.into()
+}
+
+// Run limits given on the command line replace those of the environment file as a whole.
+// They are set before the file is loaded so they also take precedence on reload.
+fn override_run_limits(matches: &ArgMatches) {
+    // In the order of limits::LIMIT_VARS
+    let flags = [
+        matches.get_one::<u32>("epochs").map(u32::to_string),
+        matches.get_one::<String>("run-for").cloned(),
+        matches.get_one::<u64>("max-messages").map(u64::to_string),
+        matches.get_one::<String>("run-until").cloned(),
+        matches.get_flag("unlimited").then(|| "true".to_string()),
+    ];
+    if flags.iter().all(Option::is_none) {
+        return;
+    }
+    for (name, value) in limits::LIMIT_VARS.iter().zip(flags) {
+        std::env::set_var(name, value.unwrap_or_default());
+    }
+}
+
+// `ctl` subcommand: send one command to a running client and print its answer
+fn ctl(matches: &ArgMatches) -> ExitCode {
+    let Some(socket) = matches.get_one::<String>("socket").cloned().or_else(|| var("PT_CONTROL_SOCKET").ok()) else {
+        eprintln!("No control socket given, use --socket or set PT_CONTROL_SOCKET");
+        return ExitStatus::Config.into();
+    };
+    let command = matches.get_many::<String>("command").unwrap_or_default()
+        .map(String::as_str).collect::<Vec<_>>().join(" ");
+
+    let path = std::path::Path::new(&socket);
+    let response = if command == "watch-alerts" {
+        // Runs until interrupted, printing one alert per line
+        control::watch_alerts_at(path, |alert| {
+            println!("{}", alert);
+            let _ = std::io::stdout().flush();
+        })
+    } else {
+        control::send_command(path, &command)
+    };
+    match response {
+        Ok(response) if response["ok"] == true => {
+            println!("{}", serde_json::to_string_pretty(&response["result"]).unwrap_or_default());
+            ExitStatus::Success.into()
+        }
+        Ok(response) => {
+            eprintln!("{}", response["error"].as_str().unwrap_or("command failed"));
+            ExitStatus::Failure.into()
+        }
+        Err(e) => {
+            eprintln!("Failed to reach the client on {}: {}", socket, e);
+            ExitStatus::NetworkExhausted.into()
+        }
+    }
+}
+
+// An explicitly passed level flag wins over the environment variable, which wins over the flag's default
+fn level_setting(matches: &ArgMatches, flag: &str, env_var: &str) -> Option<String> {
+    if matches.value_source(flag) == Some(ValueSource::CommandLine) {
+        return matches.get_one::<String>(flag).cloned();
+    }
+    var(env_var).ok()
+}
+
+fn print_state_changes(changes: &Receiver<StateChange>) {
+    for change in changes.try_iter() {
+        console!("Connection state {} -> {} at {} ({})", change.from, change.to,
+                 change.at.format("%H:%M:%S%.3f"), change.reason);
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Environment {
     Development,
@@ -116,6 +538,26 @@ fn main() -> ExitCode {
     let matches = Command::new("Power.Trade Websocket Client")
         .version(static_version)  // Use as_str() to get a &str from String
         .about("Client for Power.Trade WebSocket API")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("ctl")
+                .about("Send a command to a running client over its control socket")
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .help("Control socket of the running client (defaults to PT_CONTROL_SOCKET)")
+                )
+                .arg(
+                    Arg::new("command")
+                        .required(true)
+                        .num_args(1..)
+                        .help("status, pause, resume, book [symbol], subscriptions, subscribe <feed>, unsubscribe <feed>, reconnect, rotate-logs, alerts, watch-alerts, order <buy|sell> <symbol> <quantity> [price] [gtc|ioc|fok], cancel <symbol> <order>, cancel-all [symbol], amend <symbol> <order> [price=<price>] [quantity=<quantity>], orders [client_order_id], risk, kill-switch [on|off] or help")
+                )
+        )
+        .subcommand(
+            Command::new("tui")
+                .about("Run the client with a terminal dashboard in place of the console output, e.g. '--env test tui'")
+        )
         .arg(
             Arg::new("env")
                 .action(ArgAction::Set)
@@ -139,14 +581,144 @@ fn main() -> ExitCode {
                 .help("Path to log file")
                 .default_value("logs/app.log")
         )
+        .arg(
+            Arg::new("console-log-level")
+                .long("console-log-level")
+                .help("Override logging level for the console (defaults to --log-level)")
+        )
+        .arg(
+            Arg::new("file-log-level")
+                .long("file-log-level")
+                .help("Override logging level for the log file (defaults to --log-level)")
+        )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .help("Format of log file records (text, json)")
+                .default_value("text")
+                .value_parser(value_parser!(LogFormat))
+        )
+        .arg(
+            Arg::new("log-rotation")
+                .long("log-rotation")
+                .help("When to rotate the log file (never, daily, size)")
+                .default_value("never")
+                .value_parser(["never", "daily", "size"])
+        )
+        .arg(
+            Arg::new("log-max-size")
+                .long("log-max-size")
+                .help("Maximum log file size in MB before rotating (with --log-rotation size)")
+                .default_value("100")
+                .value_parser(value_parser!(u64))
+        )
+        .arg(
+            Arg::new("log-max-files")
+                .long("log-max-files")
+                .help("Number of rotated log files to keep")
+                .default_value("5")
+                .value_parser(value_parser!(usize))
+        )
+        .arg(
+            Arg::new("log-compress")
+                .long("log-compress")
+                .help("Gzip rotated log files")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("summary-file")
+                .long("summary-file")
+                .help("Also write the JSON run summary emitted on exit to this file")
+        )
+        .arg(
+            Arg::new("epochs")
+                .long("epochs")
+                .help("Stop after this many read iterations (PT_EPOCH_COUNT)")
+                .value_parser(value_parser!(u32).range(1..))
+        )
+        .arg(
+            Arg::new("run-for")
+                .long("run-for")
+                .help("Stop after running this long, e.g. 90s, 30m, 8h (PT_RUN_DURATION)")
+                .value_parser(|value: &str| limits::parse_duration(value).map(|_| value.to_string()))
+        )
+        .arg(
+            Arg::new("max-messages")
+                .long("max-messages")
+                .help("Stop after receiving this many messages (PT_MAX_MESSAGES)")
+                .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("run-until")
+                .long("run-until")
+                .help("Stop at this RFC 3339 time, e.g. 2025-01-31T17:00:00Z (PT_RUN_UNTIL)")
+                .value_parser(|value: &str| DateTime::parse_from_rfc3339(value).map(|_| value.to_string()))
+        )
+        .arg(
+            Arg::new("unlimited")
+                .long("unlimited")
+                .help("Run until stopped (PT_RUN_UNLIMITED)")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["epochs", "run-for", "max-messages", "run-until"])
+        )
+        .arg(
+            Arg::new("control-socket")
+                .long("control-socket")
+                .help("Accept commands on this Unix domain socket, see the 'ctl' subcommand (PT_CONTROL_SOCKET)")
+        )
+        .arg(
+            Arg::new("http-addr")
+                .long("http-addr")
+                .help("Serve the JSON status API on this address, e.g. 127.0.0.1:8080 (PT_HTTP_ADDR)")
+        )
+        .arg(
+            Arg::new("fanout-addr")
+                .long("fanout-addr")
+                .help("Re-broadcast received messages to local WebSocket consumers on this address, e.g. 127.0.0.1:9001 (PT_FANOUT_ADDR)")
+        )
+        .arg(
+            Arg::new("alert-rules")
+                .long("alert-rules")
+                .help("Evaluate the alert rules in this JSON file against the client state (PT_ALERT_RULES)")
+        )
+        .arg(
+            Arg::new("risk-limits")
+                .long("risk-limits")
+                .help("Check every order against the risk limits in this JSON file (PT_RISK_LIMITS)")
+        )
+        .arg(
+            Arg::new("no-config-watch")
+                .long("no-config-watch")
+                .help("Only reload the environment file on SIGHUP, not when it changes on disk")
+                .action(ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("otlp-endpoint")
+                .long("otlp-endpoint")
+                .help("Export tracing spans to this OTLP/HTTP collector, e.g. http://localhost:4318 (requires the 'otlp' feature)")
+        )
         .get_matches();
+
+    if let Some(("ctl", ctl_matches)) = matches.subcommand() {
+        return ctl(ctl_matches);
+    }
+    let with_tui = matches.subcommand_name() == Some("tui");
+    if with_tui && !matches.contains_id("env") {
+        eprintln!("The tui subcommand runs the client and needs its options first, e.g. '--env test tui'");
+        return ExitStatus::Config.into();
+    }
     
+    let started_at = Utc::now();
+    let mut stats = RunStats::default();
+    let summary_file = matches.get_one::<String>("summary-file");
+
     // Use the same version string for logging
     println!("Starting websocket client for power.trade [{}]", static_version);
     info!("Starting websocket client for power.trade [{}]", static_version);
     
     // Get environment and load appropriate .env file
     let pt_env = matches.get_one::<Environment>("env").expect("env is required");
+    let environment = pt_env.to_possible_value().map(|v| v.get_name().to_string());
 
     let env_file = match pt_env {
         Environment::Development => {
@@ -163,70 +735,301 @@ fn main() -> ExitCode {
         },
     };
 
-    if let Err(e) = dotenvy::from_filename(env_file) {
-        eprintln!("Failed to load environment file '{}': {}", env_file, e);
-        eprintln!("Please ensure the file exists and is readable.");
-        return ExitCode::FAILURE;
-    }
-    
+    override_run_limits(&matches);
+    let env_file = match EnvFile::load(env_file) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Failed to load environment file '{}': {}", env_file, e);
+            eprintln!("Please ensure the file exists and is readable.");
+            let error = AppError::Config(format!("Failed to load environment file '{}': {}", env_file, e));
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+    // The environment is not changed from here on, reloads build settings of their own
+    let settings = Settings::from_env();
+
     // Setup logging
     let log_level = matches.get_one::<String>("log-level").unwrap();
     let log_file = matches.get_one::<String>("log-file").unwrap();
-    
-    let level = match log_level.to_lowercase().as_str() {
-        "error" => log::LevelFilter::Error,
-        "warn" => log::LevelFilter::Warn,
-        "info" => log::LevelFilter::Info,
-        "debug" => log::LevelFilter::Debug,
-        "trace" => log::LevelFilter::Trace,
-        _ => log::LevelFilter::Info,
-    };
-    
-    if let Err(e) = setup_logging(log_file, level) {
-        eprintln!("Failed to initialize logging: {}", e);
-        return ExitCode::FAILURE;
+
+    let level = parse_level(&level_setting(&matches, "log-level", "PT_LOG_LEVEL").unwrap_or(log_level.clone()));
+    let mut log_options = LogOptions::new(log_file, level);
+    if let Some(console_level) = level_setting(&matches, "console-log-level", "PT_CONSOLE_LOG_LEVEL") {
+        log_options.console_level = parse_level(&console_level);
     }
+    if let Some(file_level) = level_setting(&matches, "file-log-level", "PT_FILE_LOG_LEVEL") {
+        log_options.file_level = parse_level(&file_level);
+    }
+    log_options.format = *matches.get_one::<LogFormat>("log-format").unwrap();
+    log_options.rotation = match matches.get_one::<String>("log-rotation").unwrap().as_str() {
+        "daily" => Rotation::Daily,
+        "size" => {
+            let max_size = *matches.get_one::<u64>("log-max-size").unwrap();
+            match max_size.checked_mul(1024 * 1024) {
+                Some(bytes) => Rotation::Size(bytes),
+                None => {
+                    eprintln!("--log-max-size {} MB is too large", max_size);
+                    let error = AppError::Config(format!("--log-max-size {} MB is too large", max_size));
+                    return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+                }
+            }
+        },
+        _ => Rotation::Never,
+    };
+    log_options.max_files = *matches.get_one::<usize>("log-max-files").unwrap();
+    log_options.compress = matches.get_flag("log-compress");
+    log_options.otlp_endpoint = matches.get_one::<String>("otlp-endpoint").cloned();
 
-    // Setup graceful shutdown handler
-    let shutdown = Arc::new(AtomicBool::new(false));
-    let shutdown_clone = shutdown.clone();
+    // Held until exit so buffered spans are exported on shutdown
+    let logging = match setup_logging(&log_options) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Failed to initialize logging: {}", e);
+            return finish(RunSummary::new(ExitStatus::Failure, environment, started_at, &stats, None), summary_file);
+        }
+    };
 
-    ctrlc::set_handler(move || {
-        info!("Received interrupt signal (Ctrl+C)");
-        println!("\nReceived interrupt signal, shutting down gracefully...");
-        shutdown_clone.store(true, Ordering::Relaxed);
-    }).expect("Error setting Ctrl-C handler");
+    // Setup graceful shutdown on SIGINT (Ctrl+C) and SIGTERM
+    let shutdown = Shutdown::new();
+    shutdown.install_signal_handlers(ExitStatus::Interrupted.code().into())
+        .expect("Error setting signal handlers");
+    shutdown.on_flush("stdout", || std::io::stdout().flush());
 
-    // Run with retry logic
-    let max_retries = var("PT_MAX_RETRIES").unwrap_or_else(|_| "5".to_string())
-        .parse::<u32>().unwrap_or(5);
+    // Reload configuration on SIGHUP and when the environment file changes
+    let reloader = Reloader::new(env_file).with_log_levels(logging.levels());
+    if let Err(e) = reloader.install_signal_handler() {
+        error!("Failed to listen for SIGHUP, configuration reload on signal disabled: {}", e);
+    }
+    if !matches.get_flag("no-config-watch") {
+        if let Err(e) = reloader.watch_file() {
+            error!("Failed to watch environment file, configuration reload on change disabled: {}", e);
+        }
+    }
 
-    let mut retries = max_retries;
-    loop {
-        match run(shutdown.clone()) {
-            Ok(()) => break,
+    let state = SharedState::new();
+
+    // Serve the status API, if configured
+    let http_addr = matches.get_one::<String>("http-addr").cloned()
+        .or_else(|| settings.value("PT_HTTP_ADDR"));
+    if let Some(addr) = http_addr {
+        match http_api::start(&addr, state.clone()) {
+            Ok(local_addr) => println!("HTTP status API listening on http://{}", local_addr),
+            Err(e) => warn!(event = "http_failed", "HTTP status API on {} not available: {}", addr, e),
+        }
+    }
+
+    // Re-broadcast received messages to local consumers, if configured
+    let fanout_addr = matches.get_one::<String>("fanout-addr").cloned()
+        .or_else(|| settings.value("PT_FANOUT_ADDR"));
+    let fanout = fanout_addr.and_then(|addr| {
+        let allowed_origins = settings.value("PT_FANOUT_ALLOWED_ORIGINS")
+            .map_or(Vec::new(), |origins| origins.split(',').map(str::trim).filter(|o| !o.is_empty()).map(str::to_string).collect());
+        let fanout = Fanout::new().with_allowed_origins(allowed_origins);
+        match fanout.start(&addr) {
+            Ok(local_addr) => {
+                println!("Fan-out server listening on ws://{}", local_addr);
+                Some(fanout)
+            }
             Err(e) => {
-                error!("Error: {}", e);
-                if retries > 0 {
-                    retries -= 1;
-                    info!("Retrying... attempts left: {}", retries);
-                    sleep(Duration::from_secs(5));
-                } else {
-                    error!("Max connection retries reached. Exiting Power.Trade ws client");
-                    return ExitCode::FAILURE;
-                }
+                warn!(event = "fanout_failed", "Fan-out server on {} not available: {}", addr, e);
+                None
             }
         }
+    });
+
+    // Publish received messages to a message bus, if configured
+    let sink = match SinkConfig::from_settings(&settings).and_then(|config| config.map(|config| Sink::start(&config)).transpose()) {
+        Ok(sink) => sink,
+        Err(e) => {
+            error!(event = "sink_failed", "{}", e);
+            let error = AppError::Config(e);
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+    if let Some(sink) = sink.clone() {
+        shutdown.on_flush("sink", move || sink.flush(SINK_FLUSH_TIMEOUT));
     }
-    
-    ExitCode::SUCCESS
+
+    // Notify webhook endpoints of selected events, if configured
+    let webhooks = WebhookConfig::from_settings(&settings).and_then(|config| config
+        .map(|config| Webhooks::start(config, environment.clone()).map_err(|e| format!("Failed to start webhooks: {}", e)))
+        .transpose());
+    let webhooks = match webhooks {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            error!(event = "webhooks_failed", "{}", e);
+            let error = AppError::Config(e);
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+    if let Some(webhooks) = webhooks.clone() {
+        shutdown.on_flush("webhooks", move || webhooks.flush(WEBHOOK_FLUSH_TIMEOUT));
+    }
+
+    // Evaluate alert rules against the client state, if configured
+    let alert_rules = matches.get_one::<String>("alert-rules").cloned()
+        .or_else(|| settings.value("PT_ALERT_RULES"));
+    let alerts = alert_rules.map(|path| alerts::load_rules(std::path::Path::new(&path))
+        .and_then(|rules| Alerts::start(rules, state.clone(), webhooks.clone()).map_err(|e| format!("Failed to start alerts: {}", e))))
+        .transpose();
+    let alerts = match alerts {
+        Ok(alerts) => alerts,
+        Err(e) => {
+            error!(event = "alerts_failed", "{}", e);
+            let error = AppError::Config(e);
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+
+    // Running services take reloaded settings too, alert rules come from --alert-rules when given
+    let reloader = reloader.with_sink(sink.clone()).with_webhooks(webhooks.clone())
+        .with_alerts(alerts.clone(), matches.get_one::<String>("alert-rules").map(PathBuf::from));
+
+    // Place, amend and cancel orders over the connection, if enabled
+    let orders = match OrderConfig::from_settings(&settings) {
+        Ok(config) => config.map(Orders::new),
+        Err(e) => {
+            error!(event = "orders_failed", "{}", e);
+            let error = AppError::Config(e);
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+
+    // Check every order against the risk limits, if configured
+    let risk_limits = matches.get_one::<String>("risk-limits").cloned()
+        .or_else(|| settings.value("PT_RISK_LIMITS"));
+    let risk = match risk_limits.map(|path| risk::load_limits(std::path::Path::new(&path))).transpose() {
+        Ok(limits) => limits.map(|limits| RiskGate::new(limits, state.clone())),
+        Err(e) => {
+            error!(event = "risk_limits_failed", "{}", e);
+            let error = AppError::Config(e);
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+    let orders = match (orders, risk) {
+        (Some(orders), Some(risk)) => Some(orders.with_risk(risk)),
+        (Some(orders), None) => {
+            warn!(event = "risk_limits_missing",
+                  "Order entry is enabled without risk limits, orders are sent unchecked; set PT_RISK_LIMITS to check them");
+            Some(orders)
+        }
+        (None, None) => None,
+        (None, Some(_)) => {
+            warn!(event = "risk_limits_unused", "Risk limits are configured but order entry is not enabled");
+            None
+        }
+    };
+
+    // Accept commands from `ctl` on the control socket, if configured
+    let (actions, control) = channel();
+    let control_socket = matches.get_one::<String>("control-socket").cloned()
+        .or_else(|| settings.value("PT_CONTROL_SOCKET"))
+        .map(PathBuf::from);
+    let _control_server = control_socket.and_then(|path| {
+        let mut control = Control::new(state.clone(), Some(logging.file()), actions);
+        if let Some(alerts) = alerts {
+            control = control.with_alerts(alerts);
+        }
+        if let Some(orders) = orders.clone() {
+            control = control.with_orders(orders);
+        }
+        ControlServer::start(&path, control)
+            .map_err(|e| warn!(event = "control_failed", "Control socket {} not available: {}", path.display(), e))
+            .ok()
+    });
+
+    // Run with retry logic
+    // Draw the dashboard in place of the console output until the run ends
+    let tui = match with_tui.then(|| Tui::start(state.clone(), shutdown.clone())).transpose() {
+        Ok(tui) => tui,
+        Err(e) => {
+            error!(event = "tui_failed", "Terminal UI not available: {}", e);
+            let error = AppError::Config(format!("Terminal UI not available: {}", e));
+            return finish(RunSummary::new(ExitStatus::Config, environment, started_at, &stats, Some(&error)), summary_file);
+        }
+    };
+
+    let mut ctx = RunContext { shutdown: shutdown.clone(), reloader, settings, started_at, state, control, fanout, sink, webhooks, orders };
+    let result = run_with_retries(&mut ctx, &mut stats);
+    drop(tui);
+    // Nothing is sent once the run ends, so requests still waiting cannot complete
+    if let Some(orders) = &ctx.orders {
+        orders.stop();
+    }
+
+    let status = match &result {
+        _ if shutdown.is_requested() => ExitStatus::Interrupted,
+        Ok(()) => ExitStatus::Success,
+        Err(e) => ExitStatus::from_error(e),
+    };
+    shutdown.flush_all();
+    let mut summary = RunSummary::new(status, environment, started_at, &stats, result.as_ref().err());
+    summary.shutdown_reason = shutdown.reason();
+    finish(summary, summary_file)
 }
 
 // Tests module including dummy test
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::test_support::{spawn_ws_server, test_config};
+    use std::io::ErrorKind;
+
     #[test]
     fn test_dummy_001() {
         assert_eq!(true, true);
     }
+
+    #[test]
+    fn test_ping_round_trip_excludes_the_sleep() {
+        // Sends a message every 100ms, answering pings as it reads
+        let (url, server) = spawn_ws_server(|mut socket, _| {
+            socket.get_ref().set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            loop {
+                match socket.read() {
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        if socket.send(Message::text("{}")).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        let mut config = test_config(&url);
+        config.sleep_duration = 1;
+        config.ping_interval = 1;
+        config.limits = limits::RunLimits { duration: Some(Duration::from_millis(3500)), ..Default::default() };
+
+        let env_path = std::env::temp_dir().join(format!("client-rust-ws-ping-{}.env", std::process::id()));
+        std::fs::write(&env_path, "").unwrap();
+        let (_actions, control) = channel();
+        let mut ctx = RunContext {
+            shutdown: Shutdown::new(),
+            reloader: Reloader::new(EnvFile::load(&env_path).unwrap()),
+            settings: Settings::default(),
+            started_at: Utc::now(),
+            state: SharedState::new(),
+            control,
+            fanout: None,
+            sink: None,
+            webhooks: None,
+            orders: None,
+        };
+        let mut lifecycle = ConnectionLifecycle::new();
+        let state_changes = lifecycle.subscribe();
+        let mut client = WebSocketClient::new(config.clone(), lifecycle).unwrap();
+        let mut stats = RunStats::default();
+        process_messages(&mut client, &mut config, &mut ctx, &state_changes, &mut stats).unwrap();
+
+        // Pings sent before sleeping a second, each answered well within PT_PING_WARN_MS
+        let rtt = client.rtt_summary();
+        assert!(rtt.count >= 2, "{} pongs", rtt.count);
+        assert!(rtt.max < Duration::from_millis(config.ping_warn_ms), "round-trip {:?}", rtt.max);
+        assert_eq!(client.state(), ConnectionState::Open);
+        assert!(client.close("test finished"));
+        server.join().unwrap();
+        std::fs::remove_file(env_path).unwrap();
+    }
 }
@@ -0,0 +1,111 @@
+use hdrhistogram::Histogram;
+use std::fmt;
+use std::time::Duration;
+
+/// Histogram of latencies recorded in microseconds (1µs to 1 hour, 3 significant digits)
+pub struct LatencyHistogram {
+    histogram: Histogram<u64>,
+}
+
+/// Point-in-time summary of a `LatencyHistogram`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        let histogram = Histogram::new_with_bounds(1, 3_600_000_000, 3)
+            .expect("valid histogram bounds");
+        LatencyHistogram { histogram }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let micros = (latency.as_micros() as u64).max(1);
+        // Values above the upper bound are clamped rather than dropped
+        self.histogram.saturating_record(micros);
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        if self.histogram.is_empty() {
+            return LatencySummary::default();
+        }
+        LatencySummary {
+            count: self.histogram.len(),
+            p50: Duration::from_micros(self.histogram.value_at_quantile(0.50)),
+            p99: Duration::from_micros(self.histogram.value_at_quantile(0.99)),
+            max: Duration::from_micros(self.histogram.max()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.histogram.reset();
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} p50={:.1}ms p99={:.1}ms max={:.1}ms",
+            self.count,
+            self.p50.as_secs_f64() * 1000.0,
+            self.p99.as_secs_f64() * 1000.0,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_histogram_summary() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.summary(), LatencySummary::default());
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = LatencyHistogram::new();
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let summary = histogram.summary();
+        assert_eq!(summary.count, 100);
+        // hdrhistogram values are accurate to 3 significant digits
+        assert!((summary.p50.as_millis() as i64 - 50).abs() <= 1);
+        assert!((summary.p99.as_millis() as i64 - 99).abs() <= 1);
+        assert!((summary.max.as_millis() as i64 - 100).abs() <= 1);
+    }
+
+    #[test]
+    fn test_histogram_reset() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_millis(5));
+        histogram.reset();
+        assert_eq!(histogram.summary().count, 0);
+    }
+
+    #[test]
+    fn test_summary_display() {
+        let summary = LatencySummary {
+            count: 2,
+            p50: Duration::from_micros(1500),
+            p99: Duration::from_millis(20),
+            max: Duration::from_millis(20),
+        };
+        assert_eq!(summary.to_string(), "n=2 p50=1.5ms p99=20.0ms max=20.0ms");
+    }
+}
@@ -0,0 +1,441 @@
+// Local order state: each order placed by this client followed from request to
+// fill or cancellation, and its fills checked against the reported positions.
+
+use crate::orders::{text_field, NewOrder, OrderRef, Side, REASON_FIELDS};
+use crate::state::{amounts, POSITION_ID_FIELDS, POSITION_SIZE_FIELDS};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+// Finished orders kept for `orders <id>` after they stop being open
+const MAX_CLOSED: usize = 500;
+
+// Discrepancies kept for the `orders` command
+const MAX_DISCREPANCIES: usize = 50;
+
+// Positions this close to the expected size match, allowing for decimal rounding
+const TOLERANCE: f64 = 1e-9;
+
+// Fields of a fill carrying its quantity, price and id, in order of preference
+const FILL_QUANTITY_FIELDS: [&str; 4] = ["executed_quantity", "fill_quantity", "last_quantity", "quantity"];
+const FILL_PRICE_FIELDS: [&str; 4] = ["executed_price", "fill_price", "last_price", "price"];
+const FILL_ID_FIELDS: [&str; 2] = ["trade_id", "execution_id"];
+
+// Messages which change the state of an order
+const ORDER_MESSAGES: [&str; 9] = [
+    "order_accepted", "order_rejected", "order_filled", "execution", "order_deleted", "cancel_order_accepted",
+    "order_updated", "modify_order_accepted", "cancel_all_orders_accepted",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Sent, not acknowledged yet
+    Pending,
+    Acked,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Rejected,
+}
+
+impl OrderStatus {
+    pub fn is_open(self) -> bool {
+        matches!(self, OrderStatus::Pending | OrderStatus::Acked | OrderStatus::PartiallyFilled)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Fill {
+    pub trade_id: Option<String>,
+    pub quantity: f64,
+    pub price: f64,
+    pub at: DateTime<Utc>,
+}
+
+/// An order placed by this client as far as the server has told
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackedOrder {
+    pub client_order_id: String,
+    pub order_id: Option<String>,
+    pub symbol: String,
+    pub side: Side,
+    /// Ordered quantity, as last amended
+    pub quantity: f64,
+    pub price: Option<String>,
+    pub status: OrderStatus,
+    pub filled_quantity: f64,
+    pub remaining_quantity: f64,
+    /// Quantity-weighted price of the fills so far
+    pub average_fill_price: Option<f64>,
+    pub reject_reason: Option<String>,
+    pub fills: Vec<Fill>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TrackedOrder {
+    // Add a fill unless it was seen before, returning the signed position change
+    fn fill(&mut self, fill: Fill) -> Option<f64> {
+        if fill.trade_id.is_some() && self.fills.iter().any(|seen| seen.trade_id == fill.trade_id) {
+            return None;
+        }
+        let cost = self.average_fill_price.unwrap_or(0.0) * self.filled_quantity + fill.price * fill.quantity;
+        self.filled_quantity += fill.quantity;
+        self.average_fill_price = Some(cost / self.filled_quantity);
+        // A fill racing a cancel still counts, but only a complete one changes a finished order
+        if self.filled_quantity + TOLERANCE >= self.quantity {
+            self.status = OrderStatus::Filled;
+        } else if self.status.is_open() {
+            self.status = OrderStatus::PartiallyFilled;
+        }
+        self.updated_at = fill.at;
+        let change = fill.quantity * if self.side == Side::Buy { 1.0 } else { -1.0 };
+        self.fills.push(fill);
+        self.update_remaining();
+        Some(change)
+    }
+
+    fn update_remaining(&mut self) {
+        self.remaining_quantity = if self.status.is_open() { (self.quantity - self.filled_quantity).max(0.0) } else { 0.0 };
+    }
+}
+
+/// A reported position which does not match the fills seen since the previous one
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub symbol: String,
+    /// Previous reported size plus the fills since
+    pub expected: f64,
+    pub reported: f64,
+    pub difference: f64,
+    pub at: DateTime<Utc>,
+}
+
+// Position in one symbol as reported, and the fills seen since
+#[derive(Default)]
+struct PositionCheck {
+    reported: Option<f64>,
+    filled_since: f64,
+    // A mismatch is only flagged when the next summary still shows it, as fills and
+    // position updates need not arrive in order
+    mismatched: bool,
+}
+
+#[derive(Default)]
+struct Tracker {
+    orders: BTreeMap<String, TrackedOrder>,
+    closed: VecDeque<String>,
+    positions: BTreeMap<String, PositionCheck>,
+    discrepancies: VecDeque<Discrepancy>,
+}
+
+impl Tracker {
+    fn find(&mut self, body: &Value) -> Option<&mut TrackedOrder> {
+        let client_order_id = text_field(body, "client_order_id");
+        let order_id = text_field(body, "order_id");
+        self.orders.values_mut().find(|order| match (&client_order_id, &order_id) {
+            (Some(id), _) => &order.client_order_id == id,
+            (None, Some(id)) => order.order_id.as_ref() == Some(id),
+            (None, None) => false,
+        })
+    }
+
+    // Keep finished orders for a while, dropping the oldest
+    fn closed(&mut self, client_order_id: String) {
+        self.closed.push_back(client_order_id);
+        while self.closed.len() > MAX_CLOSED {
+            if let Some(id) = self.closed.pop_front() {
+                self.orders.remove(&id);
+            }
+        }
+    }
+
+    // Mark the open orders, or those in one symbol, as cancelled
+    fn cancel_open(&mut self, symbol: Option<&str>, now: DateTime<Utc>) {
+        let cancelled: Vec<String> = self.orders.values_mut()
+            .filter(|order| order.status.is_open() && symbol.map_or(true, |symbol| order.symbol == symbol))
+            .map(|order| {
+                order.status = OrderStatus::Cancelled;
+                order.updated_at = now;
+                order.update_remaining();
+                order.client_order_id.clone()
+            })
+            .collect();
+        cancelled.into_iter().for_each(|id| self.closed(id));
+    }
+
+    fn apply(&mut self, kind: &str, body: &Value, now: DateTime<Utc>) {
+        if kind == "cancel_all_orders_accepted" {
+            self.cancel_open(text_field(body, "symbol").as_deref(), now);
+            return;
+        }
+        let Some(order) = self.find(body) else {
+            return;
+        };
+        let was_open = order.status.is_open();
+        if order.order_id.is_none() {
+            order.order_id = text_field(body, "order_id");
+        }
+        let mut position_change = None;
+        match kind {
+            "order_accepted" => {
+                if order.status == OrderStatus::Pending {
+                    order.status = OrderStatus::Acked;
+                }
+            }
+            "order_rejected" => {
+                order.status = OrderStatus::Rejected;
+                order.reject_reason = REASON_FIELDS.iter().find_map(|field| text_field(body, field));
+            }
+            "order_filled" | "execution" => {
+                let number = |fields: &[&str]| fields.iter().find_map(|field| text_field(body, field)?.parse::<f64>().ok());
+                let (Some(quantity), Some(price)) = (number(&FILL_QUANTITY_FIELDS), number(&FILL_PRICE_FIELDS)) else {
+                    warn!(event = "fill_unreadable", client_order_id = %order.client_order_id, "Fill without quantity or price: {}", body);
+                    return;
+                };
+                let trade_id = FILL_ID_FIELDS.iter().find_map(|field| text_field(body, field));
+                position_change = order.fill(Fill { trade_id, quantity, price, at: now });
+            }
+            "order_deleted" | "cancel_order_accepted" => {
+                if order.status.is_open() {
+                    order.status = OrderStatus::Cancelled;
+                }
+            }
+            "order_updated" | "modify_order_accepted" => {
+                if let Some(quantity) = text_field(body, "quantity").and_then(|q| q.parse::<f64>().ok()) {
+                    order.quantity = quantity;
+                }
+                if let Some(price) = text_field(body, "price") {
+                    order.price = Some(price);
+                }
+            }
+            _ => return,
+        }
+        order.updated_at = now;
+        order.update_remaining();
+        info!(event = "order_status", client_order_id = %order.client_order_id, status = ?order.status,
+              filled = order.filled_quantity, remaining = order.remaining_quantity, "Order {} {:?}", order.client_order_id, order.status);
+        let (symbol, client_order_id, is_open) = (order.symbol.clone(), order.client_order_id.clone(), order.status.is_open());
+        if let Some(change) = position_change {
+            self.positions.entry(symbol).or_default().filled_since += change;
+        }
+        if was_open && !is_open {
+            self.closed(client_order_id);
+        }
+    }
+
+    // Compare each reported position with the previous one plus the fills since
+    fn reconcile(&mut self, positions: &[Value], now: DateTime<Utc>) {
+        let reported = amounts(positions, &POSITION_ID_FIELDS, &POSITION_SIZE_FIELDS);
+        let symbols: HashSet<String> = reported.keys().chain(self.positions.keys()).cloned().collect();
+        for symbol in symbols {
+            let size = reported.get(&symbol).copied().unwrap_or(0.0);
+            let check = self.positions.entry(symbol.clone()).or_default();
+            let Some(previous) = check.reported else {
+                // The first summary only sets the baseline
+                *check = PositionCheck { reported: Some(size), ..Default::default() };
+                continue;
+            };
+            let expected = previous + check.filled_since;
+            if (size - expected).abs() <= TOLERANCE * expected.abs().max(1.0) {
+                *check = PositionCheck { reported: Some(size), ..Default::default() };
+            } else if !check.mismatched {
+                check.mismatched = true;
+            } else {
+                *check = PositionCheck { reported: Some(size), ..Default::default() };
+                let discrepancy = Discrepancy { symbol, expected, reported: size, difference: size - expected, at: now };
+                warn!(event = "position_discrepancy", symbol = %discrepancy.symbol, expected, reported = size,
+                      "Position in {} is {} but the fills seen add up to {}", discrepancy.symbol, size, expected);
+                self.discrepancies.push_back(discrepancy);
+                if self.discrepancies.len() > MAX_DISCREPANCIES {
+                    self.discrepancies.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Follows the orders placed by this client, cheap to clone
+#[derive(Clone, Default)]
+pub struct OrderManager {
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl OrderManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start following a new order as it is sent
+    pub fn track(&self, order: &NewOrder, client_order_id: &str) {
+        let now = Utc::now();
+        let quantity = order.quantity.parse::<f64>().unwrap_or(0.0);
+        self.tracker.lock().unwrap().orders.insert(client_order_id.to_string(), TrackedOrder {
+            client_order_id: client_order_id.to_string(),
+            order_id: None,
+            symbol: order.symbol.clone(),
+            side: order.side,
+            quantity,
+            price: order.price.clone(),
+            status: OrderStatus::Pending,
+            filled_quantity: 0.0,
+            remaining_quantity: quantity,
+            average_fill_price: None,
+            reject_reason: None,
+            fills: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        });
+    }
+
+    /// Mark an order which could not be sent as rejected
+    pub fn not_sent(&self, client_order_id: &str, reason: &str) {
+        let mut tracker = self.tracker.lock().unwrap();
+        if let Some(order) = tracker.orders.get_mut(client_order_id) {
+            order.status = OrderStatus::Rejected;
+            order.reject_reason = Some(reason.to_string());
+            order.updated_at = Utc::now();
+            order.update_remaining();
+            tracker.closed(client_order_id.to_string());
+        }
+    }
+
+    /// Update orders from a message of the server and check positions against fills
+    pub fn handle(&self, message: &Map<String, Value>, now: DateTime<Utc>) {
+        let mut tracker = self.tracker.lock().unwrap();
+        for (kind, body) in message {
+            if kind == "position_summary" {
+                if let Some(positions) = body.get("positions").and_then(Value::as_array) {
+                    tracker.reconcile(positions, now);
+                }
+            } else if ORDER_MESSAGES.contains(&kind.as_str()) {
+                tracker.apply(kind, body, now);
+            }
+        }
+    }
+
+    pub fn order(&self, client_order_id: &str) -> Option<TrackedOrder> {
+        self.tracker.lock().unwrap().orders.get(client_order_id).cloned()
+    }
+
+    /// The order a cancel or amend names
+    pub fn find(&self, order: &OrderRef) -> Option<TrackedOrder> {
+        let tracker = self.tracker.lock().unwrap();
+        match order {
+            OrderRef::Client(id) => tracker.orders.get(id).cloned(),
+            OrderRef::Server(id) => tracker.orders.values().find(|order| order.order_id.as_ref() == Some(id)).cloned(),
+        }
+    }
+
+    pub fn open_orders(&self) -> Vec<TrackedOrder> {
+        self.tracker.lock().unwrap().orders.values().filter(|order| order.status.is_open()).cloned().collect()
+    }
+
+    pub fn discrepancies(&self) -> Vec<Discrepancy> {
+        self.tracker.lock().unwrap().discrepancies.iter().cloned().collect()
+    }
+
+    /// Open orders and recent discrepancies, for the `orders` command
+    pub fn status(&self) -> Value {
+        json!({ "open": self.open_orders(), "discrepancies": self.discrepancies() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::TimeInForce;
+
+    fn place(manager: &OrderManager, client_order_id: &str, side: Side, quantity: &str) {
+        manager.track(&NewOrder {
+            symbol: "BTC-USD".to_string(),
+            side,
+            quantity: quantity.to_string(),
+            price: Some("100".to_string()),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            client_order_id: Some(client_order_id.to_string()),
+        }, client_order_id);
+    }
+
+    fn receive(manager: &OrderManager, message: Value) {
+        manager.handle(message.as_object().unwrap(), Utc::now());
+    }
+
+    fn position(size: &str) -> Value {
+        json!({ "position_summary": { "positions": [{ "symbol": "BTC-USD", "size": size }] } })
+    }
+
+    #[test]
+    fn test_order_follows_acks_and_fills() {
+        let manager = OrderManager::new();
+        place(&manager, "pt-1", Side::Buy, "3");
+        assert_eq!(manager.order("pt-1").unwrap().status, OrderStatus::Pending);
+
+        receive(&manager, json!({ "order_accepted": { "client_order_id": "pt-1", "order_id": "77" } }));
+        assert_eq!(manager.order("pt-1").unwrap().status, OrderStatus::Acked);
+
+        // Later messages may name the order by the server's id only, and repeat a fill
+        let fill = json!({ "order_filled": { "order_id": "77", "executed_quantity": "1", "executed_price": "100", "trade_id": "t1" } });
+        receive(&manager, fill.clone());
+        receive(&manager, fill);
+        let order = manager.order("pt-1").unwrap();
+        assert_eq!((order.status, order.filled_quantity, order.remaining_quantity), (OrderStatus::PartiallyFilled, 1.0, 2.0));
+
+        receive(&manager, json!({ "execution": { "order_id": 77, "fill_quantity": 2, "fill_price": "103", "trade_id": "t2" } }));
+        let order = manager.order("pt-1").unwrap();
+        assert_eq!((order.status, order.remaining_quantity, order.fills.len()), (OrderStatus::Filled, 0.0, 2));
+        assert_eq!(order.average_fill_price, Some(102.0));
+        assert!(manager.open_orders().is_empty());
+    }
+
+    #[test]
+    fn test_cancels_and_rejects_close_orders() {
+        let manager = OrderManager::new();
+        for id in ["pt-1", "pt-2", "pt-3", "pt-4"] {
+            place(&manager, id, Side::Sell, "1");
+        }
+        receive(&manager, json!({ "order_rejected": { "client_order_id": "pt-1", "reason": "price out of range" } }));
+        receive(&manager, json!({ "order_deleted": { "client_order_id": "pt-2" } }));
+        receive(&manager, json!({ "modify_order_accepted": { "client_order_id": "pt-3", "quantity": "4" } }));
+        manager.not_sent("pt-4", "connection closed");
+
+        let rejected = manager.order("pt-1").unwrap();
+        assert_eq!((rejected.status, rejected.reject_reason.as_deref()), (OrderStatus::Rejected, Some("price out of range")));
+        assert_eq!(manager.order("pt-2").unwrap().status, OrderStatus::Cancelled);
+        assert_eq!(manager.order("pt-3").unwrap().remaining_quantity, 4.0);
+        assert_eq!(manager.order("pt-4").unwrap().status, OrderStatus::Rejected);
+
+        receive(&manager, json!({ "cancel_all_orders_accepted": { "symbol": "ETH-USD" } }));
+        assert_eq!(manager.open_orders().len(), 1);
+        receive(&manager, json!({ "cancel_all_orders_accepted": {} }));
+        assert_eq!(manager.order("pt-3").unwrap().status, OrderStatus::Cancelled);
+        assert_eq!(manager.status()["open"], json!([]));
+    }
+
+    #[test]
+    fn test_positions_are_reconciled_with_fills() {
+        let manager = OrderManager::new();
+        receive(&manager, position("2"));
+        place(&manager, "pt-1", Side::Sell, "0.5");
+        receive(&manager, json!({ "order_filled": { "client_order_id": "pt-1", "quantity": "0.5", "price": "100" } }));
+        receive(&manager, position("1.5"));
+        assert!(manager.discrepancies().is_empty());
+
+        // One summary out of step with the fills is allowed, the next one is flagged
+        receive(&manager, position("1.2"));
+        assert!(manager.discrepancies().is_empty());
+        receive(&manager, position("1.2"));
+        let discrepancies = manager.discrepancies();
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!((discrepancies[0].expected, discrepancies[0].reported), (1.5, 1.2));
+
+        // Flagged sizes become the new baseline
+        receive(&manager, position("1.2"));
+        assert_eq!(manager.discrepancies().len(), 1);
+    }
+}
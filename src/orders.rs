@@ -0,0 +1,1060 @@
+// Order entry: typed requests to place, amend and cancel orders, written to the
+// server by the run loop and matched with the acknowledgements read back.
+
+use crate::config::Settings;
+use crate::error::AppError;
+use crate::order_manager::OrderManager;
+use crate::risk::{RiskError, RiskGate};
+use crate::websocket::WebSocketClient;
+
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tracing::{info, warn};
+use tungstenite::Message;
+
+// Responses answering each kind of request, accepted or rejected
+const RESPONSES: [(&str, RequestKind, bool); 9] = [
+    ("order_accepted", RequestKind::New, true),
+    ("order_rejected", RequestKind::New, false),
+    ("cancel_order_accepted", RequestKind::Cancel, true),
+    ("order_deleted", RequestKind::Cancel, true),
+    ("cancel_order_rejected", RequestKind::Cancel, false),
+    ("cancel_all_orders_accepted", RequestKind::CancelAll, true),
+    ("cancel_all_orders_rejected", RequestKind::CancelAll, false),
+    ("modify_order_accepted", RequestKind::Amend, true),
+    ("modify_order_rejected", RequestKind::Amend, false),
+];
+
+// Fields of a rejection carrying its reason, in order of preference
+pub const REASON_FIELDS: [&str; 3] = ["reason", "rejection_reason", "error"];
+
+// Query parameter asking the server at login to cancel the session's orders when its connection drops
+const CANCEL_ON_DISCONNECT_PARAM: &str = "cancel_on_disconnect=true";
+
+/// What is done about resting orders when the connection drops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CancelOnDisconnect {
+    Off,
+    /// Ask the server at login to cancel them when the connection drops, and cancel all once connected again
+    Login,
+    /// Cancel all orders once connected again
+    Reconnect,
+}
+
+impl FromStr for CancelOnDisconnect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(CancelOnDisconnect::Off),
+            "login" => Ok(CancelOnDisconnect::Login),
+            "reconnect" => Ok(CancelOnDisconnect::Reconnect),
+            _ => Err(format!("Invalid PT_CANCEL_ON_DISCONNECT '{}', expected off, login or reconnect", s)),
+        }
+    }
+}
+
+/// How order requests are sent and how long acknowledgements are awaited
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderConfig {
+    /// How long the blocking API waits for an acknowledgement
+    pub ack_timeout: Duration,
+    /// How long after its timestamp the server may still act on a request
+    pub recv_window: Duration,
+    /// Start of every generated client order id
+    pub client_order_id_prefix: String,
+    pub cancel_on_disconnect: CancelOnDisconnect,
+}
+
+impl OrderConfig {
+    /// Read the order entry settings, `None` unless `PT_ORDER_ENTRY` is `true`
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        match settings.value("PT_ORDER_ENTRY").map(|v| v.trim().to_lowercase()).as_deref() {
+            None | Some("false") => return Ok(None),
+            Some("true") => {}
+            Some(other) => return Err(format!("Invalid PT_ORDER_ENTRY '{}', expected true or false", other)),
+        }
+        let config = OrderConfig {
+            ack_timeout: Duration::from_millis(settings.number("PT_ORDER_ACK_TIMEOUT_MS", 10_000)?),
+            recv_window: Duration::from_millis(settings.number("PT_ORDER_RECV_WINDOW_MS", 5_000)?),
+            client_order_id_prefix: settings.value("PT_CLIENT_ORDER_ID_PREFIX").unwrap_or_else(|| "pt".to_string()),
+            cancel_on_disconnect: settings.value("PT_CANCEL_ON_DISCONNECT").map_or(Ok(CancelOnDisconnect::Off), |v| v.trim().parse())?,
+        };
+        if config.ack_timeout.is_zero() || config.recv_window.is_zero() {
+            return Err("PT_ORDER_ACK_TIMEOUT_MS and PT_ORDER_RECV_WINDOW_MS must be positive".to_string());
+        }
+        if !config.client_order_id_prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid PT_CLIENT_ORDER_ID_PREFIX '{}', expected letters, digits, '-' or '_'",
+                config.client_order_id_prefix));
+        }
+        Ok(Some(config))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+impl Side {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Side::Buy => "buy",
+            Side::Sell => "sell",
+        }
+    }
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "buy" => Ok(Side::Buy),
+            "sell" => Ok(Side::Sell),
+            _ => Err(format!("Invalid side '{}', expected buy or sell", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeInForce {
+    GoodTillCancelled,
+    ImmediateOrCancel,
+    FillOrKill,
+}
+
+impl TimeInForce {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimeInForce::GoodTillCancelled => "GTC",
+            TimeInForce::ImmediateOrCancel => "IOC",
+            TimeInForce::FillOrKill => "FOK",
+        }
+    }
+}
+
+impl FromStr for TimeInForce {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "GTC" => Ok(TimeInForce::GoodTillCancelled),
+            "IOC" => Ok(TimeInForce::ImmediateOrCancel),
+            "FOK" => Ok(TimeInForce::FillOrKill),
+            _ => Err(format!("Invalid time in force '{}', expected gtc, ioc or fok", s)),
+        }
+    }
+}
+
+/// A new order; without a price it is a market order
+#[derive(Clone, Debug, PartialEq)]
+pub struct NewOrder {
+    pub symbol: String,
+    pub side: Side,
+    /// Decimal quantity, sent as given
+    pub quantity: String,
+    /// Decimal limit price, sent as given
+    pub price: Option<String>,
+    pub time_in_force: TimeInForce,
+    /// Generated when left out
+    pub client_order_id: Option<String>,
+}
+
+/// The order a cancel or amend applies to
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderRef {
+    /// Client order id given when the order was placed
+    Client(String),
+    /// Order id assigned by the server
+    Server(String),
+}
+
+impl OrderRef {
+    fn field(&self) -> (&'static str, &str) {
+        match self {
+            OrderRef::Client(id) => ("client_order_id", id),
+            OrderRef::Server(id) => ("order_id", id),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderRequest {
+    New(NewOrder),
+    Cancel { symbol: String, order: OrderRef },
+    /// Cancel every open order, or those in one symbol
+    CancelAll { symbol: Option<String> },
+    /// Change the price and/or quantity of an open order
+    Amend { symbol: String, order: OrderRef, price: Option<String>, quantity: Option<String> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RequestKind {
+    New,
+    Cancel,
+    CancelAll,
+    Amend,
+}
+
+impl RequestKind {
+    /// Message type of the request
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestKind::New => "new_order",
+            RequestKind::Cancel => "cancel_order",
+            RequestKind::CancelAll => "cancel_all_orders",
+            RequestKind::Amend => "modify_order",
+        }
+    }
+}
+
+impl OrderRequest {
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            OrderRequest::New(_) => RequestKind::New,
+            OrderRequest::Cancel { .. } => RequestKind::Cancel,
+            OrderRequest::CancelAll { .. } => RequestKind::CancelAll,
+            OrderRequest::Amend { .. } => RequestKind::Amend,
+        }
+    }
+
+    /// Check what the server would otherwise reject
+    pub fn validate(&self) -> Result<(), String> {
+        let decimal = |name: &str, value: &str| match value.parse::<f64>() {
+            Ok(number) if number.is_finite() && number > 0.0 => Ok(()),
+            _ => Err(format!("Invalid {} '{}', expected a positive decimal", name, value)),
+        };
+        match self {
+            OrderRequest::New(order) => {
+                decimal("quantity", &order.quantity)?;
+                order.price.as_deref().map_or(Ok(()), |price| decimal("price", price))?;
+                if order.price.is_none() && order.time_in_force == TimeInForce::GoodTillCancelled {
+                    return Err("Market orders cannot rest, use ioc or fok".to_string());
+                }
+                Ok(())
+            }
+            OrderRequest::Amend { price: None, quantity: None, .. } => Err("Amend needs a new price or quantity".to_string()),
+            OrderRequest::Amend { price, quantity, .. } => {
+                price.as_deref().map_or(Ok(()), |price| decimal("price", price))?;
+                quantity.as_deref().map_or(Ok(()), |quantity| decimal("quantity", quantity))
+            }
+            OrderRequest::Cancel { .. } | OrderRequest::CancelAll { .. } => Ok(()),
+        }
+    }
+
+    // The message sent for the request, stamped with the time it was made
+    fn to_message(&self, client_order_id: Option<&str>, recv_window: Duration) -> Value {
+        let mut body = Map::new();
+        let mut set = |name: &str, value: &str| {
+            body.insert(name.to_string(), json!(value));
+        };
+        match self {
+            OrderRequest::New(order) => {
+                set("symbol", &order.symbol);
+                set("side", order.side.as_str());
+                set("type", if order.price.is_some() { "LIMIT" } else { "MARKET" });
+                set("quantity", &order.quantity);
+                if let Some(price) = &order.price {
+                    set("price", price);
+                }
+                set("time_in_force", order.time_in_force.as_str());
+                if let Some(id) = client_order_id {
+                    set("client_order_id", id);
+                }
+            }
+            OrderRequest::Cancel { symbol, order } => {
+                set("symbol", symbol);
+                let (field, id) = order.field();
+                set(field, id);
+            }
+            OrderRequest::CancelAll { symbol } => {
+                if let Some(symbol) = symbol {
+                    set("symbol", symbol);
+                }
+            }
+            OrderRequest::Amend { symbol, order, price, quantity } => {
+                set("symbol", symbol);
+                let (field, id) = order.field();
+                set(field, id);
+                if let Some(price) = price {
+                    set("price", price);
+                }
+                if let Some(quantity) = quantity {
+                    set("quantity", quantity);
+                }
+            }
+        }
+        set("recv_window", &recv_window.as_millis().to_string());
+        json!({ self.kind().as_str(): body })
+    }
+
+    // Field and value an acknowledgement of the request carries
+    fn correlation(&self, client_order_id: Option<&str>) -> Option<(&'static str, String)> {
+        match self {
+            OrderRequest::New(_) => client_order_id.map(|id| ("client_order_id", id.to_string())),
+            OrderRequest::Cancel { order, .. } | OrderRequest::Amend { order, .. } => {
+                let (field, id) = order.field();
+                Some((field, id.to_string()))
+            }
+            OrderRequest::CancelAll { .. } => None,
+        }
+    }
+}
+
+/// An accepted request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ack {
+    /// Type of the acknowledgement, e.g. `order_accepted`
+    pub kind: String,
+    pub client_order_id: Option<String>,
+    pub order_id: Option<String>,
+    /// Body of the acknowledgement as sent
+    pub message: Value,
+}
+
+impl Ack {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": "accepted",
+            "type": self.kind,
+            "client_order_id": self.client_order_id,
+            "order_id": self.order_id,
+            "message": self.message,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderError {
+    /// The request was not valid and was not sent
+    Invalid(String),
+    /// The risk gate refused the request, which was not sent
+    Risk(RiskError),
+    /// The server refused the request
+    Rejected { reason: String, message: Value },
+    /// No acknowledgement arrived in time; the request may still be acted on
+    Timeout,
+    /// The connection was lost before an acknowledgement arrived; the request may have been acted on
+    ConnectionLost(String),
+    /// Writing the request failed
+    NotSent(String),
+    /// The client is shutting down
+    Stopped,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::Invalid(msg) => write!(f, "Invalid order request: {}", msg),
+            OrderError::Risk(e) => write!(f, "Refused by the risk checks: {}", e),
+            OrderError::Rejected { reason, .. } => write!(f, "Rejected by the server: {}", reason),
+            OrderError::Timeout => write!(f, "No acknowledgement in time, the outcome is unknown"),
+            OrderError::ConnectionLost(reason) => write!(f, "Connection lost before an acknowledgement ({}), the outcome is unknown", reason),
+            OrderError::NotSent(msg) => write!(f, "Request not sent: {}", msg),
+            OrderError::Stopped => write!(f, "The client is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for OrderError {}
+
+pub type Outcome = Result<Ack, OrderError>;
+
+#[derive(Default)]
+struct Slot {
+    outcome: Option<Outcome>,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Default)]
+struct Completion {
+    slot: Arc<(Mutex<Slot>, Condvar)>,
+}
+
+impl Completion {
+    /// Set the outcome unless there is one already, returns whether it was set
+    fn complete(&self, outcome: Outcome) -> bool {
+        let (slot, ready) = &*self.slot;
+        let mut slot = slot.lock().unwrap();
+        if slot.outcome.is_some() {
+            return false;
+        }
+        slot.outcome = Some(outcome);
+        ready.notify_all();
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn outcome(&self) -> Option<Outcome> {
+        self.slot.0.lock().unwrap().outcome.clone()
+    }
+
+    fn is(&self, other: &Completion) -> bool {
+        Arc::ptr_eq(&self.slot, &other.slot)
+    }
+}
+
+/// A submitted request waiting for its acknowledgement. Wait for it with `wait`,
+/// check on it with `try_outcome` or `.await` it from any executor.
+pub struct PendingOrder {
+    client_order_id: Option<String>,
+    completion: Completion,
+}
+
+impl PendingOrder {
+    /// Client order id of a new order, generated unless one was given
+    pub fn client_order_id(&self) -> Option<&str> {
+        self.client_order_id.as_deref()
+    }
+
+    /// Block until the outcome is known, at most `timeout`
+    pub fn wait(self, timeout: Duration) -> Outcome {
+        let (slot, ready) = &*self.completion.slot;
+        let slot = slot.lock().unwrap();
+        let (slot, _) = ready.wait_timeout_while(slot, timeout, |slot| slot.outcome.is_none()).unwrap();
+        slot.outcome.clone().unwrap_or(Err(OrderError::Timeout))
+    }
+}
+
+impl Future for PendingOrder {
+    type Output = Outcome;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Outcome> {
+        let mut slot = self.completion.slot.0.lock().unwrap();
+        match slot.outcome.clone() {
+            Some(outcome) => Poll::Ready(outcome),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// A request on its way: queued until the run loop writes it, then awaiting its acknowledgement
+struct InFlight {
+    kind: RequestKind,
+    message: Value,
+    correlation: Option<(&'static str, String)>,
+    completion: Completion,
+}
+
+impl InFlight {
+    // Timestamp the request as it is written, so that the receive window counts from then
+    fn stamp(&mut self) {
+        if let Some(Value::Object(body)) = self.message.get_mut(self.kind.as_str()) {
+            body.insert("timestamp".to_string(), json!(Utc::now().timestamp_millis().to_string()));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    outbox: VecDeque<InFlight>,
+    awaiting: VecDeque<InFlight>,
+}
+
+/// Places, amends and cancels orders through the run loop's connection, cheap to clone
+#[derive(Clone)]
+pub struct Orders {
+    config: Arc<OrderConfig>,
+    /// Distinguishes client order ids of this process from those of earlier runs
+    session: String,
+    next_id: Arc<AtomicU64>,
+    queues: Arc<Mutex<Queues>>,
+    manager: OrderManager,
+    risk: Option<RiskGate>,
+    /// Refuses every new order and amendment while engaged
+    kill_switch: Arc<AtomicBool>,
+    /// Whether a connection was open before, so that the next one is a reconnect
+    connected_before: Arc<AtomicBool>,
+    // Held from the risk checks until the request is queued, so that concurrent requests are checked in turn
+    submitting: Arc<Mutex<()>>,
+}
+
+impl Orders {
+    pub fn new(config: OrderConfig) -> Self {
+        let session = radix36(Utc::now().timestamp_millis() as u64);
+        Orders {
+            config: Arc::new(config),
+            session,
+            next_id: Arc::new(AtomicU64::new(1)),
+            queues: Arc::default(),
+            manager: OrderManager::new(),
+            risk: None,
+            kill_switch: Arc::default(),
+            connected_before: Arc::default(),
+            submitting: Arc::default(),
+        }
+    }
+
+    /// Check every request with `risk` before it is queued
+    pub fn with_risk(mut self, risk: RiskGate) -> Self {
+        if risk.limits().kill_switch {
+            self.kill_switch.store(true, Ordering::SeqCst);
+        }
+        self.risk = Some(risk);
+        self
+    }
+
+    pub fn kill_switch_engaged(&self) -> bool {
+        self.kill_switch.load(Ordering::SeqCst)
+    }
+
+    /// Refuse new orders and amendments from now on, drop those not yet written,
+    /// then cancel all orders and wait for the acknowledgement
+    pub fn engage_kill_switch(&self) -> Outcome {
+        let pending = {
+            let _submitting = self.submitting.lock().unwrap();
+            self.kill_switch.store(true, Ordering::SeqCst);
+            warn!(event = "kill_switch_engaged", "Kill switch engaged, cancelling all orders");
+            let mut queues = self.queues.lock().unwrap();
+            let (dropped, kept) = queues.outbox.drain(..).partition(|request| matches!(request.kind, RequestKind::New | RequestKind::Amend));
+            queues.outbox = kept;
+            drop(queues);
+            for request in dropped {
+                if let (RequestKind::New, Some((_, client_order_id))) = (request.kind, &request.correlation) {
+                    self.manager.not_sent(client_order_id, &RiskError::KillSwitch.to_string());
+                }
+                request.completion.complete(Err(OrderError::Risk(RiskError::KillSwitch)));
+            }
+            self.queue(OrderRequest::CancelAll { symbol: None })?
+        };
+        self.wait(pending)
+    }
+
+    pub fn release_kill_switch(&self) {
+        self.kill_switch.store(false, Ordering::SeqCst);
+        info!(event = "kill_switch_released", "Kill switch released");
+    }
+
+    /// `server_url` with cancel-on-disconnect requested, if configured to ask at login
+    pub fn login_url(&self, server_url: &str) -> String {
+        if self.config.cancel_on_disconnect != CancelOnDisconnect::Login || server_url.contains(CANCEL_ON_DISCONNECT_PARAM) {
+            return server_url.to_string();
+        }
+        let separator = match server_url.split_once('?') {
+            None => "?",
+            Some((_, "")) => "",
+            Some(_) => "&",
+        };
+        format!("{}{}{}", server_url, separator, CANCEL_ON_DISCONNECT_PARAM)
+    }
+
+    /// A connection opened; after a reconnect, cancel all orders if configured to.
+    /// Done at login too, as nothing confirms that the server honoured the request.
+    pub fn connected(&self) {
+        let reconnected = self.connected_before.swap(true, Ordering::SeqCst);
+        if reconnected && self.config.cancel_on_disconnect != CancelOnDisconnect::Off {
+            info!(event = "cancel_on_reconnect", "Cancelling all orders left while disconnected");
+            // Answered through the usual logging, nobody waits for it
+            let _ = self.submit(OrderRequest::CancelAll { symbol: None });
+        }
+    }
+
+    pub fn risk(&self) -> Option<&RiskGate> {
+        self.risk.as_ref()
+    }
+
+    /// State of the orders placed through this handle
+    pub fn manager(&self) -> &OrderManager {
+        &self.manager
+    }
+
+    /// A client order id not used before, e.g. `pt-lw3k2a1b-7`
+    pub fn next_client_order_id(&self) -> String {
+        format!("{}-{}-{}", self.config.client_order_id_prefix, self.session, self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Queue `request` for the run loop and return at once
+    pub fn submit(&self, request: OrderRequest) -> Result<PendingOrder, OrderError> {
+        request.validate().map_err(OrderError::Invalid)?;
+        let _submitting = self.submitting.lock().unwrap();
+        self.queue(request)
+    }
+
+    // Check and queue `request`, with `submitting` held so that the kill switch can't be engaged in between
+    fn queue(&self, mut request: OrderRequest) -> Result<PendingOrder, OrderError> {
+        let cancel = matches!(request, OrderRequest::Cancel { .. } | OrderRequest::CancelAll { .. });
+        if self.kill_switch_engaged() && !cancel {
+            warn!(event = "order_request_refused", kind = request.kind().as_str(), "Kill switch refused {}", request.kind().as_str());
+            return Err(OrderError::Risk(RiskError::KillSwitch));
+        }
+        if let Some(risk) = &self.risk {
+            risk.check(&request, &self.manager).map_err(|e| {
+                warn!(event = "order_request_refused", kind = request.kind().as_str(), reason = %e, "Risk checks refused {}: {}", request.kind().as_str(), e);
+                OrderError::Risk(e)
+            })?;
+        }
+        if let OrderRequest::New(order) = &mut request {
+            order.client_order_id.get_or_insert_with(|| self.next_client_order_id());
+        }
+        let client_order_id = match &request {
+            OrderRequest::New(order) => {
+                let client_order_id = order.client_order_id.clone().unwrap_or_default();
+                self.manager.track(order, &client_order_id);
+                Some(client_order_id)
+            }
+            _ => None,
+        };
+        let completion = Completion::default();
+        self.queues.lock().unwrap().outbox.push_back(InFlight {
+            kind: request.kind(),
+            message: request.to_message(client_order_id.as_deref(), self.config.recv_window),
+            correlation: request.correlation(client_order_id.as_deref()),
+            completion: completion.clone(),
+        });
+        Ok(PendingOrder { client_order_id, completion })
+    }
+
+    /// Submit `request` and wait for its acknowledgement, at most the configured timeout
+    pub fn execute(&self, request: OrderRequest) -> Outcome {
+        let pending = self.submit(request)?;
+        self.wait(pending)
+    }
+
+    fn wait(&self, pending: PendingOrder) -> Outcome {
+        let client_order_id = pending.client_order_id().map(str::to_string);
+        let completion = pending.completion.clone();
+        let outcome = pending.wait(self.config.ack_timeout);
+        if outcome != Err(OrderError::Timeout) {
+            return outcome;
+        }
+        // An acknowledgement may still have come in since
+        if !completion.complete(Err(OrderError::Timeout)) {
+            return completion.outcome().unwrap_or(outcome);
+        }
+        warn!(event = "order_request_timeout", client_order_id = ?client_order_id,
+              "No acknowledgement within {:?}", self.config.ack_timeout);
+        self.abandon(&completion);
+        outcome
+    }
+
+    // Forget a request nobody waits for any more, so that it isn't written later nor takes another request's acknowledgement
+    fn abandon(&self, completion: &Completion) {
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(position) = queues.outbox.iter().position(|request| request.completion.is(completion)) {
+            let request = queues.outbox.remove(position).unwrap();
+            drop(queues);
+            if let (RequestKind::New, Some((_, client_order_id))) = (request.kind, &request.correlation) {
+                self.manager.not_sent(client_order_id, "no acknowledgement in time");
+            }
+            return;
+        }
+        queues.awaiting.retain(|request| !request.completion.is(completion));
+    }
+
+    /// Whether requests are queued or waiting for their acknowledgement
+    pub fn busy(&self) -> bool {
+        let queues = self.queues.lock().unwrap();
+        !queues.outbox.is_empty() || !queues.awaiting.is_empty()
+    }
+
+    /// Write the queued requests, called by the run loop which owns the connection
+    pub fn send_queued(&self, client: &mut WebSocketClient) -> Result<(), AppError> {
+        loop {
+            let Some(mut request) = self.queues.lock().unwrap().outbox.pop_front() else {
+                return Ok(());
+            };
+            request.stamp();
+            match client.send_message(Message::text(request.message.to_string())) {
+                Ok(()) => {
+                    info!(event = "order_request_sent", kind = request.kind.as_str(), request = %request.message,
+                          "Sent {}", request.kind.as_str());
+                    // Unless it timed out while being written
+                    let mut queues = self.queues.lock().unwrap();
+                    if request.completion.outcome().is_none() {
+                        queues.awaiting.push_back(request);
+                    }
+                }
+                Err(e) => {
+                    if let (RequestKind::New, Some((_, client_order_id))) = (request.kind, &request.correlation) {
+                        self.manager.not_sent(client_order_id, &e.to_string());
+                    }
+                    request.completion.complete(Err(OrderError::NotSent(e.to_string())));
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Match an acknowledgement or rejection to the request it answers.
+    /// Returns whether the message answered a request.
+    pub fn handle_message(&self, text: &str) -> bool {
+        let Ok(Value::Object(message)) = serde_json::from_str::<Value>(text) else {
+            return false;
+        };
+        self.manager.handle(&message, Utc::now());
+        let mut answered = false;
+        for (kind, body) in &message {
+            let Some((_, request_kind, accepted)) = RESPONSES.iter().find(|(response, ..)| response == kind) else {
+                continue;
+            };
+            let mut queues = self.queues.lock().unwrap();
+            // Requests are answered by the id they carry, cancel-all by the oldest waiting one
+            let position = queues.awaiting.iter().position(|request| request.kind == *request_kind && match &request.correlation {
+                Some((field, id)) => text_field(body, field).as_deref() == Some(id.as_str()),
+                None => true,
+            });
+            let Some(request) = position.and_then(|position| queues.awaiting.remove(position)) else {
+                continue;
+            };
+            drop(queues);
+            answered = true;
+            let outcome = if *accepted {
+                info!(event = "order_request_accepted", kind = kind.as_str(), "{} accepted: {}", request.kind.as_str(), body);
+                Ok(Ack {
+                    kind: kind.clone(),
+                    client_order_id: text_field(body, "client_order_id"),
+                    order_id: text_field(body, "order_id"),
+                    message: body.clone(),
+                })
+            } else {
+                let reason = REASON_FIELDS.iter().find_map(|field| text_field(body, field)).unwrap_or_else(|| "no reason given".to_string());
+                warn!(event = "order_request_rejected", kind = kind.as_str(), reason = %reason, "{} rejected: {}", request.kind.as_str(), reason);
+                Err(OrderError::Rejected { reason, message: body.clone() })
+            };
+            request.completion.complete(outcome);
+        }
+        answered
+    }
+
+    /// Fail the requests written on a connection which is gone; their outcome is unknown
+    pub fn connection_lost(&self, reason: &str) {
+        let awaiting: Vec<InFlight> = self.queues.lock().unwrap().awaiting.drain(..).collect();
+        if !awaiting.is_empty() {
+            warn!(event = "order_requests_unanswered", count = awaiting.len(), "{} order requests unanswered when the connection was lost", awaiting.len());
+        }
+        for request in awaiting {
+            request.completion.complete(Err(OrderError::ConnectionLost(reason.to_string())));
+        }
+    }
+
+    /// Fail everything still queued or waiting, on shutdown
+    pub fn stop(&self) {
+        let queues = &mut *self.queues.lock().unwrap();
+        for request in queues.outbox.drain(..).chain(queues.awaiting.drain(..)) {
+            request.completion.complete(Err(OrderError::Stopped));
+        }
+    }
+}
+
+/// Text or number field of a JSON object as a string
+pub fn text_field(value: &Value, name: &str) -> Option<String> {
+    match value.get(name)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+fn radix36(mut n: u64) -> String {
+    let mut digits = Vec::new();
+    loop {
+        digits.push(std::char::from_digit((n % 36) as u32, 36).unwrap());
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_state::ConnectionLifecycle;
+    use crate::order_manager::OrderStatus;
+    use crate::test_support::{spawn_ws_server, test_config};
+    use std::sync::mpsc::channel;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+
+    fn orders() -> Orders {
+        orders_waiting(Duration::from_millis(200))
+    }
+
+    fn orders_waiting(ack_timeout: Duration) -> Orders {
+        orders_with(ack_timeout, CancelOnDisconnect::Off)
+    }
+
+    fn orders_with(ack_timeout: Duration, cancel_on_disconnect: CancelOnDisconnect) -> Orders {
+        Orders::new(OrderConfig {
+            ack_timeout,
+            recv_window: Duration::from_secs(5),
+            client_order_id_prefix: "test".to_string(),
+            cancel_on_disconnect,
+        })
+    }
+
+    fn limit_buy(price: &str) -> OrderRequest {
+        OrderRequest::New(NewOrder {
+            symbol: "BTC-USD".to_string(),
+            side: Side::Buy,
+            quantity: "0.5".to_string(),
+            price: Some(price.to_string()),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            client_order_id: None,
+        })
+    }
+
+    // Take the queued requests as the run loop would, without a connection
+    fn send(orders: &Orders) -> Vec<Value> {
+        let mut queues = orders.queues.lock().unwrap();
+        let mut sent: Vec<InFlight> = queues.outbox.drain(..).collect();
+        sent.iter_mut().for_each(InFlight::stamp);
+        let messages = sent.iter().map(|request| request.message.clone()).collect();
+        queues.awaiting.extend(sent);
+        messages
+    }
+
+    // Run a future on the current thread, parking it until woken
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut context = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            match future.as_mut().poll(&mut context) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_requests_are_typed_messages_with_generated_ids() {
+        let orders = orders();
+        let first = orders.submit(limit_buy("100")).unwrap();
+        let second = orders.submit(limit_buy("101")).unwrap();
+        assert!(first.client_order_id().unwrap().starts_with("test-"));
+        assert_ne!(first.client_order_id(), second.client_order_id());
+        orders.submit(OrderRequest::Amend {
+            symbol: "BTC-USD".to_string(),
+            order: OrderRef::Server("42".to_string()),
+            price: Some("99".to_string()),
+            quantity: None,
+        }).unwrap();
+        orders.submit(OrderRequest::CancelAll { symbol: None }).unwrap();
+
+        let sent = send(&orders);
+        let new_order = &sent[0]["new_order"];
+        assert_eq!(new_order["client_order_id"], first.client_order_id().unwrap());
+        assert_eq!(new_order["type"], "LIMIT");
+        assert_eq!(new_order["time_in_force"], "GTC");
+        assert_eq!(new_order["price"], "100");
+        assert_eq!(new_order["recv_window"], "5000");
+        assert!(new_order["timestamp"].as_str().unwrap().parse::<i64>().is_ok());
+        assert_eq!(sent[2]["modify_order"]["order_id"], "42");
+        assert_eq!(sent[2]["modify_order"].get("quantity"), None);
+        assert_eq!(sent[3]["cancel_all_orders"].get("symbol"), None);
+
+        let resting_market = OrderRequest::New(NewOrder {
+            symbol: "BTC-USD".to_string(),
+            side: Side::Sell,
+            quantity: "1".to_string(),
+            price: None,
+            time_in_force: TimeInForce::GoodTillCancelled,
+            client_order_id: None,
+        });
+        assert!(matches!(orders.submit(resting_market), Err(OrderError::Invalid(_))));
+        assert!(matches!(orders.submit(limit_buy("-1")), Err(OrderError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_responses_complete_the_request_they_answer() {
+        let orders = orders();
+        let first = orders.submit(limit_buy("100")).unwrap();
+        let second = orders.submit(limit_buy("101")).unwrap();
+        let cancel_all = orders.submit(OrderRequest::CancelAll { symbol: Some("BTC-USD".to_string()) }).unwrap();
+        send(&orders);
+
+        // Answered out of order, each by its client order id
+        let rejected = json!({ "order_rejected": { "client_order_id": second.client_order_id(), "reason": "insufficient margin" } });
+        assert!(orders.handle_message(&rejected.to_string()));
+        let accepted = json!({ "order_accepted": { "client_order_id": first.client_order_id(), "order_id": 7 } });
+        assert!(orders.handle_message(&accepted.to_string()));
+        assert!(orders.handle_message(r#"{"cancel_all_orders_accepted":{"symbol":"BTC-USD"}}"#));
+        assert!(!orders.handle_message(r#"{"order_accepted":{"client_order_id":"unknown"}}"#));
+        assert!(!orders.handle_message(r#"{"mbp_snapshot":{}}"#));
+
+        let ack = first.wait(Duration::ZERO).unwrap();
+        assert_eq!(ack.order_id.as_deref(), Some("7"));
+        assert_eq!(ack.kind, "order_accepted");
+        match second.wait(Duration::ZERO) {
+            Err(OrderError::Rejected { reason, .. }) => assert_eq!(reason, "insufficient margin"),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(block_on(cancel_all).is_ok());
+    }
+
+    #[test]
+    fn test_unanswered_requests_time_out_or_fail_with_the_connection() {
+        let orders = orders();
+        assert_eq!(orders.execute(limit_buy("100")), Err(OrderError::Timeout));
+        let caller = orders.clone();
+        let waiter = thread::spawn(move || caller.execute(OrderRequest::CancelAll { symbol: None }));
+        while send(&orders).is_empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(waiter.join().unwrap(), Err(OrderError::Timeout));
+
+        // Neither is written later nor takes the acknowledgement of the next cancel-all
+        let cancel_all = orders.submit(OrderRequest::CancelAll { symbol: None }).unwrap();
+        assert_eq!(send(&orders).len(), 1);
+        assert!(orders.handle_message(r#"{"cancel_all_orders_accepted":{}}"#));
+        assert!(cancel_all.wait(Duration::ZERO).is_ok());
+        assert!(orders.queues.lock().unwrap().awaiting.is_empty());
+
+        let pending = orders.submit(limit_buy("100")).unwrap();
+        send(&orders);
+        let queued = orders.submit(limit_buy("101")).unwrap();
+        let waiter = thread::spawn(move || block_on(pending));
+        orders.connection_lost("server went away");
+        assert_eq!(waiter.join().unwrap(), Err(OrderError::ConnectionLost("server went away".to_string())));
+
+        // Requests not yet written wait for the next connection, until the client stops
+        assert_eq!(orders.queues.lock().unwrap().outbox.len(), 1);
+        orders.stop();
+        assert_eq!(queued.wait(Duration::ZERO), Err(OrderError::Stopped));
+    }
+
+    #[test]
+    fn test_kill_switch_only_lets_cancels_through() {
+        let orders = orders();
+        // Nothing answers the cancel-all here, the switch is engaged all the same
+        assert_eq!(orders.engage_kill_switch(), Err(OrderError::Timeout));
+        assert_eq!(orders.submit(limit_buy("100")).err(), Some(OrderError::Risk(RiskError::KillSwitch)));
+        assert!(orders.submit(OrderRequest::Cancel { symbol: "BTC-USD".to_string(), order: OrderRef::Client("pt-1".to_string()) }).is_ok());
+        orders.release_kill_switch();
+        assert!(orders.submit(limit_buy("100")).is_ok());
+
+        // Risk limits can start the client with the kill switch engaged
+        let limits = serde_json::from_value(json!({ "kill_switch": true })).unwrap();
+        let limited = orders.clone().with_risk(RiskGate::new(limits, crate::state::SharedState::new()));
+        assert!(limited.kill_switch_engaged());
+    }
+
+    #[test]
+    fn test_kill_switch_drops_orders_submitted_concurrently() {
+        let orders = orders();
+        let (started, running) = channel();
+        let submitters: Vec<_> = (0..4).map(|_| {
+            let orders = orders.clone();
+            let started = started.clone();
+            thread::spawn(move || {
+                let mut pending = Vec::new();
+                loop {
+                    match orders.submit(limit_buy("100")) {
+                        Ok(order) => pending.push(order),
+                        Err(e) => return (pending, e),
+                    }
+                    if pending.len() == 1 {
+                        started.send(()).unwrap();
+                    }
+                }
+            })
+        }).collect();
+        for _ in 0..submitters.len() {
+            running.recv().unwrap();
+        }
+        assert_eq!(orders.engage_kill_switch(), Err(OrderError::Timeout));
+
+        // Nothing was written, so every order either never got queued or was dropped again
+        for submitter in submitters {
+            let (pending, refused) = submitter.join().unwrap();
+            assert_eq!(refused, OrderError::Risk(RiskError::KillSwitch));
+            for order in pending {
+                assert_eq!(order.wait(Duration::ZERO), Err(OrderError::Risk(RiskError::KillSwitch)));
+            }
+        }
+        assert!(send(&orders).iter().all(|message| message.get("cancel_all_orders").is_some()));
+    }
+
+    #[test]
+    fn test_cancel_on_disconnect() {
+        let at_login = orders_with(Duration::from_millis(200), CancelOnDisconnect::Login);
+        assert_eq!(at_login.login_url("wss://example.com/v1/trading"), "wss://example.com/v1/trading?cancel_on_disconnect=true");
+        assert_eq!(at_login.login_url("wss://example.com/v1/trading?a=1"), "wss://example.com/v1/trading?a=1&cancel_on_disconnect=true");
+        assert_eq!(orders().login_url("wss://example.com/v1/trading"), "wss://example.com/v1/trading");
+
+        // Orders stay open until a cancel is acknowledged, the server may not have honoured the request
+        at_login.connected();
+        let pending = at_login.submit(limit_buy("100")).unwrap();
+        send(&at_login);
+        at_login.connection_lost("server went away");
+        assert_eq!(at_login.manager().order(pending.client_order_id().unwrap()).unwrap().status, OrderStatus::Pending);
+        at_login.connected();
+        let sent = send(&at_login);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].get("cancel_all_orders").is_some());
+        assert!(at_login.handle_message(r#"{"cancel_all_orders_accepted":{}}"#));
+        assert_eq!(at_login.manager().order(pending.client_order_id().unwrap()).unwrap().status, OrderStatus::Cancelled);
+
+        // Otherwise all orders are cancelled once connected again
+        let on_reconnect = orders_with(Duration::from_millis(200), CancelOnDisconnect::Reconnect);
+        on_reconnect.connected();
+        assert!(send(&on_reconnect).is_empty());
+        on_reconnect.connection_lost("server went away");
+        on_reconnect.connected();
+        let sent = send(&on_reconnect);
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].get("cancel_all_orders").is_some());
+
+        // Never without being asked to
+        let off = orders();
+        off.connected();
+        off.connection_lost("server went away");
+        off.connected();
+        assert!(send(&off).is_empty());
+    }
+
+    #[test]
+    fn test_blocking_api_over_the_connection() {
+        let (url, server) = spawn_ws_server(|mut socket, _| {
+            let request: Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+            let id = request["new_order"]["client_order_id"].clone();
+            socket.send(Message::text(json!({ "order_accepted": { "client_order_id": id, "order_id": "1" } }).to_string())).unwrap();
+            while socket.read().is_ok() {}
+        });
+        let mut client = WebSocketClient::new(test_config(&url), ConnectionLifecycle::new()).unwrap();
+        let orders = orders_waiting(Duration::from_secs(10));
+
+        let (done, outcome) = channel();
+        let caller = orders.clone();
+        thread::spawn(move || done.send(caller.execute(limit_buy("100"))).unwrap());
+        let ack = loop {
+            orders.send_queued(&mut client).unwrap();
+            if let Some(Message::Text(text)) = client.read_message().unwrap() {
+                orders.handle_message(&text);
+            }
+            if let Ok(outcome) = outcome.try_recv() {
+                break outcome.unwrap();
+            }
+        };
+        assert_eq!(ack.order_id.as_deref(), Some("1"));
+
+        assert!(client.close("test finished"));
+        server.join().unwrap();
+    }
+}
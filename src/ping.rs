@@ -0,0 +1,189 @@
+use crate::metrics::{LatencyHistogram, LatencySummary};
+
+use chrono::Utc;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+// Pings never answered within this window are counted as lost and forgotten
+const PING_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Ping payload: big-endian sequence number followed by the send time in
+/// microseconds since the Unix epoch
+pub const PING_PAYLOAD_LEN: usize = 16;
+
+pub fn encode_payload(seq: u64, sent_at_micros: i64) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(PING_PAYLOAD_LEN);
+    payload.extend_from_slice(&seq.to_be_bytes());
+    payload.extend_from_slice(&sent_at_micros.to_be_bytes());
+    payload
+}
+
+pub fn decode_payload(payload: &[u8]) -> Option<(u64, i64)> {
+    if payload.len() != PING_PAYLOAD_LEN {
+        return None;
+    }
+    let seq = u64::from_be_bytes(payload[..8].try_into().ok()?);
+    let sent_at = i64::from_be_bytes(payload[8..].try_into().ok()?);
+    Some((seq, sent_at))
+}
+
+/// Tracks pings sent on a connection and the round-trip time of their pongs
+pub struct PingTracker {
+    next_seq: u64,
+    outstanding: HashMap<u64, Instant>,
+    rtt: LatencyHistogram,
+    warn_threshold: Duration,
+    last_sent: Option<Instant>,
+    lost: u64,
+}
+
+impl PingTracker {
+    pub fn new(warn_threshold: Duration) -> Self {
+        PingTracker {
+            next_seq: 1,
+            outstanding: HashMap::new(),
+            rtt: LatencyHistogram::new(),
+            warn_threshold,
+            last_sent: None,
+            lost: 0,
+        }
+    }
+
+    /// Register a new ping and return its sequence number and payload
+    pub fn next_ping(&mut self) -> (u64, Vec<u8>) {
+        let now = Instant::now();
+        let before = self.outstanding.len();
+        self.outstanding.retain(|_, sent| now.duration_since(*sent) < PING_EXPIRY);
+        self.lost += (before - self.outstanding.len()) as u64;
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.outstanding.insert(seq, now);
+        self.last_sent = Some(now);
+        (seq, encode_payload(seq, Utc::now().timestamp_micros()))
+    }
+
+    /// Match a pong against an outstanding ping, returning the round-trip time
+    pub fn record_pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        let (seq, _) = decode_payload(payload)?;
+        let sent = match self.outstanding.remove(&seq) {
+            Some(sent) => sent,
+            None => {
+                debug!(event = "pong_unmatched", seq, "Ignoring pong for unknown ping");
+                return None;
+            }
+        };
+
+        let rtt = sent.elapsed();
+        self.rtt.record(rtt);
+
+        if rtt > self.warn_threshold {
+            warn!(event = "ping_latency_high", seq, rtt_ms = rtt.as_millis() as u64,
+                  threshold_ms = self.warn_threshold.as_millis() as u64,
+                  "Ping round-trip {:?} exceeded threshold {:?}", rtt, self.warn_threshold);
+        } else {
+            debug!(event = "pong_received", seq, rtt_ms = rtt.as_millis() as u64, "Pong received");
+        }
+        Some(rtt)
+    }
+
+    /// Change the round-trip time above which a warning is logged
+    pub fn set_warn_threshold(&mut self, warn_threshold: Duration) {
+        self.warn_threshold = warn_threshold;
+    }
+
+    /// Whether `interval` has passed since the last ping was sent
+    pub fn is_due(&self, interval: Duration) -> bool {
+        self.last_sent.map_or(true, |sent| sent.elapsed() >= interval)
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        self.rtt.summary()
+    }
+
+    /// Whether a ping sent less than `within` ago is still unanswered
+    pub fn awaiting_pong(&self, within: Duration) -> bool {
+        self.outstanding.values().any(|sent| sent.elapsed() < within)
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Forget all state when a new connection replaces the old one
+    pub fn reset(&mut self) {
+        self.outstanding.clear();
+        self.rtt.reset();
+        self.last_sent = None;
+        self.lost = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_round_trip() {
+        let payload = encode_payload(42, 1_700_000_000_000_000);
+        assert_eq!(payload.len(), PING_PAYLOAD_LEN);
+        assert_eq!(decode_payload(&payload), Some((42, 1_700_000_000_000_000)));
+    }
+
+    #[test]
+    fn test_decode_rejects_foreign_payload() {
+        assert_eq!(decode_payload(&[1, 2, 3]), None);
+        assert_eq!(decode_payload(&[]), None);
+    }
+
+    #[test]
+    fn test_pong_matches_outstanding_ping() {
+        let mut tracker = PingTracker::new(Duration::from_secs(1));
+        let (seq, payload) = tracker.next_ping();
+        assert_eq!(seq, 1);
+        assert_eq!(tracker.outstanding(), 1);
+        assert!(tracker.awaiting_pong(Duration::from_secs(30)));
+        assert!(!tracker.awaiting_pong(Duration::ZERO));
+
+        let rtt = tracker.record_pong(&payload);
+        assert!(rtt.is_some());
+        assert_eq!(tracker.outstanding(), 0);
+        assert!(!tracker.awaiting_pong(Duration::from_secs(30)));
+        assert_eq!(tracker.summary().count, 1);
+    }
+
+    #[test]
+    fn test_duplicate_or_unknown_pong_ignored() {
+        let mut tracker = PingTracker::new(Duration::from_secs(1));
+        let (_, payload) = tracker.next_ping();
+        assert!(tracker.record_pong(&payload).is_some());
+        assert!(tracker.record_pong(&payload).is_none());
+        assert!(tracker.record_pong(&encode_payload(99, 0)).is_none());
+        assert_eq!(tracker.summary().count, 1);
+    }
+
+    #[test]
+    fn test_is_due() {
+        let mut tracker = PingTracker::new(Duration::from_secs(1));
+        assert!(tracker.is_due(Duration::from_secs(30)));
+        tracker.next_ping();
+        assert!(!tracker.is_due(Duration::from_secs(30)));
+        assert!(tracker.is_due(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_reset_clears_state() {
+        let mut tracker = PingTracker::new(Duration::from_secs(1));
+        let (_, payload) = tracker.next_ping();
+        tracker.record_pong(&payload);
+        tracker.next_ping();
+        tracker.reset();
+        assert_eq!(tracker.outstanding(), 0);
+        assert_eq!(tracker.summary().count, 0);
+    }
+}
@@ -0,0 +1,569 @@
+// Configuration reload: re-reads the environment file on SIGHUP or when it changes
+// on disk and works out how each changed setting reaches the running client.
+
+use crate::alerts::{load_rules, Alerts, Rule};
+use crate::config::{Config, Settings};
+use crate::logging::{parse_level, LogLevels};
+use crate::sink::{Sink, SinkConfig};
+use crate::webhook::{WebhookConfig, Webhooks};
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+// How often the environment file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Environment file whose values were loaded into the process environment at startup.
+/// Variables set before it was loaded take precedence over the file, on reload too.
+pub struct EnvFile {
+    path: PathBuf,
+    external: HashSet<String>,
+    loaded: HashSet<String>,
+}
+
+impl EnvFile {
+    /// Load the file into the process environment, before any other thread is started
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, dotenvy::Error> {
+        let path = path.as_ref().to_path_buf();
+        let values = Self::read(&path)?;
+        let external: HashSet<String> = values.keys().filter(|key| env::var_os(key).is_some()).cloned().collect();
+        let mut loaded = HashSet::new();
+        for (key, value) in values.into_iter().filter(|(key, _)| !external.contains(key)) {
+            env::set_var(&key, value);
+            loaded.insert(key);
+        }
+        Ok(EnvFile { path, external, loaded })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-read the file into settings of their own: the process environment as it was
+    /// before the file was loaded, with the file's current values on top.
+    pub fn reload(&self) -> Result<Settings, dotenvy::Error> {
+        let values = Self::read(&self.path)?;
+        let mut settings = Settings::from_env();
+        for key in &self.loaded {
+            settings.remove(key);
+        }
+        for (key, value) in values.iter().filter(|(key, _)| !self.external.contains(*key)) {
+            settings.set(key, value);
+        }
+        Ok(settings)
+    }
+
+    fn read(path: &Path) -> Result<HashMap<String, String>, dotenvy::Error> {
+        dotenvy::from_path_iter(path)?.collect()
+    }
+}
+
+/// When a changed setting takes effect
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Applies {
+    /// Immediately, on the current connection
+    Live,
+    /// When the client next connects, e.g. after a reconnect
+    NextConnection,
+    /// The client reconnects to apply it
+    Reconnect,
+    /// Only once the client is restarted, e.g. turning a service on or off
+    Restart,
+}
+
+impl fmt::Display for Applies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Applies::Live => write!(f, "live"),
+            Applies::NextConnection => write!(f, "next connection"),
+            Applies::Reconnect => write!(f, "reconnect"),
+            Applies::Restart => write!(f, "restart"),
+        }
+    }
+}
+
+/// A setting which differs between the running and the reloaded configuration
+#[derive(Clone, Debug, PartialEq)]
+pub struct Change {
+    pub setting: &'static str,
+    pub old: String,
+    pub new: String,
+    pub applies: Applies,
+}
+
+/// Outcome of a successful reload
+pub struct Reload {
+    pub config: Config,
+    /// Settings the configuration was built from, for the runs which follow
+    pub settings: Settings,
+    pub changes: Vec<Change>,
+}
+
+impl Reload {
+    /// Whether a change needs a new connection, e.g. a different URL or credentials
+    pub fn needs_reconnect(&self) -> bool {
+        self.changes.iter().any(|change| change.applies == Applies::Reconnect)
+    }
+}
+
+/// Compare two configurations. Secrets are masked and proxy credentials left out.
+pub fn diff(old: &Config, new: &Config) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let mut compare = |setting, old: String, new: String, applies| {
+        if old != new {
+            changes.push(Change { setting, old, new, applies });
+        }
+    };
+
+    compare("server_url", old.server_url.clone(), new.server_url.clone(), Applies::Reconnect);
+    compare("api_key", Config::mask_sensitive(&old.api_key), Config::mask_sensitive(&new.api_key), Applies::Reconnect);
+    if old.api_secret != new.api_secret {
+        compare("api_secret", "<hidden>".to_string(), "<changed>".to_string(), Applies::Reconnect);
+    }
+    compare("run_limits", old.limits.to_string(), new.limits.to_string(), Applies::Live);
+    compare("sleep_duration", old.sleep_duration.to_string(), new.sleep_duration.to_string(), Applies::Live);
+    compare("max_retries", old.max_retries.to_string(), new.max_retries.to_string(), Applies::Live);
+    compare("ping_interval", old.ping_interval.to_string(), new.ping_interval.to_string(), Applies::Live);
+    compare("ping_warn_ms", old.ping_warn_ms.to_string(), new.ping_warn_ms.to_string(), Applies::Live);
+
+    let proxy = |config: &Config| config.proxy.as_ref()
+        .map_or("none".to_string(), |p| format!("{}:{} (no_proxy {})", p.host, p.port, p.no_proxy.join(",")));
+    compare("proxy", proxy(old), proxy(new), Applies::NextConnection);
+    let tls = |config: &Config| format!("ca={} client_cert={} pins={}",
+        config.tls.ca_file.as_ref().map_or("system".to_string(), |p| p.display().to_string()),
+        config.tls.client_cert.as_ref().map_or("none".to_string(), |p| p.display().to_string()),
+        config.tls.pins.len());
+    compare("tls", tls(old), tls(new), Applies::NextConnection);
+    compare("transport", format!("{:?}", old.transport), format!("{:?}", new.transport), Applies::NextConnection);
+    // Header values may carry credentials, only their names are shown
+    let headers = |config: &Config| config.headers.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(",");
+    if old.headers != new.headers {
+        compare("headers", headers(old), format!("{} (values changed)", headers(new)), Applies::NextConnection);
+    }
+    compare("user_agent", old.user_agent.clone(), new.user_agent.clone(), Applies::NextConnection);
+    compare("correlation_id", old.correlation_id.clone(), new.correlation_id.clone(), Applies::NextConnection);
+    changes
+}
+
+/// Reloads configuration when asked to by SIGHUP, a change to the environment file
+/// or any other trigger calling `request`
+pub struct Reloader {
+    env_file: EnvFile,
+    requested: Arc<AtomicBool>,
+    log_levels: Option<LogLevels>,
+    sink: Option<Sink>,
+    webhooks: Option<Webhooks>,
+    alerts: Option<Alerts>,
+    alert_rules: Option<PathBuf>,
+}
+
+impl Reloader {
+    pub fn new(env_file: EnvFile) -> Self {
+        Reloader {
+            env_file,
+            requested: Arc::new(AtomicBool::new(false)),
+            log_levels: None,
+            sink: None,
+            webhooks: None,
+            alerts: None,
+            alert_rules: None,
+        }
+    }
+
+    /// Also apply PT_LOG_LEVEL, PT_CONSOLE_LOG_LEVEL and PT_FILE_LOG_LEVEL on reload
+    pub fn with_log_levels(mut self, log_levels: LogLevels) -> Self {
+        self.log_levels = Some(log_levels);
+        self
+    }
+
+    /// Also apply the PT_SINK_* settings to the running sink on reload
+    pub fn with_sink(mut self, sink: Option<Sink>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Also apply the PT_WEBHOOK_* settings to the running webhooks on reload
+    pub fn with_webhooks(mut self, webhooks: Option<Webhooks>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Also re-read the alert rules on reload, from `path` when given and PT_ALERT_RULES otherwise
+    pub fn with_alerts(mut self, alerts: Option<Alerts>, path: Option<PathBuf>) -> Self {
+        self.alerts = alerts;
+        self.alert_rules = path;
+        self
+    }
+
+    /// Handle for requesting a reload from another thread
+    pub fn trigger(&self) -> ReloadTrigger {
+        ReloadTrigger { requested: self.requested.clone() }
+    }
+
+    /// Whether a reload was requested since the last call
+    pub fn take_request(&self) -> bool {
+        self.requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Request a reload on SIGHUP
+    #[cfg(unix)]
+    pub fn install_signal_handler(&self) -> io::Result<()> {
+        use signal_hook::consts::SIGHUP;
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGHUP])?;
+        let trigger = self.trigger();
+        thread::Builder::new().name("sighup".to_string()).spawn(move || {
+            for _ in signals.forever() {
+                trigger.request("SIGHUP");
+            }
+        })?;
+        Ok(())
+    }
+
+    // There is no SIGHUP to listen for, the file watch still applies
+    #[cfg(not(unix))]
+    pub fn install_signal_handler(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Request a reload whenever the environment file's modification time changes
+    pub fn watch_file(&self) -> io::Result<()> {
+        let path = self.env_file.path().to_path_buf();
+        let trigger = self.trigger();
+        let mut last_modified = modified(&path);
+        thread::Builder::new().name("config-watch".to_string()).spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            let modified = modified(&path);
+            if modified != last_modified {
+                last_modified = modified;
+                trigger.request("file changed");
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Re-read the environment file and build the configuration it describes.
+    /// `previous` are the settings `current` was built from. On error `current` stays in effect.
+    pub fn reload(&self, current: &Config, previous: &Settings) -> Result<Reload, String> {
+        let path = self.env_file.path().display().to_string();
+        let settings = self.env_file.reload()
+            .map_err(|e| format!("Failed to read environment file '{}': {}", path, e))?;
+        let mut config = Config::from_settings(&settings)?;
+        config.transport.validate()?;
+        // A generated correlation id is per run, not something to reload
+        if settings.var("PT_CORRELATION_ID").is_err() {
+            config.correlation_id = current.correlation_id.clone();
+        }
+
+        let services = self.services(&settings)?;
+
+        let mut changes = diff(current, &config);
+        if let Some(log_levels) = &self.log_levels {
+            changes.extend(reload_log_levels(log_levels, &settings));
+        }
+        changes.extend(self.reload_services(services, previous, &settings));
+        for change in &changes {
+            info!(event = "config_changed", setting = change.setting, old = %change.old, new = %change.new,
+                  applies = %change.applies, "Setting {} changed from {} to {} ({})",
+                  change.setting, change.old, change.new, change.applies);
+        }
+        Ok(Reload { config, settings, changes })
+    }
+
+    // Settings of the optional services, all checked before any is applied
+    fn services(&self, settings: &Settings) -> Result<Services, String> {
+        Ok(Services {
+            sink: SinkConfig::from_settings(settings)?,
+            webhooks: WebhookConfig::from_settings(settings)?,
+            alert_rules: self.alert_rules_path(settings).map(|path| load_rules(&path)).transpose()?,
+        })
+    }
+
+    fn alert_rules_path(&self, settings: &Settings) -> Option<PathBuf> {
+        self.alert_rules.clone().or_else(|| settings.value("PT_ALERT_RULES").map(PathBuf::from))
+    }
+
+    // Running services take their new settings. Starting or stopping one needs a restart,
+    // which is reported when the settings ask for it and not again on later reloads.
+    fn reload_services(&self, services: Services, previous: &Settings, settings: &Settings) -> Vec<Change> {
+        let mut changes = Vec::new();
+        let restart = |setting, old: Option<String>, new: Option<String>| (old != new).then(|| Change {
+            setting,
+            old: old.unwrap_or_else(|| "off".to_string()),
+            new: new.unwrap_or_else(|| "off".to_string()),
+            applies: Applies::Restart,
+        });
+
+        match (&self.sink, services.sink) {
+            (Some(sink), Some(config)) => {
+                let old = sink.config();
+                match sink.reconfigure(&config) {
+                    Ok(true) => changes.push(Change { setting: "sink", old: describe_sink(&old), new: describe_sink(&config), applies: Applies::Live }),
+                    Ok(false) => {}
+                    Err(e) => warn!(event = "sink_reload_failed", "Keeping the current sink settings: {}", e),
+                }
+            }
+            (_, config) => {
+                let old = SinkConfig::from_settings(previous).ok().flatten();
+                changes.extend(restart("sink", old.as_ref().map(describe_sink), config.as_ref().map(describe_sink)));
+            }
+        }
+
+        match (&self.webhooks, services.webhooks) {
+            (Some(webhooks), Some(config)) => {
+                let old = webhooks.config();
+                if old.secret != config.secret {
+                    changes.push(Change { setting: "webhook_secret", old: "<hidden>".to_string(), new: "<changed>".to_string(), applies: Applies::Live });
+                }
+                let (old, new) = (describe_webhooks(&old), describe_webhooks(&config));
+                if webhooks.reconfigure(config) && old != new {
+                    changes.push(Change { setting: "webhooks", old, new, applies: Applies::Live });
+                }
+            }
+            (_, config) => {
+                let old = WebhookConfig::from_settings(previous).ok().flatten();
+                changes.extend(restart("webhooks", old.as_ref().map(describe_webhooks), config.as_ref().map(describe_webhooks)));
+            }
+        }
+
+        match (&self.alerts, services.alert_rules) {
+            (Some(alerts), Some(rules)) => {
+                let old = alerts.rule_names().join(",");
+                let mut new = rules.iter().map(|rule| rule.name.as_str()).collect::<Vec<_>>().join(",");
+                if alerts.replace_rules(rules) {
+                    if new == old {
+                        new.push_str(" (conditions changed)");
+                    }
+                    changes.push(Change { setting: "alert_rules", old, new, applies: Applies::Live });
+                }
+            }
+            _ => {
+                let path = |settings| self.alert_rules_path(settings).map(|path| path.display().to_string());
+                changes.extend(restart("alert_rules", path(previous), path(settings)));
+            }
+        }
+        changes
+    }
+}
+
+// What the reloaded settings ask of the optional services
+struct Services {
+    sink: Option<SinkConfig>,
+    webhooks: Option<WebhookConfig>,
+    alert_rules: Option<Vec<Rule>>,
+}
+
+// Passwords in a Redis URL are not shown
+fn describe_sink(config: &SinkConfig) -> String {
+    let url = match url::Url::parse(&config.url) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => config.url.clone(),
+    };
+    format!("{} {} topic={} batch={}/{:?} buffer={} retry={:?}",
+        config.backend, url, config.topic, config.batch_size, config.linger, config.buffer, config.retry_backoff)
+}
+
+// Endpoint URLs may carry tokens, only their hosts are shown
+fn describe_webhooks(config: &WebhookConfig) -> String {
+    let hosts = config.urls.iter().map(|url| url.host_str().unwrap_or_default()).collect::<Vec<_>>().join(",");
+    let mut events = config.events.iter().map(String::as_str).collect::<Vec<_>>();
+    events.sort_unstable();
+    let thresholds = config.balance_thresholds.iter().map(|(currency, amount)| format!("{}:{}", currency, amount)).collect::<Vec<_>>();
+    format!("{} endpoints ({}) events={} position_change={} balance_thresholds={} attempts={} retry={:?}",
+        config.urls.len(), hosts, events.join(","), config.position_change.map_or("none".to_string(), |change| change.to_string()),
+        thresholds.join(","), config.max_attempts, config.retry_backoff)
+}
+
+/// Requests a reload from any thread
+#[derive(Clone)]
+pub struct ReloadTrigger {
+    requested: Arc<AtomicBool>,
+}
+
+impl ReloadTrigger {
+    pub fn request(&self, trigger: &str) {
+        info!(event = "reload_requested", trigger, "Configuration reload requested ({})", trigger);
+        self.requested.store(true, Ordering::SeqCst);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Level variables fall back to PT_LOG_LEVEL, unset ones keep the current level
+fn reload_log_levels(log_levels: &LogLevels, settings: &Settings) -> Vec<Change> {
+    let (console, file) = log_levels.current();
+    let level = |name: &str| settings.var(name).or_else(|_| settings.var("PT_LOG_LEVEL")).ok().map(|v| parse_level(&v));
+    let new_console = level("PT_CONSOLE_LOG_LEVEL").unwrap_or(console);
+    let new_file = level("PT_FILE_LOG_LEVEL").unwrap_or(file);
+    if (new_console, new_file) == (console, file) {
+        return Vec::new();
+    }
+    if let Err(e) = log_levels.set(new_console, new_file) {
+        warn!(event = "log_level_reload_failed", "Failed to change log levels: {}", e);
+        return Vec::new();
+    }
+
+    let mut changes = Vec::new();
+    for (setting, old, new) in [("console_log_level", console, new_console), ("file_log_level", file, new_file)] {
+        if old != new {
+            changes.push(Change { setting, old: old.to_string(), new: new.to_string(), applies: Applies::Live });
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+
+    fn write_env_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("client-rust-ws-{}-{}.env", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_env_file_reload_leaves_the_environment_alone() {
+        env::remove_var("PT_RELOAD_TEST_KEPT");
+        env::remove_var("PT_RELOAD_TEST_DROPPED");
+        env::set_var("PT_RELOAD_TEST_EXTERNAL", "from-shell");
+
+        let path = write_env_file("reload", "PT_RELOAD_TEST_KEPT=1\nPT_RELOAD_TEST_DROPPED=x\nPT_RELOAD_TEST_EXTERNAL=from-file\n");
+        let env_file = EnvFile::load(&path).unwrap();
+        assert_eq!(env::var("PT_RELOAD_TEST_KEPT").unwrap(), "1");
+        assert_eq!(env::var("PT_RELOAD_TEST_DROPPED").unwrap(), "x");
+        assert_eq!(env::var("PT_RELOAD_TEST_EXTERNAL").unwrap(), "from-shell");
+
+        fs::write(&path, "PT_RELOAD_TEST_KEPT=2\nPT_RELOAD_TEST_EXTERNAL=changed\n").unwrap();
+        let settings = env_file.reload().unwrap();
+        assert_eq!(settings.var("PT_RELOAD_TEST_KEPT").unwrap(), "2");
+        assert!(settings.var("PT_RELOAD_TEST_DROPPED").is_err());
+        assert_eq!(settings.var("PT_RELOAD_TEST_EXTERNAL").unwrap(), "from-shell");
+        assert_eq!(env::var("PT_RELOAD_TEST_KEPT").unwrap(), "1");
+        assert_eq!(env::var("PT_RELOAD_TEST_DROPPED").unwrap(), "x");
+
+        env::remove_var("PT_RELOAD_TEST_KEPT");
+        env::remove_var("PT_RELOAD_TEST_DROPPED");
+        env::remove_var("PT_RELOAD_TEST_EXTERNAL");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_diff_classifies_and_masks() {
+        let old = test_config("ws://127.0.0.1:1/ws");
+        let mut new = old.clone();
+        assert!(diff(&old, &new).is_empty());
+
+        new.ping_warn_ms = 250;
+        new.user_agent = "desk/2".to_string();
+        new.api_secret = "rotated".to_string();
+        new.headers = vec![("X-Token".to_string(), "secret-value".to_string())];
+        let changes = diff(&old, &new);
+
+        let applies = |setting| changes.iter().find(|c| c.setting == setting).map(|c| c.applies);
+        assert_eq!(applies("ping_warn_ms"), Some(Applies::Live));
+        assert_eq!(applies("user_agent"), Some(Applies::NextConnection));
+        assert_eq!(applies("api_secret"), Some(Applies::Reconnect));
+        assert_eq!(applies("server_url"), None);
+        let rendered = format!("{:?}", changes);
+        assert!(!rendered.contains("rotated"));
+        assert!(!rendered.contains("secret-value"));
+    }
+
+    #[test]
+    fn test_reloader_applies_file_changes() {
+        for name in ["PT_SERVER_URL", "PT_API_KEY", "PT_API_SECRET", "PT_EPOCH_COUNT", "PT_WS_SLEEP", "PT_PING_WARN_MS"] {
+            env::remove_var(name);
+        }
+        let base = "PT_SERVER_URL=ws://127.0.0.1:1/ws\nPT_API_KEY=key\nPT_API_SECRET=secret\nPT_EPOCH_COUNT=3\nPT_WS_SLEEP=1\n";
+        let path = write_env_file("reloader", base);
+        let reloader = Reloader::new(EnvFile::load(&path).unwrap());
+        let current = Config::from_settings(&Settings::from_env()).unwrap();
+
+        assert!(!reloader.take_request());
+        reloader.trigger().request("test");
+        assert!(reloader.take_request());
+        assert!(!reloader.take_request());
+
+        fs::write(&path, format!("{}PT_PING_WARN_MS=50\n", base)).unwrap();
+        let reload = reloader.reload(&current, &Settings::from_env()).unwrap();
+        assert_eq!(reload.config.ping_warn_ms, 50);
+        assert_eq!(reload.settings.var("PT_PING_WARN_MS").unwrap(), "50");
+        assert!(env::var("PT_PING_WARN_MS").is_err());
+        assert_eq!(reload.config.correlation_id, current.correlation_id);
+        assert_eq!(reload.changes.len(), 1);
+        assert!(!reload.needs_reconnect());
+
+        fs::write(&path, base.replace("127.0.0.1:1", "127.0.0.1:2")).unwrap();
+        assert!(reloader.reload(&current, &Settings::from_env()).unwrap().needs_reconnect());
+
+        // An invalid file is rejected, and the environment is never changed
+        fs::write(&path, base.replace("PT_EPOCH_COUNT=3", "PT_EPOCH_COUNT=many")).unwrap();
+        assert!(reloader.reload(&current, &Settings::from_env()).is_err());
+        assert_eq!(env::var("PT_SERVER_URL").unwrap(), "ws://127.0.0.1:1/ws");
+        assert_eq!(env::var("PT_EPOCH_COUNT").unwrap(), "3");
+
+        for name in ["PT_SERVER_URL", "PT_API_KEY", "PT_API_SECRET", "PT_EPOCH_COUNT", "PT_WS_SLEEP", "PT_PING_WARN_MS"] {
+            env::remove_var(name);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reloader_reconfigures_services() {
+        let names = ["PT_SERVER_URL", "PT_API_KEY", "PT_API_SECRET", "PT_EPOCH_COUNT", "PT_WS_SLEEP", "PT_WEBHOOK_URL",
+            "PT_WEBHOOK_SECRET", "PT_WEBHOOK_EVENTS", "PT_SINK", "PT_SINK_URL"];
+        for name in names {
+            env::remove_var(name);
+        }
+        let base = "PT_SERVER_URL=ws://127.0.0.1:1/ws\nPT_API_KEY=key\nPT_API_SECRET=secret\nPT_EPOCH_COUNT=3\nPT_WS_SLEEP=1\n\
+                    PT_WEBHOOK_URL=http://127.0.0.1:1/hook?token=t0k3n\nPT_WEBHOOK_SECRET=s3cret\n";
+        let path = write_env_file("services", base);
+        let env_file = EnvFile::load(&path).unwrap();
+        let settings = Settings::from_env();
+        let current = Config::from_settings(&settings).unwrap();
+        let webhooks = Webhooks::start(WebhookConfig::from_settings(&settings).unwrap().unwrap(), None).unwrap();
+        let reloader = Reloader::new(env_file).with_webhooks(Some(webhooks.clone()));
+
+        fs::write(&path, format!("{}PT_WEBHOOK_EVENTS=disconnect\nPT_SINK=redis\nPT_SINK_URL=redis://user:pw@127.0.0.1:1/0\n",
+            base.replace("s3cret", "rotated"))).unwrap();
+        let reload = reloader.reload(&current, &settings).unwrap();
+        let applies = |setting| reload.changes.iter().find(|c| c.setting == setting).map(|c| c.applies);
+        assert_eq!(applies("webhooks"), Some(Applies::Live));
+        assert_eq!(applies("webhook_secret"), Some(Applies::Live));
+        assert_eq!(applies("sink"), Some(Applies::Restart));
+        assert_eq!(webhooks.config().events.len(), 1);
+        assert_eq!(webhooks.config().secret.as_deref(), Some("rotated"));
+        let rendered = format!("{:?}", reload.changes);
+        assert!(!rendered.contains("t0k3n") && !rendered.contains(":pw@") && !rendered.contains("rotated"));
+
+        // Nothing left to apply, and the pending restart is not reported again
+        assert!(reloader.reload(&current, &reload.settings).unwrap().changes.is_empty());
+
+        // Settings a service cannot run with reject the whole reload
+        fs::write(&path, format!("{}PT_WEBHOOK_EVENTS=fire\n", base)).unwrap();
+        assert!(reloader.reload(&current, &reload.settings).err().unwrap().contains("PT_WEBHOOK_EVENTS"));
+        assert_eq!(webhooks.config().events.len(), 1);
+
+        for name in names {
+            env::remove_var(name);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
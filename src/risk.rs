@@ -0,0 +1,356 @@
+// Pre-trade risk checks: limits declared in a JSON file which every order and
+// amendment must pass before it is written to the connection.
+
+use crate::order_manager::{OrderManager, TrackedOrder};
+use crate::orders::{OrderRequest, Side};
+use crate::state::SharedState;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Limits of one instrument; those left out are not checked
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstrumentLimits {
+    /// Largest quantity of one order
+    pub max_order_size: Option<f64>,
+    /// Largest quantity times price of one order, market orders priced at the mid
+    pub max_order_notional: Option<f64>,
+    /// Largest absolute position the order could lead to, counting open orders on the same side
+    pub max_position: Option<f64>,
+    /// Furthest a limit price may be from the mid of the local book, in basis points
+    pub price_collar_bps: Option<f64>,
+}
+
+impl InstrumentLimits {
+    // Limits of `self`, falling back to `default` for those left out
+    fn or(&self, default: &InstrumentLimits) -> InstrumentLimits {
+        InstrumentLimits {
+            max_order_size: self.max_order_size.or(default.max_order_size),
+            max_order_notional: self.max_order_notional.or(default.max_order_notional),
+            max_position: self.max_position.or(default.max_position),
+            price_collar_bps: self.price_collar_bps.or(default.price_collar_bps),
+        }
+    }
+
+    fn values(&self) -> [Option<f64>; 4] {
+        [self.max_order_size, self.max_order_notional, self.max_position, self.price_collar_bps]
+    }
+}
+
+/// Contents of the risk limits file, e.g. `{"max_open_orders": 20, "default": {"price_collar_bps": 500},
+/// "instruments": {"BTC-USD-PERPETUAL": {"max_order_size": 1}}}`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskLimits {
+    /// Start with the kill switch engaged, refusing every new order and amendment
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// Most orders open at once, counting those not acknowledged yet
+    pub max_open_orders: Option<usize>,
+    /// Limits of instruments not listed in `instruments`, and of those listed where left out
+    #[serde(default)]
+    pub default: InstrumentLimits,
+    #[serde(default)]
+    pub instruments: BTreeMap<String, InstrumentLimits>,
+}
+
+impl RiskLimits {
+    pub fn for_instrument(&self, symbol: &str) -> InstrumentLimits {
+        self.instruments.get(symbol).map_or_else(|| self.default.clone(), |limits| limits.or(&self.default))
+    }
+}
+
+/// Read and check the risk limits of a JSON file
+pub fn load_limits(path: &Path) -> Result<RiskLimits, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read risk limits {}: {}", path.display(), e))?;
+    let limits: RiskLimits = serde_json::from_str(&text).map_err(|e| format!("Invalid risk limits {}: {}", path.display(), e))?;
+    let all = std::iter::once(("default", &limits.default)).chain(limits.instruments.iter().map(|(symbol, limits)| (symbol.as_str(), limits)));
+    for (name, instrument) in all {
+        if instrument.values().iter().flatten().any(|limit| !limit.is_finite() || *limit < 0.0) {
+            return Err(format!("Risk limits of {} must be finite and zero or more", name));
+        }
+    }
+    Ok(limits)
+}
+
+/// Why the risk gate refused a request
+#[derive(Clone, Debug, PartialEq)]
+pub enum RiskError {
+    KillSwitch,
+    OrderSize { symbol: String, quantity: f64, limit: f64 },
+    OrderNotional { symbol: String, notional: f64, limit: f64 },
+    OpenOrders { open: usize, limit: usize },
+    PriceCollar { symbol: String, price: f64, mid: f64, deviation_bps: f64, limit_bps: f64 },
+    Position { symbol: String, position: f64, resulting: f64, limit: f64 },
+    /// A limit needs the mid price, and no book with both sides has been received
+    NoReferencePrice { symbol: String },
+    /// A position limit is set, and no position summary has been received
+    NoPosition { symbol: String },
+    /// A number of the request could not be read
+    Unreadable(String),
+}
+
+impl fmt::Display for RiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskError::KillSwitch => write!(f, "the kill switch is engaged"),
+            RiskError::OrderSize { symbol, quantity, limit } =>
+                write!(f, "quantity {} of {} is above the limit of {}", quantity, symbol, limit),
+            RiskError::OrderNotional { symbol, notional, limit } =>
+                write!(f, "notional {} of {} is above the limit of {}", notional, symbol, limit),
+            RiskError::OpenOrders { open, limit } => write!(f, "{} orders are open, the limit is {}", open, limit),
+            RiskError::PriceCollar { symbol, price, mid, deviation_bps, limit_bps } =>
+                write!(f, "price {} of {} is {:.1} bps from the mid {}, above the collar of {} bps", price, symbol, deviation_bps, mid, limit_bps),
+            RiskError::Position { symbol, position, resulting, limit } =>
+                write!(f, "position in {} of {} could reach {}, above the limit of {}", symbol, position, resulting, limit),
+            RiskError::NoReferencePrice { symbol } => write!(f, "no book received for {} to check the price against", symbol),
+            RiskError::NoPosition { symbol } => write!(f, "no position summary received to check the position in {}", symbol),
+            RiskError::Unreadable(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RiskError {}
+
+// What a request would add: an order, or the increase of an amended one
+struct Exposure<'a> {
+    symbol: &'a str,
+    /// `None` for an amended order not tracked here, which is checked as either side
+    side: Option<Side>,
+    /// Quantity of the whole order, for the size and notional limits
+    quantity: Option<f64>,
+    price: Option<f64>,
+    /// Quantity the position could grow by
+    increase: f64,
+    /// Order being amended, left out of the open orders counted against the position limit
+    amended: Option<String>,
+}
+
+/// Checks requests against the limits, on the books and positions of the shared state. Cheap to clone.
+#[derive(Clone)]
+pub struct RiskGate {
+    limits: Arc<RiskLimits>,
+    state: SharedState,
+}
+
+impl RiskGate {
+    pub fn new(limits: RiskLimits, state: SharedState) -> Self {
+        RiskGate { limits: Arc::new(limits), state }
+    }
+
+    pub fn limits(&self) -> &RiskLimits {
+        &self.limits
+    }
+
+    /// Refuse or pass `request`; cancels always pass
+    pub fn check(&self, request: &OrderRequest, manager: &OrderManager) -> Result<(), RiskError> {
+        let exposure = match request {
+            OrderRequest::Cancel { .. } | OrderRequest::CancelAll { .. } => return Ok(()),
+            OrderRequest::New(order) => {
+                if let Some(limit) = self.limits.max_open_orders {
+                    let open = manager.open_orders().len();
+                    if open >= limit {
+                        return Err(RiskError::OpenOrders { open, limit });
+                    }
+                }
+                let quantity = number("quantity", &order.quantity)?;
+                Exposure {
+                    symbol: &order.symbol,
+                    side: Some(order.side),
+                    quantity: Some(quantity),
+                    price: order.price.as_deref().map(|price| number("price", price)).transpose()?,
+                    increase: quantity,
+                    amended: None,
+                }
+            }
+            OrderRequest::Amend { symbol, order, price, quantity } => {
+                let tracked: Option<TrackedOrder> = manager.find(order);
+                let quantity = quantity.as_deref().map(|quantity| number("quantity", quantity)).transpose()?;
+                let price = price.as_deref().map(|price| number("price", price)).transpose()?;
+                let previous = tracked.as_ref().map(|order| order.quantity);
+                Exposure {
+                    symbol,
+                    side: tracked.as_ref().map(|order| order.side),
+                    quantity: quantity.or(previous),
+                    price: price.or_else(|| tracked.as_ref()?.price.as_deref()?.parse().ok()),
+                    // Nothing is known of what an untracked order already counts for
+                    increase: match (quantity, previous) {
+                        (Some(quantity), Some(previous)) => (quantity - previous).max(0.0),
+                        (Some(quantity), None) => quantity,
+                        (None, _) => 0.0,
+                    },
+                    amended: tracked.map(|order| order.client_order_id),
+                }
+            }
+        };
+        self.check_exposure(&exposure, manager)
+    }
+
+    fn check_exposure(&self, exposure: &Exposure, manager: &OrderManager) -> Result<(), RiskError> {
+        let symbol = exposure.symbol.to_string();
+        let limits = self.limits.for_instrument(&symbol);
+        let mid = self.state.lock().mid(&symbol);
+        let reference = || mid.ok_or_else(|| RiskError::NoReferencePrice { symbol: symbol.clone() });
+
+        if let (Some(limit), Some(quantity)) = (limits.max_order_size, exposure.quantity) {
+            if quantity > limit {
+                return Err(RiskError::OrderSize { symbol, quantity, limit });
+            }
+        }
+        if let (Some(limit_bps), Some(price)) = (limits.price_collar_bps, exposure.price) {
+            let mid = reference()?;
+            let deviation_bps = (price - mid).abs() / mid * 10_000.0;
+            if deviation_bps > limit_bps {
+                return Err(RiskError::PriceCollar { symbol, price, mid, deviation_bps, limit_bps });
+            }
+        }
+        if let (Some(limit), Some(quantity)) = (limits.max_order_notional, exposure.quantity) {
+            let notional = quantity * exposure.price.map_or_else(reference, Ok)?;
+            if notional > limit {
+                return Err(RiskError::OrderNotional { symbol, notional, limit });
+            }
+        }
+        if let Some(limit) = limits.max_position {
+            let position = self.position(&symbol).ok_or_else(|| RiskError::NoPosition { symbol: symbol.clone() })?;
+            let sides = match exposure.side {
+                Some(side) => vec![side],
+                None => vec![Side::Buy, Side::Sell],
+            };
+            for side in sides {
+                // Every open order on the same side could fill as well
+                let open: f64 = manager.open_orders().iter()
+                    .filter(|order| order.symbol == symbol && order.side == side && Some(&order.client_order_id) != exposure.amended.as_ref())
+                    .map(|order| order.remaining_quantity)
+                    .sum();
+                let amended_remaining = exposure.amended.as_ref()
+                    .and_then(|id| manager.order(id))
+                    .map_or(0.0, |order| order.remaining_quantity);
+                let sign = if side == Side::Buy { 1.0 } else { -1.0 };
+                let resulting = position + sign * (open + amended_remaining + exposure.increase);
+                // Orders which bring the position back towards the limit are let through
+                if resulting.abs() > limit && resulting.abs() > position.abs() {
+                    return Err(RiskError::Position { symbol, position, resulting, limit });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Size of the position in `symbol` from the latest position summary, zero without one in it
+    fn position(&self, symbol: &str) -> Option<f64> {
+        Some(self.state.lock().position_sizes()?.get(symbol).copied().unwrap_or(0.0))
+    }
+}
+
+fn number(name: &str, value: &str) -> Result<f64, RiskError> {
+    value.parse::<f64>().map_err(|_| RiskError::Unreadable(format!("{} '{}' is not a number", name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::{NewOrder, OrderRef, TimeInForce};
+    use serde_json::{json, Value};
+
+    fn gate(limits: Value) -> (RiskGate, OrderManager) {
+        let state = SharedState::new();
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"BTC-USD","buy":[{"price":"99","quantity":"1"}],"sell":[{"price":"101","quantity":"1"}]}}"#);
+        state.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"1"}]}}"#);
+        (RiskGate::new(serde_json::from_value(limits).unwrap(), state), OrderManager::new())
+    }
+
+    fn order(side: Side, quantity: &str, price: Option<&str>) -> OrderRequest {
+        OrderRequest::New(NewOrder {
+            symbol: "BTC-USD".to_string(),
+            side,
+            quantity: quantity.to_string(),
+            price: price.map(str::to_string),
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            client_order_id: None,
+        })
+    }
+
+    #[test]
+    fn test_order_limits() {
+        let (gate, manager) = gate(json!({
+            "default": { "max_order_size": 10, "price_collar_bps": 500 },
+            "instruments": { "BTC-USD": { "max_order_size": 2, "max_order_notional": 150 } },
+        }));
+        assert_eq!(gate.check(&order(Side::Buy, "1", Some("100")), &manager), Ok(()));
+        assert!(matches!(gate.check(&order(Side::Buy, "3", Some("100")), &manager), Err(RiskError::OrderSize { limit, .. }) if limit == 2.0));
+        assert!(matches!(gate.check(&order(Side::Buy, "1", Some("106")), &manager), Err(RiskError::PriceCollar { mid, .. }) if mid == 100.0));
+        // Market orders are priced at the mid
+        assert!(matches!(gate.check(&order(Side::Sell, "1.6", None), &manager), Err(RiskError::OrderNotional { notional, .. }) if notional == 160.0));
+        let unpriced = OrderRequest::New(NewOrder {
+            symbol: "ETH-USD".to_string(),
+            side: Side::Buy,
+            quantity: "1".to_string(),
+            price: Some("2000".to_string()),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            client_order_id: None,
+        });
+        assert_eq!(gate.check(&unpriced, &manager), Err(RiskError::NoReferencePrice { symbol: "ETH-USD".to_string() }));
+    }
+
+    #[test]
+    fn test_open_orders_and_position_limits() {
+        let (gate, manager) = gate(json!({ "max_open_orders": 2, "default": { "max_position": 3 } }));
+        let open = |id: &str, side, quantity: &str| manager.track(&NewOrder {
+            symbol: "BTC-USD".to_string(),
+            side,
+            quantity: quantity.to_string(),
+            price: Some("100".to_string()),
+            time_in_force: TimeInForce::GoodTillCancelled,
+            client_order_id: Some(id.to_string()),
+        }, id);
+
+        // Long 1 with 1.5 more open to buy leaves room for 0.5
+        open("pt-1", Side::Buy, "1.5");
+        assert_eq!(gate.check(&order(Side::Buy, "0.5", Some("100")), &manager), Ok(()));
+        assert!(matches!(gate.check(&order(Side::Buy, "0.6", Some("100")), &manager),
+            Err(RiskError::Position { resulting, .. }) if (resulting - 3.1).abs() < 1e-9));
+        assert_eq!(gate.check(&order(Side::Sell, "4", Some("100")), &manager), Ok(()));
+        let amend = |quantity: &str| OrderRequest::Amend {
+            symbol: "BTC-USD".to_string(),
+            order: OrderRef::Client("pt-1".to_string()),
+            price: None,
+            quantity: Some(quantity.to_string()),
+        };
+        assert_eq!(gate.check(&amend("2"), &manager), Ok(()));
+        assert!(matches!(gate.check(&amend("2.5"), &manager), Err(RiskError::Position { .. })));
+
+        // An order placed elsewhere counts in full, on whichever side would breach
+        let untracked = |quantity: &str| OrderRequest::Amend {
+            symbol: "BTC-USD".to_string(),
+            order: OrderRef::Server("77".to_string()),
+            price: None,
+            quantity: Some(quantity.to_string()),
+        };
+        assert!(matches!(gate.check(&untracked("0.6"), &manager),
+            Err(RiskError::Position { resulting, .. }) if (resulting - 3.1).abs() < 1e-9));
+        assert_eq!(gate.check(&untracked("0.5"), &manager), Ok(()));
+        let reprice = OrderRequest::Amend { symbol: "BTC-USD".to_string(), order: OrderRef::Server("77".to_string()),
+            price: Some("100".to_string()), quantity: None };
+        assert_eq!(gate.check(&reprice, &manager), Ok(()));
+
+        open("pt-2", Side::Sell, "1");
+        assert_eq!(gate.check(&order(Side::Sell, "1", Some("100")), &manager), Err(RiskError::OpenOrders { open: 2, limit: 2 }));
+    }
+
+    #[test]
+    fn test_load_limits_rejects_bad_files() {
+        let path = std::env::temp_dir().join(format!("client-rust-ws-risk-{}.json", std::process::id()));
+        let load = |text: &str| {
+            std::fs::write(&path, text).unwrap();
+            load_limits(&path)
+        };
+        assert_eq!(load(r#"{"instruments":{"BTC-USD":{"max_order_size":1}}}"#).unwrap().for_instrument("BTC-USD").max_order_size, Some(1.0));
+        assert!(load(r#"{"max_order_size":1}"#).unwrap_err().contains("unknown field"));
+        assert!(load(r#"{"default":{"price_collar_bps":-5}}"#).unwrap_err().contains("default"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+// Coordinated shutdown: the signal which requested it, interruptible waits for
+// the run loop and the outputs to flush before the process exits.
+
+use crate::console::console;
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+type FlushFn = Box<dyn FnMut() -> io::Result<()> + Send>;
+
+// Granularity of interruptible sleeps
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+/// Shutdown request shared between the signal handler and the run loop
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    reason: Mutex<Option<String>>,
+    flushes: Mutex<Vec<(String, FlushFn)>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the run loop to stop. The first reason given is kept.
+    pub fn request(&self, reason: &str) {
+        let mut current = self.inner.reason.lock().unwrap();
+        if current.is_none() {
+            *current = Some(reason.to_string());
+        }
+        self.inner.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Why shutdown was requested, e.g. the signal name
+    pub fn reason(&self) -> Option<String> {
+        self.inner.reason.lock().unwrap().clone()
+    }
+
+    /// Sleep for `duration`, waking early when shutdown is requested.
+    /// Returns whether shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_unless(duration, || false)
+    }
+
+    /// Sleep for `duration`, waking early when shutdown is requested or `wake` returns true.
+    /// Returns whether shutdown was requested.
+    pub fn sleep_unless<F: Fn() -> bool>(&self, duration: Duration, wake: F) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.is_requested() {
+            let now = Instant::now();
+            if now >= deadline || wake() {
+                return false;
+            }
+            thread::sleep(SLEEP_STEP.min(deadline - now));
+        }
+        true
+    }
+
+    /// Register an output to flush before exiting
+    pub fn on_flush<F>(&self, name: &str, flush: F)
+    where
+        F: FnMut() -> io::Result<()> + Send + 'static,
+    {
+        self.inner.flushes.lock().unwrap().push((name.to_string(), Box::new(flush)));
+    }
+
+    /// Flush every registered output in registration order, logging failures.
+    /// Returns the number of outputs which failed to flush.
+    pub fn flush_all(&self) -> usize {
+        let mut failed = 0;
+        for (name, flush) in self.inner.flushes.lock().unwrap().iter_mut() {
+            match flush() {
+                Ok(()) => info!(event = "flushed", output = %name, "Flushed {}", name),
+                Err(e) => {
+                    error!(event = "flush_failed", output = %name, "Failed to flush {}: {}", name, e);
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    /// Request shutdown on SIGINT and SIGTERM. A second signal exits immediately
+    /// with `force_exit_code`, for when graceful shutdown hangs.
+    #[cfg(unix)]
+    pub fn install_signal_handlers(&self, force_exit_code: i32) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        thread::Builder::new().name("signals".to_string()).spawn(move || {
+            for signal in signals.forever() {
+                let name = if signal == SIGTERM { "SIGTERM" } else { "SIGINT" };
+                shutdown.signal_received(name, force_exit_code);
+            }
+        })?;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn install_signal_handlers(&self, force_exit_code: i32) -> io::Result<()> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || shutdown.signal_received("Ctrl+C", force_exit_code))
+            .map_err(io::Error::other)
+    }
+
+    fn signal_received(&self, name: &str, force_exit_code: i32) {
+        if self.is_requested() {
+            warn!(event = "forced_exit", signal = name, "Received {} during shutdown, exiting immediately", name);
+            eprintln!("\nReceived {} again, exiting without waiting for shutdown", name);
+            std::process::exit(force_exit_code);
+        }
+        info!(event = "signal", signal = name, "Received {}, shutting down gracefully", name);
+        console!("\nReceived {}, shutting down gracefully...", name);
+        self.request(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_first_reason_is_kept() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_requested());
+        assert_eq!(shutdown.reason(), None);
+
+        shutdown.clone().request("SIGTERM");
+        shutdown.request("SIGINT");
+        assert!(shutdown.is_requested());
+        assert_eq!(shutdown.reason().as_deref(), Some("SIGTERM"));
+    }
+
+    #[test]
+    fn test_sleep_wakes_on_request() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.sleep(Duration::from_millis(10)));
+
+        let requester = shutdown.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            requester.request("test");
+        });
+        let started = Instant::now();
+        assert!(shutdown.sleep(Duration::from_secs(30)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        handle.join().unwrap();
+
+        // Woken without a shutdown
+        let shutdown = Shutdown::new();
+        let started = Instant::now();
+        assert!(!shutdown.sleep_unless(Duration::from_secs(30), || started.elapsed() > Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_flush_all_runs_every_output() {
+        let shutdown = Shutdown::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counter = calls.clone();
+        shutdown.on_flush("first", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(io::Error::other("disk full"))
+        });
+        let counter = calls.clone();
+        shutdown.on_flush("second", move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        assert_eq!(shutdown.flush_all(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
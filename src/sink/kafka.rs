@@ -0,0 +1,141 @@
+// Kafka backend: each message is produced to its topic keyed by instrument or
+// account, so one key's messages stay in order on one partition.
+
+use super::{Publisher, Record};
+
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{DeliveryResult, Header, OwnedHeaders};
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer, ProducerContext, PurgeConfig};
+use rdkafka::ClientContext;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// How long a batch may take to be acknowledged before it counts as failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Wait for room when librdkafka's local queue is full
+const QUEUE_FULL_WAIT: Duration = Duration::from_millis(100);
+
+// Keeps the first delivery error of the batch in flight
+#[derive(Default)]
+struct DeliveryReport {
+    error: Mutex<Option<KafkaError>>,
+}
+
+impl ClientContext for DeliveryReport {}
+
+impl ProducerContext for DeliveryReport {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((error, _)) = result {
+            self.error.lock().unwrap().get_or_insert_with(|| error.clone());
+        }
+    }
+}
+
+pub struct KafkaPublisher {
+    producer: BaseProducer<DeliveryReport>,
+}
+
+impl KafkaPublisher {
+    /// Producer for the comma separated `bootstrap_servers`
+    pub fn new(bootstrap_servers: &str) -> Result<Self, String> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("message.timeout.ms", DELIVERY_TIMEOUT.as_millis().to_string())
+            .create_with_context(DeliveryReport::default())
+            .map_err(|e| format!("Invalid Kafka settings: {}", e))?;
+        Ok(KafkaPublisher { producer })
+    }
+
+    // Drop what is left of a failed batch, it is sent again as a whole
+    fn abandon(&self, error: KafkaError) -> String {
+        self.producer.purge(PurgeConfig::default().queue().inflight());
+        self.producer.poll(Duration::ZERO);
+        error.to_string()
+    }
+}
+
+impl Publisher for KafkaPublisher {
+    fn publish(&mut self, batch: &[Record]) -> Result<(), String> {
+        self.producer.poll(Duration::ZERO);
+        self.producer.context().error.lock().unwrap().take();
+
+        for record in batch {
+            let headers = OwnedHeaders::new().insert(Header { key: "type", value: Some(&record.kind) });
+            let mut message = BaseRecord::to(&record.topic).key(&record.key).payload(&record.payload).headers(headers);
+            loop {
+                match self.producer.send(message) {
+                    Ok(()) => break,
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), unsent)) => {
+                        message = unsent;
+                        self.producer.poll(QUEUE_FULL_WAIT);
+                    }
+                    Err((e, _)) => return Err(self.abandon(e)),
+                }
+            }
+        }
+
+        // Queued messages are delivered or failed within DELIVERY_TIMEOUT
+        if let Err(e) = self.producer.flush(DELIVERY_TIMEOUT + Duration::from_secs(1)) {
+            return Err(self.abandon(e));
+        }
+        match self.producer.context().error.lock().unwrap().take() {
+            Some(e) => Err(format!("delivery failed: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::message::{Headers, Message};
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::{Offset, TopicPartitionList};
+
+    fn record(key: &str, payload: &str) -> Record {
+        Record { topic: "pt.trade".to_string(), key: key.to_string(), kind: "trade".to_string(), payload: payload.to_string() }
+    }
+
+    #[test]
+    fn test_publishes_keyed_records_to_mock_cluster() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("pt.trade", 1, 1).unwrap();
+        let mut publisher = KafkaPublisher::new(&cluster.bootstrap_servers()).unwrap();
+        publisher.publish(&[record("BTC-USD", "{\"a\":1}"), record("ETH-USD", "{\"b\":2}")]).unwrap();
+
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "test")
+            .create()
+            .unwrap();
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition_offset("pt.trade", 0, Offset::Beginning).unwrap();
+        consumer.assign(&partitions).unwrap();
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let message = consumer.poll(Duration::from_secs(10)).expect("no message").unwrap();
+            assert_eq!(message.headers().unwrap().get(0).value, Some("trade".as_bytes()));
+            received.push((message.key_view::<str>().unwrap().unwrap().to_string(), message.payload_view::<str>().unwrap().unwrap().to_string()));
+        }
+        assert_eq!(received, vec![("BTC-USD".to_string(), "{\"a\":1}".to_string()), ("ETH-USD".to_string(), "{\"b\":2}".to_string())]);
+    }
+
+    #[test]
+    fn test_fails_while_broker_is_down() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("pt.trade", 1, 1).unwrap();
+        let mut publisher = KafkaPublisher::new(&cluster.bootstrap_servers()).unwrap();
+        publisher.publish(&[record("BTC-USD", "{}")]).unwrap();
+
+        cluster.broker_down(1).unwrap();
+        assert!(publisher.publish(&[record("BTC-USD", "{}")]).is_err());
+        cluster.broker_up(1).unwrap();
+        publisher.publish(&[record("BTC-USD", "{}")]).unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+// Publishing sink: passes received messages on to a message bus, batched on a
+// background thread and held back while the broker is unreachable.
+
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "redis")]
+mod redis_streams;
+
+use crate::config::Settings;
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Fields naming the instrument or account a message is about, in order of preference
+const KEY_FIELDS: [&str; 4] = ["symbol", "tradeable_entity_id", "account_id", "account"];
+
+// Longest pause between attempts while the broker is unreachable
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A received message bound for the bus
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Kafka topic or Redis stream
+    pub topic: String,
+    /// Instrument or account the message is about, its type when it names neither
+    pub key: String,
+    /// Message type, the top-level key of the message
+    pub kind: String,
+    /// The message as received
+    pub payload: String,
+}
+
+impl Record {
+    /// Decode a received message, `None` unless it is a JSON object keyed by its type.
+    /// `{type}` in the topic template is replaced with the message type.
+    pub fn from_message(text: &str, topic_template: &str) -> Option<Self> {
+        let Ok(Value::Object(message)) = serde_json::from_str::<Value>(text) else {
+            return None;
+        };
+        let (kind, body) = message.iter().next()?;
+        let key = KEY_FIELDS.iter()
+            .filter_map(|field| match body.get(field)? {
+                Value::String(s) if !s.is_empty() => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+            .next()
+            .unwrap_or_else(|| kind.clone());
+        Some(Record {
+            topic: topic_template.replace("{type}", kind),
+            key,
+            kind: kind.clone(),
+            payload: text.to_string(),
+        })
+    }
+}
+
+/// A message bus client. Batches are published in order, all of the batch or an
+/// error, and sent again after an error, so consumers see messages at least once.
+pub trait Publisher: Send {
+    fn publish(&mut self, batch: &[Record]) -> Result<(), String>;
+}
+
+/// Message bus the sink publishes to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Kafka,
+    Redis,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "kafka" => Ok(Backend::Kafka),
+            "redis" => Ok(Backend::Redis),
+            other => Err(format!("Unknown PT_SINK '{}', expected kafka or redis", other)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Kafka => write!(f, "kafka"),
+            Backend::Redis => write!(f, "redis"),
+        }
+    }
+}
+
+/// Where and how received messages are published
+#[derive(Clone, Debug, PartialEq)]
+pub struct SinkConfig {
+    pub backend: Backend,
+    /// Kafka bootstrap servers (`host:port,...`) or Redis URL (`redis://host:port/db`)
+    pub url: String,
+    /// Topic or stream name, `{type}` is replaced with the message type
+    pub topic: String,
+    /// Most messages published at once
+    pub batch_size: usize,
+    /// How long a message waits for its batch to fill up
+    pub linger: Duration,
+    /// Messages held while the broker is unreachable, the oldest are dropped beyond that
+    pub buffer: usize,
+    /// First pause after a failed publish, doubled up to 30s while failures continue
+    pub retry_backoff: Duration,
+}
+
+impl SinkConfig {
+    /// Read the sink settings, `None` unless `PT_SINK` is set
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let Some(backend) = settings.value("PT_SINK") else {
+            return Ok(None);
+        };
+        let config = SinkConfig {
+            backend: backend.parse()?,
+            url: settings.value("PT_SINK_URL").ok_or("PT_SINK_URL is required with PT_SINK")?,
+            topic: settings.value("PT_SINK_TOPIC").unwrap_or_else(|| "powertrade.{type}".to_string()),
+            batch_size: settings.number("PT_SINK_BATCH_SIZE", 100)? as usize,
+            linger: Duration::from_millis(settings.number("PT_SINK_BATCH_MS", 100)?),
+            buffer: settings.number("PT_SINK_BUFFER", 100_000)? as usize,
+            retry_backoff: Duration::from_millis(settings.number("PT_SINK_RETRY_MS", 1000)?),
+        };
+        if config.batch_size == 0 || config.buffer < config.batch_size {
+            return Err("PT_SINK_BATCH_SIZE must be positive and no larger than PT_SINK_BUFFER".to_string());
+        }
+        if config.retry_backoff.is_zero() {
+            return Err("PT_SINK_RETRY_MS must be positive".to_string());
+        }
+        Ok(Some(config))
+    }
+
+    // Client for the configured bus; brokers are only contacted when publishing
+    fn publisher(&self) -> Result<Box<dyn Publisher>, String> {
+        match self.backend {
+            #[cfg(feature = "kafka")]
+            Backend::Kafka => Ok(Box::new(kafka::KafkaPublisher::new(&self.url)?)),
+            #[cfg(feature = "redis")]
+            Backend::Redis => Ok(Box::new(redis_streams::RedisPublisher::new(&self.url)?)),
+            #[allow(unreachable_patterns)]
+            backend => Err(format!("PT_SINK={} needs a build with the '{}' cargo feature", backend, backend)),
+        }
+    }
+}
+
+enum Command {
+    Publish(Record),
+    /// Publish everything held, answering with the number of messages left over
+    Flush(Sender<usize>),
+}
+
+/// Handle to the publishing thread, cheap to clone
+#[derive(Clone)]
+pub struct Sink {
+    current: Arc<Mutex<Publishing>>,
+}
+
+// The configuration in effect and the thread publishing with it
+struct Publishing {
+    config: SinkConfig,
+    commands: Sender<Command>,
+}
+
+impl Sink {
+    /// Connect the configured backend and start publishing
+    pub fn start(config: &SinkConfig) -> Result<Self, String> {
+        let publisher = config.publisher()?;
+        info!(event = "sink_started", backend = %config.backend, url = %config.url, topic = %config.topic,
+            "Publishing received messages to {} at {}", config.backend, config.url);
+        Self::with_publisher(publisher, config)
+    }
+
+    /// Start publishing to `publisher`, with the batching and buffering of `config`
+    pub fn with_publisher(publisher: Box<dyn Publisher>, config: &SinkConfig) -> Result<Self, String> {
+        Ok(Sink { current: Arc::new(Mutex::new(Self::spawn(publisher, config)?)) })
+    }
+
+    fn spawn(publisher: Box<dyn Publisher>, config: &SinkConfig) -> Result<Publishing, String> {
+        let (commands, received) = channel();
+        let worker = Worker {
+            publisher,
+            batch_size: config.batch_size,
+            linger: config.linger,
+            buffer: config.buffer,
+            retry_backoff: config.retry_backoff,
+            pending: VecDeque::new(),
+            oldest_at: None,
+            retry_at: None,
+            backoff: config.retry_backoff,
+            dropped: 0,
+        };
+        thread::Builder::new().name("sink".to_string())
+            .spawn(move || worker.run(received))
+            .map_err(|e| format!("Failed to start the publishing thread: {}", e))?;
+        Ok(Publishing { config: config.clone(), commands })
+    }
+
+    /// Publish with `config` from now on, returns whether it differed from the one in effect.
+    /// The previous thread publishes what it still holds, then stops.
+    pub fn reconfigure(&self, config: &SinkConfig) -> Result<bool, String> {
+        if self.current.lock().unwrap().config == *config {
+            return Ok(false);
+        }
+        self.replace(config.publisher()?, config)?;
+        info!(event = "sink_reconfigured", backend = %config.backend, url = %config.url, topic = %config.topic,
+            "Publishing received messages to {} at {}", config.backend, config.url);
+        Ok(true)
+    }
+
+    fn replace(&self, publisher: Box<dyn Publisher>, config: &SinkConfig) -> Result<(), String> {
+        *self.current.lock().unwrap() = Self::spawn(publisher, config)?;
+        Ok(())
+    }
+
+    /// Configuration in effect
+    pub fn config(&self) -> SinkConfig {
+        self.current.lock().unwrap().config.clone()
+    }
+
+    /// Queue a received message, without waiting for the broker
+    pub fn publish(&self, text: &str) {
+        let current = self.current.lock().unwrap();
+        if let Some(record) = Record::from_message(text, &current.config.topic) {
+            let _ = current.commands.send(Command::Publish(record));
+        }
+    }
+
+    /// Publish everything queued, waiting up to `timeout`
+    pub fn flush(&self, timeout: Duration) -> io::Result<()> {
+        let (reply, left) = channel();
+        self.current.lock().unwrap().commands.send(Command::Flush(reply))
+            .map_err(|_| io::Error::other("publishing thread has stopped"))?;
+        match left.recv_timeout(timeout) {
+            Ok(0) => Ok(()),
+            Ok(left) => Err(io::Error::other(format!("{} messages could not be published", left))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out publishing queued messages")),
+        }
+    }
+}
+
+struct Worker {
+    publisher: Box<dyn Publisher>,
+    batch_size: usize,
+    linger: Duration,
+    buffer: usize,
+    retry_backoff: Duration,
+    pending: VecDeque<Record>,
+    /// When the oldest pending message was queued
+    oldest_at: Option<Instant>,
+    /// Set while the broker is failing
+    retry_at: Option<Instant>,
+    backoff: Duration,
+    /// Messages dropped during the current outage
+    dropped: u64,
+}
+
+impl Worker {
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let command = match self.next_attempt() {
+                None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(at) => commands.recv_timeout(at.saturating_duration_since(Instant::now())),
+            };
+            match command {
+                Ok(Command::Publish(record)) => self.push(record),
+                Ok(Command::Flush(reply)) => {
+                    self.flush();
+                    let _ = reply.send(self.pending.len());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    if !self.pending.is_empty() {
+                        warn!(event = "sink_abandoned", pending = self.pending.len(),
+                            "Publishing stopped with {} messages still queued, dropping them", self.pending.len());
+                    }
+                    return;
+                }
+            }
+            if self.next_attempt().is_some_and(|at| at <= Instant::now()) {
+                self.publish_batch();
+            }
+        }
+    }
+
+    // When the next batch is due: now if one is full, after the linger time otherwise,
+    // and not before the backoff is over while the broker is failing
+    fn next_attempt(&self) -> Option<Instant> {
+        let oldest_at = self.oldest_at.filter(|_| !self.pending.is_empty())?;
+        let due = match self.pending.len() >= self.batch_size {
+            true => Instant::now(),
+            false => oldest_at + self.linger,
+        };
+        Some(self.retry_at.map_or(due, |retry_at| retry_at.max(due)))
+    }
+
+    fn push(&mut self, record: Record) {
+        if self.pending.len() >= self.buffer {
+            self.pending.pop_front();
+            if self.dropped == 0 {
+                warn!(event = "sink_dropping", buffer = self.buffer,
+                    "Publishing buffer of {} messages is full, dropping the oldest", self.buffer);
+            }
+            self.dropped += 1;
+        }
+        if self.pending.is_empty() {
+            self.oldest_at = Some(Instant::now());
+        }
+        self.pending.push_back(record);
+    }
+
+    fn publish_batch(&mut self) -> bool {
+        let count = self.pending.len().min(self.batch_size);
+        let batch = &self.pending.make_contiguous()[..count];
+        match self.publisher.publish(batch) {
+            Ok(()) => {
+                self.pending.drain(..count);
+                if self.retry_at.take().is_some() {
+                    info!(event = "sink_recovered", dropped = self.dropped,
+                        "Publishing resumed, {} messages were dropped while the broker was unreachable", self.dropped);
+                }
+                self.backoff = self.retry_backoff;
+                self.dropped = 0;
+                true
+            }
+            Err(e) => {
+                warn!(event = "sink_publish_failed", pending = self.pending.len(), retry_in_ms = self.backoff.as_millis() as u64,
+                    "Failed to publish {} messages, retrying in {:?}: {}", count, self.backoff, e);
+                self.retry_at = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(MAX_RETRY_BACKOFF);
+                false
+            }
+        }
+    }
+
+    // Publish everything held, stopping at the first failure
+    fn flush(&mut self) {
+        while !self.pending.is_empty() && self.publish_batch() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // Stand-in broker recording each batch, failing while `down` is set
+    #[derive(Clone, Default)]
+    struct FakeBroker {
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Publisher for FakeBroker {
+        fn publish(&mut self, batch: &[Record]) -> Result<(), String> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err("connection refused".to_string());
+            }
+            self.batches.lock().unwrap().push(batch.iter().map(|record| record.key.clone()).collect());
+            Ok(())
+        }
+    }
+
+    fn config(batch_size: usize, buffer: usize) -> SinkConfig {
+        SinkConfig {
+            backend: Backend::Kafka,
+            url: "localhost:9092".to_string(),
+            topic: "pt.{type}".to_string(),
+            batch_size,
+            linger: Duration::from_millis(20),
+            buffer,
+            retry_backoff: Duration::from_millis(20),
+        }
+    }
+
+    fn trade(symbol: &str) -> String {
+        format!(r#"{{"trade":{{"symbol":"{}","price":"1"}}}}"#, symbol)
+    }
+
+    #[test]
+    fn test_records_are_keyed_by_instrument_or_account() {
+        let record = Record::from_message(&trade("BTC-USD"), "pt.{type}").unwrap();
+        assert_eq!((record.topic.as_str(), record.key.as_str(), record.kind.as_str()), ("pt.trade", "BTC-USD", "trade"));
+        let record = Record::from_message(r#"{"multi_leg_mbp_snapshot":{"tradeable_entity_id":42}}"#, "pt").unwrap();
+        assert_eq!((record.topic.as_str(), record.key.as_str()), ("pt", "42"));
+        let record = Record::from_message(r#"{"position_summary":{"account_id":"acc-1","positions":[]}}"#, "pt").unwrap();
+        assert_eq!(record.key, "acc-1");
+        assert_eq!(Record::from_message(r#"{"heartbeat":{}}"#, "pt").unwrap().key, "heartbeat");
+        assert!(Record::from_message("[1]", "pt").is_none());
+    }
+
+    #[test]
+    fn test_batches_are_held_and_retried_while_broker_is_down() {
+        let broker = FakeBroker::default();
+        let sink = Sink::with_publisher(Box::new(broker.clone()), &config(2, 100)).unwrap();
+
+        for symbol in ["A", "B", "C"] {
+            sink.publish(&trade(symbol));
+        }
+        sink.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!(*broker.batches.lock().unwrap(), vec![vec!["A", "B"], vec!["C"]]);
+
+        broker.down.store(true, Ordering::SeqCst);
+        sink.publish(&trade("D"));
+        sink.publish(&trade("E"));
+        thread::sleep(Duration::from_millis(100));
+        assert!(sink.flush(Duration::from_secs(5)).unwrap_err().to_string().contains("2 messages"));
+
+        broker.down.store(false, Ordering::SeqCst);
+        let started = Instant::now();
+        while broker.batches.lock().unwrap().len() < 3 {
+            assert!(started.elapsed() < Duration::from_secs(5), "batch was not retried");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(broker.batches.lock().unwrap()[2], vec!["D", "E"]);
+    }
+
+    #[test]
+    fn test_oldest_messages_are_dropped_when_buffer_is_full() {
+        let broker = FakeBroker::default();
+        broker.down.store(true, Ordering::SeqCst);
+        let sink = Sink::with_publisher(Box::new(broker.clone()), &config(2, 3)).unwrap();
+        for symbol in ["A", "B", "C", "D", "E"] {
+            sink.publish(&trade(symbol));
+        }
+        thread::sleep(Duration::from_millis(100));
+        broker.down.store(false, Ordering::SeqCst);
+        sink.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!(*broker.batches.lock().unwrap(), vec![vec!["C", "D"], vec!["E"]]);
+    }
+
+    #[test]
+    fn test_reconfigure_moves_to_the_new_settings() {
+        let (old, new) = (FakeBroker::default(), FakeBroker::default());
+        let sink = Sink::with_publisher(Box::new(old.clone()), &config(10, 100)).unwrap();
+        assert_eq!(sink.reconfigure(&config(10, 100)), Ok(false));
+
+        sink.publish(&trade("A"));
+        let mut changed = config(1, 100);
+        changed.topic = "desk.{type}".to_string();
+        sink.replace(Box::new(new.clone()), &changed).unwrap();
+        sink.publish(&trade("B"));
+        sink.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!(*new.batches.lock().unwrap(), vec![vec!["B"]]);
+        assert_eq!(sink.current.lock().unwrap().config.topic, "desk.{type}");
+
+        // The previous thread still publishes what it held
+        let started = Instant::now();
+        while old.batches.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5), "held message was not published");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*old.batches.lock().unwrap(), vec![vec!["A"]]);
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let vars = ["PT_SINK", "PT_SINK_URL", "PT_SINK_TOPIC", "PT_SINK_BATCH_SIZE", "PT_SINK_BATCH_MS", "PT_SINK_BUFFER", "PT_SINK_RETRY_MS"];
+        for name in vars {
+            env::remove_var(name);
+        }
+        assert_eq!(SinkConfig::from_settings(&Settings::from_env()), Ok(None));
+
+        env::set_var("PT_SINK", "Redis");
+        assert!(SinkConfig::from_settings(&Settings::from_env()).unwrap_err().contains("PT_SINK_URL"));
+        env::set_var("PT_SINK_URL", "redis://127.0.0.1:6379");
+        env::set_var("PT_SINK_BATCH_SIZE", "50");
+        let config = SinkConfig::from_settings(&Settings::from_env()).unwrap().unwrap();
+        assert_eq!(config.backend, Backend::Redis);
+        assert_eq!(config.topic, "powertrade.{type}");
+        assert_eq!(config.batch_size, 50);
+        assert_eq!(config.linger, Duration::from_millis(100));
+
+        env::set_var("PT_SINK_BUFFER", "10");
+        assert!(SinkConfig::from_settings(&Settings::from_env()).unwrap_err().contains("no larger than PT_SINK_BUFFER"));
+        env::set_var("PT_SINK", "rabbitmq");
+        assert!(SinkConfig::from_settings(&Settings::from_env()).unwrap_err().contains("Unknown PT_SINK"));
+        for name in vars {
+            env::remove_var(name);
+        }
+    }
+}
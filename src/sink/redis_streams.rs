@@ -0,0 +1,127 @@
+// Redis Streams backend: each message is appended to its stream with XADD,
+// a batch going out as one pipeline.
+
+use super::{Publisher, Record};
+
+use redis::{Client, Connection};
+use std::time::Duration;
+
+// Connecting and each pipeline must complete within this time
+const REDIS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RedisPublisher {
+    client: Client,
+    /// Reopened on the next batch after a failure
+    connection: Option<Connection>,
+}
+
+impl RedisPublisher {
+    /// Publisher for a `redis://` URL, connecting on first use. `rediss://` needs a TLS
+    /// feature of the redis crate which this build does not enable.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let client = Client::open(url).map_err(|e| format!("Invalid Redis URL: {}", e))?;
+        Ok(RedisPublisher { client, connection: None })
+    }
+
+    fn connection(&mut self) -> redis::RedisResult<&mut Connection> {
+        if self.connection.is_none() {
+            let connection = self.client.get_connection_with_timeout(REDIS_TIMEOUT)?;
+            connection.set_read_timeout(Some(REDIS_TIMEOUT))?;
+            connection.set_write_timeout(Some(REDIS_TIMEOUT))?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+impl Publisher for RedisPublisher {
+    fn publish(&mut self, batch: &[Record]) -> Result<(), String> {
+        let mut pipeline = redis::pipe();
+        for record in batch {
+            pipeline.cmd("XADD").arg(&record.topic).arg("*")
+                .arg("type").arg(&record.kind)
+                .arg("key").arg(&record.key)
+                .arg("payload").arg(&record.payload)
+                .ignore();
+        }
+        let result = self.connection().and_then(|connection| pipeline.query::<()>(connection));
+        result.map_err(|e| {
+            self.connection = None;
+            e.to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+
+    // Read one RESP array of bulk strings, as sent by clients
+    fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+        (0..count).map(|_| {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut value = vec![0; len + 2];
+            reader.read_exact(&mut value).ok()?;
+            Some(String::from_utf8_lossy(&value[..len]).into_owned())
+        }).collect()
+    }
+
+    // Stand-in Redis answering every command, passing XADDs on
+    fn serve(listener: TcpListener, commands: Sender<Vec<String>>) {
+        for stream in listener.incoming().flatten() {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            while let Some(command) = read_command(&mut reader) {
+                let reply = match command[0].to_uppercase().as_str() {
+                    "XADD" => "$3\r\n1-0\r\n",
+                    _ => "+OK\r\n",
+                };
+                writer.write_all(reply.as_bytes()).unwrap();
+                if command[0] == "XADD" {
+                    commands.send(command).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_appends_batch_to_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let (commands, received) = channel();
+        thread::spawn(move || serve(listener, commands));
+
+        let mut publisher = RedisPublisher::new(&url).unwrap();
+        let record = |key: &str| Record {
+            topic: "pt.trade".to_string(), key: key.to_string(), kind: "trade".to_string(), payload: "{}".to_string(),
+        };
+        publisher.publish(&[record("BTC-USD"), record("ETH-USD")]).unwrap();
+
+        assert_eq!(received.recv().unwrap(), ["XADD", "pt.trade", "*", "type", "trade", "key", "BTC-USD", "payload", "{}"]);
+        assert_eq!(received.recv().unwrap()[6], "ETH-USD");
+    }
+
+    #[test]
+    fn test_reconnects_after_broker_outage() {
+        // Nothing listens on the port until the broker comes back
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut publisher = RedisPublisher::new(&format!("redis://127.0.0.1:{}", port)).unwrap();
+        let record = Record { topic: "pt".to_string(), key: "k".to_string(), kind: "trade".to_string(), payload: "{}".to_string() };
+        assert!(publisher.publish(std::slice::from_ref(&record)).is_err());
+
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let (commands, received) = channel();
+        thread::spawn(move || serve(listener, commands));
+        publisher.publish(&[record]).unwrap();
+        assert_eq!(received.recv().unwrap()[1], "pt");
+    }
+}
@@ -0,0 +1,407 @@
+// In-memory view of the running client: connection, counters and the latest
+// market data, shared with the local control interfaces.
+
+use crate::connection_state::{ConnectionState, StateChange};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Message types carrying a full price book for one tradeable
+const BOOK_SNAPSHOTS: [&str; 2] = ["mbp_snapshot", "multi_leg_mbp_snapshot"];
+
+// Message type carrying the account's positions and balances
+const POSITION_SUMMARY: &str = "position_summary";
+
+// Message type reporting a trade
+const TRADE: &str = "trade";
+
+// How many of the latest trades and events are kept
+const MAX_TRADES: usize = 100;
+const MAX_EVENTS: usize = 200;
+
+/// Fields naming a position's instrument and a balance's currency, in order of preference
+pub const POSITION_ID_FIELDS: [&str; 2] = ["symbol", "tradeable_entity_id"];
+pub const BALANCE_ID_FIELDS: [&str; 3] = ["currency", "symbol", "asset"];
+
+/// Fields of a position holding its size, and of a balance holding its amount, in order of preference
+pub const POSITION_SIZE_FIELDS: [&str; 3] = ["size", "quantity", "net_quantity"];
+pub const BALANCE_AMOUNT_FIELDS: [&str; 3] = ["cash_balance", "balance", "available_balance"];
+
+/// One price level of a book
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Level {
+    pub price: String,
+    pub quantity: String,
+}
+
+/// Latest price book snapshot received for a symbol
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct OrderBook {
+    pub symbol: String,
+    pub buy: Vec<Level>,
+    pub sell: Vec<Level>,
+    /// Server timestamp of the snapshot, as sent
+    pub server_timestamp: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl OrderBook {
+    // Parse the body of a price book snapshot, `None` if it names no symbol
+    fn from_snapshot(body: &Value) -> Option<Self> {
+        let symbol = body.get("symbol").or_else(|| body.get("tradeable_entity_id")).and_then(scalar)?;
+        let levels = |side: &str| {
+            body.get(side).and_then(Value::as_array).map_or(Vec::new(), |levels| {
+                levels.iter()
+                    .filter_map(|level| Some(Level {
+                        price: level.get("price").and_then(scalar)?,
+                        quantity: level.get("quantity").and_then(scalar)?,
+                    }))
+                    .collect()
+            })
+        };
+        Some(OrderBook {
+            symbol,
+            buy: levels("buy"),
+            sell: levels("sell"),
+            server_timestamp: body.get("server_utc_timestamp").and_then(scalar),
+            received_at: Utc::now(),
+        })
+    }
+
+    /// Halfway between the best bid and offer, `None` unless both sides have a price
+    pub fn mid(&self) -> Option<f64> {
+        let price = |levels: &[Level]| levels.first()?.price.parse::<f64>().ok();
+        Some((price(&self.buy)? + price(&self.sell)?) / 2.0)
+    }
+
+    /// Symbol with best bid and offer, for listings
+    pub fn top(&self) -> Value {
+        json!({
+            "symbol": self.symbol,
+            "best_buy": self.buy.first(),
+            "best_sell": self.sell.first(),
+            "received_at": self.received_at,
+        })
+    }
+}
+
+// Prices and quantities are sent as strings, accept numbers as well
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// The first of `fields` present in `entry`, as text
+pub fn field(entry: &Value, fields: &[&str]) -> Option<String> {
+    fields.iter().find_map(|name| entry.get(name).and_then(scalar))
+}
+
+/// Identifier and amount of each entry, skipping entries lacking either
+pub fn amounts(entries: &[Value], id_fields: &[&str], amount_fields: &[&str]) -> HashMap<String, f64> {
+    entries.iter()
+        .filter_map(|entry| Some((field(entry, id_fields)?, field(entry, amount_fields)?.parse::<f64>().ok()?)))
+        .collect()
+}
+
+/// One trade as reported by the server
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Trade {
+    pub symbol: String,
+    pub price: String,
+    pub quantity: String,
+    pub side: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl Trade {
+    // Parse the body of a trade message, `None` if it lacks symbol, price or quantity
+    fn from_message(body: &Value) -> Option<Self> {
+        Some(Trade {
+            symbol: body.get("symbol").or_else(|| body.get("tradeable_entity_id")).and_then(scalar)?,
+            price: body.get("price").and_then(scalar)?,
+            quantity: body.get("quantity").or_else(|| body.get("size")).and_then(scalar)?,
+            side: body.get("side").and_then(scalar),
+            received_at: Utc::now(),
+        })
+    }
+}
+
+/// Something worth showing to whoever watches the client, e.g. a connection change
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// Positions or balances from the latest position summary, as sent by the server
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccountData {
+    pub items: Value,
+    pub server_timestamp: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// State of the client as seen from outside
+#[derive(Debug)]
+pub struct ClientState {
+    pub started_at: DateTime<Utc>,
+    pub connection: ConnectionState,
+    pub connection_since: DateTime<Utc>,
+    pub conn_id: u64,
+    pub server_url: String,
+    pub reconnects: u32,
+    pub messages_received: u64,
+    pub last_message_at: Option<DateTime<Utc>>,
+    /// Whether received messages are currently left off the console
+    pub output_paused: bool,
+    pub books: BTreeMap<String, OrderBook>,
+    pub positions: Option<AccountData>,
+    pub balances: Option<AccountData>,
+    /// Latest trades, oldest first
+    pub trades: VecDeque<Trade>,
+    /// Latest events, oldest first
+    pub events: VecDeque<Event>,
+    /// When the access token of the current connection expires
+    pub token_expires_at: Option<DateTime<Utc>>,
+}
+
+impl ClientState {
+    /// Size of each position in the latest position summary by instrument, `None` before the first one
+    pub fn position_sizes(&self) -> Option<HashMap<String, f64>> {
+        let positions = self.positions.as_ref()?.items.as_array()?;
+        Some(amounts(positions, &POSITION_ID_FIELDS, &POSITION_SIZE_FIELDS))
+    }
+
+    /// Mid of the latest book of `symbol`, see `OrderBook::mid`
+    pub fn mid(&self, symbol: &str) -> Option<f64> {
+        self.books.get(symbol)?.mid()
+    }
+}
+
+/// Handle to the client state, cheap to clone and share between threads
+#[derive(Clone)]
+pub struct SharedState {
+    inner: Arc<Mutex<ClientState>>,
+}
+
+impl SharedState {
+    pub fn new() -> Self {
+        let now = Utc::now();
+        SharedState {
+            inner: Arc::new(Mutex::new(ClientState {
+                started_at: now,
+                connection: ConnectionState::Closed,
+                connection_since: now,
+                conn_id: 0,
+                server_url: String::new(),
+                reconnects: 0,
+                messages_received: 0,
+                last_message_at: None,
+                output_paused: false,
+                books: BTreeMap::new(),
+                positions: None,
+                balances: None,
+                trades: VecDeque::new(),
+                events: VecDeque::new(),
+                token_expires_at: None,
+            })),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, ClientState> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Follow the connection lifecycle, see `ConnectionLifecycle::on_change`
+    pub fn connection_changed(&self, change: &StateChange) {
+        let mut state = self.lock();
+        if change.to == ConnectionState::Reconnecting {
+            state.reconnects += 1;
+        }
+        state.connection = change.to;
+        state.connection_since = change.at;
+        state.conn_id = change.conn_id;
+        let message = match change.reason.as_str() {
+            "" => format!("Connection {} -> {}", change.from, change.to),
+            reason => format!("Connection {} -> {} ({})", change.from, change.to, reason),
+        };
+        push_event(&mut state, change.at, message);
+    }
+
+    /// Keep an event for display, dropping the oldest beyond the limit
+    pub fn record_event(&self, message: impl Into<String>) {
+        push_event(&mut self.lock(), Utc::now(), message.into());
+    }
+
+    pub fn set_server_url(&self, url: &str) {
+        self.lock().server_url = url.to_string();
+    }
+
+    /// Count a received text message and keep any market data it carries.
+    /// Messages are objects keyed by their type, e.g. `{"mbp_snapshot": {...}}`.
+    pub fn record_message(&self, text: &str) {
+        let mut state = self.lock();
+        state.messages_received += 1;
+        state.last_message_at = Some(Utc::now());
+
+        let Ok(Value::Object(message)) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        for (kind, body) in &message {
+            if BOOK_SNAPSHOTS.contains(&kind.as_str()) {
+                if let Some(book) = OrderBook::from_snapshot(body) {
+                    state.books.insert(book.symbol.clone(), book);
+                }
+            } else if kind == POSITION_SUMMARY {
+                let account_data = |key: &str| body.get(key).map(|items| AccountData {
+                    items: items.clone(),
+                    server_timestamp: body.get("server_utc_timestamp").and_then(scalar),
+                    received_at: Utc::now(),
+                });
+                if let Some(positions) = account_data("positions") {
+                    state.positions = Some(positions);
+                }
+                if let Some(balances) = account_data("balances") {
+                    state.balances = Some(balances);
+                }
+            } else if kind == TRADE {
+                if let Some(trade) = Trade::from_message(body) {
+                    if state.trades.len() == MAX_TRADES {
+                        state.trades.pop_front();
+                    }
+                    state.trades.push_back(trade);
+                }
+            }
+        }
+    }
+
+    pub fn set_token_expiry(&self, expires_at: DateTime<Utc>) {
+        self.lock().token_expires_at = Some(expires_at);
+    }
+
+    pub fn set_output_paused(&self, paused: bool) {
+        self.lock().output_paused = paused;
+    }
+
+    pub fn output_paused(&self) -> bool {
+        self.lock().output_paused
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<OrderBook> {
+        self.lock().books.get(symbol).cloned()
+    }
+
+    /// Connection and counters as JSON
+    pub fn status(&self) -> Value {
+        let state = self.lock();
+        json!({
+            "state": state.connection.as_str(),
+            "state_since": state.connection_since,
+            "conn_id": state.conn_id,
+            "server_url": state.server_url,
+            "started_at": state.started_at,
+            "uptime_secs": (Utc::now() - state.started_at).num_seconds(),
+            "reconnects": state.reconnects,
+            "messages_received": state.messages_received,
+            "last_message_at": state.last_message_at,
+            "output_paused": state.output_paused,
+            "books": state.books.len(),
+            "token_expires_at": state.token_expires_at,
+            "token_expires_in_secs": state.token_expires_at.map(|at| (at - Utc::now()).num_seconds()),
+        })
+    }
+}
+
+fn push_event(state: &mut ClientState, at: DateTime<Utc>, message: String) {
+    if state.events.len() == MAX_EVENTS {
+        state.events.pop_front();
+    }
+    state.events.push_back(Event { at, message });
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_book_snapshots_are_kept_per_symbol() {
+        let state = SharedState::new();
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"BTC-USD-PERPETUAL","server_utc_timestamp":"1700000000000",
+            "buy":[{"price":"42000.5","quantity":"1.2"}],"sell":[{"price":42001,"quantity":"0.4"}]}}"#);
+        state.record_message(r#"{"multi_leg_mbp_snapshot":{"tradeable_entity_id":"555","buy":[],"sell":[]}}"#);
+        state.record_message("not json");
+        state.record_message(r#"{"position_summary":{"server_utc_timestamp":"1700000000001",
+            "positions":[{"symbol":"BTC-USD-PERPETUAL","size":"0.5"}],"balances":[{"currency":"USD","cash_balance":"1000"}]}}"#);
+
+        let book = state.book("BTC-USD-PERPETUAL").unwrap();
+        assert_eq!(book.buy, vec![Level { price: "42000.5".to_string(), quantity: "1.2".to_string() }]);
+        assert_eq!(book.sell[0].price, "42001");
+        assert_eq!(book.server_timestamp.as_deref(), Some("1700000000000"));
+        assert!(state.book("555").is_some());
+
+        let positions = state.lock().positions.clone().unwrap();
+        assert_eq!(positions.items[0]["size"], "0.5");
+        assert_eq!(positions.server_timestamp.as_deref(), Some("1700000000001"));
+        assert_eq!(state.lock().balances.as_ref().unwrap().items[0]["currency"], "USD");
+
+        let status = state.status();
+        assert_eq!(status["messages_received"], 4);
+        assert_eq!(status["books"], 2);
+    }
+
+    #[test]
+    fn test_position_sizes_and_mid() {
+        let state = SharedState::new();
+        assert_eq!(state.lock().position_sizes(), None);
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"BTC-USD","buy":[{"price":"99","quantity":"1"}],"sell":[{"price":"101","quantity":"1"}]}}"#);
+        state.record_message(r#"{"mbp_snapshot":{"symbol":"ETH-USD","buy":[{"price":"9","quantity":"1"}],"sell":[]}}"#);
+        state.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"-2"},
+            {"tradeable_entity_id":77,"net_quantity":3},{"symbol":"SOL-USD","size":"n/a"}]}}"#);
+
+        let guard = state.lock();
+        let sizes = guard.position_sizes().unwrap();
+        assert_eq!((sizes.get("BTC-USD"), sizes.get("77"), sizes.len()), (Some(&-2.0), Some(&3.0), 2));
+        assert_eq!(guard.mid("BTC-USD"), Some(100.0));
+        assert_eq!(guard.mid("ETH-USD"), None);
+    }
+
+    #[test]
+    fn test_latest_trades_are_kept() {
+        let state = SharedState::new();
+        for price in 0..MAX_TRADES + 2 {
+            state.record_message(&format!(r#"{{"trade":{{"symbol":"BTC-USD","price":"{}","quantity":"1","side":"buy"}}}}"#, price));
+        }
+        state.record_message(r#"{"trade":{"symbol":"BTC-USD"}}"#);
+
+        let trades = &state.lock().trades;
+        assert_eq!(trades.len(), MAX_TRADES);
+        assert_eq!(trades.front().unwrap().price, "2");
+        assert_eq!(trades.back().unwrap().side.as_deref(), Some("buy"));
+    }
+
+    #[test]
+    fn test_connection_changes_are_tracked() {
+        let state = SharedState::new();
+        let change = |to| StateChange { conn_id: 7, from: ConnectionState::Open, to, at: Utc::now(), reason: String::new() };
+        state.connection_changed(&change(ConnectionState::Reconnecting));
+        state.connection_changed(&change(ConnectionState::Open));
+
+        let status = state.status();
+        assert_eq!(status["state"], "open");
+        assert_eq!(status["conn_id"], 7);
+        assert_eq!(status["reconnects"], 1);
+        assert_eq!(state.lock().events.len(), 2);
+    }
+}
@@ -0,0 +1,455 @@
+// TLS for wss:// connections: extra trusted CA certificates, an optional client
+// certificate for mutual TLS and pinning of the server certificate.
+// The backend is chosen at build time with the `native-tls` (default) or `rustls` feature.
+
+use crate::config::Settings;
+use crate::error::AppError;
+
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use tracing::info;
+use tungstenite::stream::MaybeTlsStream;
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub type Fingerprint = [u8; 32];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    /// PEM file of CA certificates trusted in addition to the system roots
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate (chain) presented for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    /// PEM PKCS#8 private key of the client certificate
+    pub client_key: Option<PathBuf>,
+    /// Accepted server certificate fingerprints, pinning is off when empty
+    pub pins: Vec<Fingerprint>,
+}
+
+impl TlsConfig {
+    /// Read the PT_TLS_* settings
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        Self::new(
+            settings.var("PT_TLS_CA_FILE").ok(),
+            settings.var("PT_TLS_CLIENT_CERT").ok(),
+            settings.var("PT_TLS_CLIENT_KEY").ok(),
+            settings.var("PT_TLS_PINS").ok().as_deref(),
+        )
+    }
+
+    /// Build the TLS settings from the raw configuration values.
+    /// `pins` is a comma separated list of hex SHA-256 certificate fingerprints,
+    /// with or without `:` separators as printed by `openssl x509 -fingerprint -sha256`.
+    pub fn new(
+        ca_file: Option<String>,
+        client_cert: Option<String>,
+        client_key: Option<String>,
+        pins: Option<&str>,
+    ) -> Result<Self, String> {
+        if client_cert.is_some() != client_key.is_some() {
+            return Err("PT_TLS_CLIENT_CERT and PT_TLS_CLIENT_KEY must be set together".to_string());
+        }
+
+        let pins = pins
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|pin| !pin.is_empty())
+            .map(parse_fingerprint)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(TlsConfig {
+            ca_file: ca_file.map(PathBuf::from),
+            client_cert: client_cert.map(PathBuf::from),
+            client_key: client_key.map(PathBuf::from),
+            pins,
+        })
+    }
+
+    /// Run the TLS handshake with `host` over `stream` and check the certificate pins.
+    /// Runs before the WebSocket handshake so no credentials reach a server failing the pin check.
+    pub fn connect(&self, host: &str, stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        // IPv6 hosts come bracketed from the URL
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = backend::connect(self, host, stream)?;
+
+        if !self.pins.is_empty() {
+            let certificate = peer_certificate(&stream).ok_or_else(|| AppError::CertificatePin {
+                host: host.to_string(),
+                fingerprint: "none".to_string(),
+            })?;
+            let fingerprint = fingerprint(&certificate);
+            if !self.pins.contains(&fingerprint) {
+                return Err(AppError::CertificatePin { host: host.to_string(), fingerprint: to_hex(&fingerprint) });
+            }
+            info!(event = "certificate_pinned", fingerprint = %to_hex(&fingerprint), "Server certificate matches pin");
+        }
+        Ok(stream)
+    }
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate
+pub fn fingerprint(der: &[u8]) -> Fingerprint {
+    Sha256::digest(der).into()
+}
+
+fn parse_fingerprint(pin: &str) -> Result<Fingerprint, String> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let invalid = || format!("Invalid certificate pin {}: expected a hex SHA-256 fingerprint", pin);
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = [0u8; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+fn to_hex(fingerprint: &Fingerprint) -> String {
+    fingerprint.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+// DER encoded leaf certificate the server presented
+fn peer_certificate(stream: &MaybeTlsStream<TcpStream>) -> Option<Vec<u8>> {
+    match stream {
+        #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+        MaybeTlsStream::NativeTls(tls) => tls.peer_certificate().ok().flatten().and_then(|cert| cert.to_der().ok()),
+        #[cfg(feature = "rustls")]
+        MaybeTlsStream::Rustls(tls) => tls.conn.peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec()),
+        _ => None,
+    }
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn read_file(path: &std::path::Path, what: &str) -> Result<Vec<u8>, AppError> {
+    std::fs::read(path)
+        .map_err(|e| AppError::Certificate(format!("Failed to read {} {}: {}", what, path.display(), e)))
+}
+
+#[cfg(feature = "rustls")]
+mod backend {
+    use super::TlsConfig;
+    use crate::error::AppError;
+
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use std::io;
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tracing::warn;
+    use tungstenite::stream::MaybeTlsStream;
+
+    pub fn connect(config: &TlsConfig, host: &str, mut stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| AppError::Config(format!("Invalid TLS server name {}: {}", host, e)))?;
+        let mut connection = ClientConnection::new(Arc::new(client_config(config)?), server_name)
+            .map_err(|e| AppError::Tls(Box::new(e)))?;
+
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream).map_err(handshake_error)?;
+        }
+        Ok(MaybeTlsStream::Rustls(StreamOwned::new(connection, stream)))
+    }
+
+    fn client_config(config: &TlsConfig) -> Result<ClientConfig, AppError> {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        for error in &native.errors {
+            warn!("Failed to load a system root certificate: {}", error);
+        }
+        roots.add_parsable_certificates(native.certs);
+
+        if let Some(path) = &config.ca_file {
+            for cert in certificates(path, "CA file")? {
+                roots.add(cert)
+                    .map_err(|e| AppError::Certificate(format!("Invalid CA certificate in {}: {}", path.display(), e)))?;
+            }
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| AppError::Tls(Box::new(e)))?
+            .with_root_certificates(roots);
+
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let key = PrivateKeyDer::from_pem_file(key).map_err(|e| {
+                    AppError::Certificate(format!("Failed to load client key {}: {}", key.display(), e))
+                })?;
+                builder.with_client_auth_cert(certificates(cert, "client certificate")?, key)
+                    .map_err(|e| AppError::Certificate(format!("Invalid client certificate or key: {}", e)))
+            }
+            _ => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    fn certificates(path: &Path, what: &str) -> Result<Vec<CertificateDer<'static>>, AppError> {
+        let pem = super::read_file(path, what)?;
+        let certs = CertificateDer::pem_slice_iter(&pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| AppError::Certificate(format!("Failed to parse {} {}: {}", what, path.display(), e)))?;
+        if certs.is_empty() {
+            return Err(AppError::Certificate(format!("No certificates found in {} {}", what, path.display())));
+        }
+        Ok(certs)
+    }
+
+    // rustls reports handshake failures as io errors wrapping a `rustls::Error`
+    fn handshake_error(error: io::Error) -> AppError {
+        if error.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) {
+            AppError::Tls(error.into_inner().expect("checked above"))
+        } else {
+            AppError::from(error)
+        }
+    }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod backend {
+    use super::TlsConfig;
+    use crate::error::AppError;
+
+    use native_tls::{Certificate, HandshakeError, Identity, TlsConnector};
+    use std::net::TcpStream;
+    use tungstenite::stream::MaybeTlsStream;
+
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    pub fn connect(config: &TlsConfig, host: &str, stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        let connector = connector(config)?;
+        match connector.connect(host, stream) {
+            Ok(tls) => Ok(MaybeTlsStream::NativeTls(tls)),
+            Err(HandshakeError::Failure(e)) => Err(AppError::Tls(Box::new(e))),
+            Err(HandshakeError::WouldBlock(_)) => Err(AppError::Timeout("TLS handshake timed out".to_string())),
+        }
+    }
+
+    fn connector(config: &TlsConfig) -> Result<TlsConnector, AppError> {
+        let mut builder = TlsConnector::builder();
+
+        if let Some(path) = &config.ca_file {
+            let pem = String::from_utf8(super::read_file(path, "CA file")?)
+                .map_err(|_| AppError::Certificate(format!("CA file {} is not PEM text", path.display())))?;
+            // native-tls takes one certificate at a time, so split the bundle
+            let mut found = false;
+            for block in pem.split(BEGIN).skip(1) {
+                let body = block.split(END).next().unwrap_or_default();
+                let cert = Certificate::from_pem(format!("{}{}{}", BEGIN, body, END).as_bytes())
+                    .map_err(|e| AppError::Certificate(format!("Invalid CA certificate in {}: {}", path.display(), e)))?;
+                builder.add_root_certificate(cert);
+                found = true;
+            }
+            if !found {
+                return Err(AppError::Certificate(format!("No certificates found in CA file {}", path.display())));
+            }
+        }
+
+        if let (Some(cert), Some(key)) = (&config.client_cert, &config.client_key) {
+            let identity = Identity::from_pkcs8(
+                &super::read_file(cert, "client certificate")?,
+                &super::read_file(key, "client key")?,
+            )
+            .map_err(|e| AppError::Certificate(format!("Invalid client certificate or key: {}", e)))?;
+            builder.identity(identity);
+        }
+
+        builder.build().map_err(|e| AppError::Tls(Box::new(e)))
+    }
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+mod backend {
+    use super::TlsConfig;
+    use crate::error::AppError;
+
+    use std::net::TcpStream;
+    use tungstenite::stream::MaybeTlsStream;
+
+    pub fn connect(_config: &TlsConfig, _host: &str, _stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, AppError> {
+        Err(AppError::Config("wss:// needs TLS support, build with the native-tls or rustls feature".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER_PIN: &str = "10:72:B6:0A:5B:C8:94:F6:70:FB:76:95:3C:CA:C2:C5:7F:5A:AD:29:7B:73:4A:6A:C1:53:63:F4:B3:46:7F:3B";
+
+    #[test]
+    fn test_parse_pins() {
+        let config = TlsConfig::new(None, None, None, Some(&format!("{}, {}", SERVER_PIN, "ab".repeat(32)))).unwrap();
+        assert_eq!(config.pins.len(), 2);
+        assert_eq!(config.pins[0][..2], [0x10, 0x72]);
+        assert_eq!(config.pins[1], [0xab; 32]);
+        assert_eq!(to_hex(&config.pins[1]), "ab".repeat(32));
+
+        assert!(TlsConfig::new(None, None, None, Some("10:72:B6")).unwrap_err().contains("Invalid certificate pin"));
+        assert!(TlsConfig::new(None, None, None, Some(&"zz".repeat(32))).is_err());
+        assert!(TlsConfig::new(None, None, None, Some(" ")).unwrap().pins.is_empty());
+    }
+
+    #[test]
+    fn test_client_cert_requires_key() {
+        let result = TlsConfig::new(None, Some("client.pem".to_string()), None, None);
+        assert!(result.unwrap_err().contains("must be set together"));
+    }
+
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    mod handshake {
+        use super::*;
+        use crate::connection_state::{ConnectionLifecycle, ConnectionState};
+        use crate::exit::ExitStatus;
+        use crate::test_support::test_config;
+        use crate::websocket::WebSocketClient;
+        use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::path::Path;
+        use std::thread::{self, JoinHandle};
+        use tungstenite::WebSocket;
+
+        /// Path of a certificate or key in `tests/fixtures/tls`
+        pub fn tls_fixture(name: &str) -> PathBuf {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+        }
+
+        /// Start a `wss://` server on a free local port presenting the fixture certificate
+        /// for 127.0.0.1, optionally requiring a client certificate signed by the fixture CA.
+        /// The server thread returns the TLS or WebSocket handshake error, if any.
+        pub fn spawn_wss_server<F, T>(require_client_cert: bool, handler: F) -> (String, JoinHandle<Result<T, String>>)
+        where
+            F: FnOnce(WebSocket<SslStream<TcpStream>>) -> T + Send + 'static,
+            T: Send + 'static,
+        {
+            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+            acceptor.set_private_key_file(tls_fixture("server.key"), SslFiletype::PEM).unwrap();
+            acceptor.set_certificate_chain_file(tls_fixture("server.pem")).unwrap();
+            if require_client_cert {
+                acceptor.set_ca_file(tls_fixture("ca.pem")).unwrap();
+                acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+            }
+            let acceptor = acceptor.build();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("wss://{}/v1/position_summary", listener.local_addr().unwrap());
+            let server = thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let stream = acceptor.accept(stream).map_err(|e| e.to_string())?;
+                let socket = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+                Ok(handler(socket))
+            });
+            (url, server)
+        }
+
+        fn fixture(name: &str) -> Option<String> {
+            Some(tls_fixture(name).to_string_lossy().into_owned())
+        }
+
+        fn connect(server_url: &str, tls: TlsConfig) -> Result<WebSocketClient, AppError> {
+            let mut config = test_config(server_url);
+            config.tls = tls;
+            WebSocketClient::new(config, ConnectionLifecycle::new())
+        }
+
+        #[test]
+        fn test_connects_with_custom_ca_and_pin() {
+            let (url, server) = spawn_wss_server(false, |mut socket| while socket.read().is_ok() {});
+
+            let tls = TlsConfig::new(fixture("ca.pem"), None, None, Some(SERVER_PIN)).unwrap();
+            let mut client = connect(&url, tls).unwrap();
+            assert_eq!(client.state(), ConnectionState::Open);
+            client.close("test finished");
+            drop(client);
+            server.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn test_untrusted_certificate_rejected() {
+            let (url, server) = spawn_wss_server(false, |_| ());
+
+            let err = connect(&url, TlsConfig::default()).err().unwrap();
+            assert!(matches!(err, AppError::Tls(_)), "unexpected error: {}", err);
+            assert!(!err.is_retryable());
+            assert!(server.join().unwrap().is_err());
+        }
+
+        #[test]
+        fn test_pin_mismatch_rejected_before_websocket_handshake() {
+            let (url, server) = spawn_wss_server(false, |_| ());
+
+            let tls = TlsConfig::new(fixture("ca.pem"), None, None, Some(&"00".repeat(32))).unwrap();
+            let err = connect(&url, tls).err().unwrap();
+            match &err {
+                AppError::CertificatePin { host, fingerprint } => {
+                    assert_eq!(host, "127.0.0.1");
+                    assert_eq!(fingerprint, &SERVER_PIN.replace(':', "").to_lowercase());
+                }
+                other => panic!("unexpected error: {}", other),
+            }
+            assert!(!err.is_retryable());
+            // The server never saw an upgrade request carrying the access token
+            assert!(server.join().unwrap().is_err());
+        }
+
+        #[test]
+        fn test_mutual_tls() {
+            let (url, server) = spawn_wss_server(true, |mut socket| while socket.read().is_ok() {});
+
+            let tls = TlsConfig::new(fixture("ca.pem"), fixture("client.pem"), fixture("client.key"), None).unwrap();
+            let mut client = connect(&url, tls).unwrap();
+            assert_eq!(client.state(), ConnectionState::Open);
+            client.close("test finished");
+            drop(client);
+            server.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn test_mutual_tls_without_client_certificate() {
+            let (url, server) = spawn_wss_server(true, |_| ());
+
+            let tls = TlsConfig::new(fixture("ca.pem"), None, None, None).unwrap();
+            assert!(connect(&url, tls).is_err());
+            assert!(server.join().unwrap().is_err());
+        }
+
+        #[test]
+        fn test_connection_closed_during_handshake_is_retryable() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                // Take the ClientHello, then hang up without answering
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0; 4096]);
+            });
+
+            let err = TlsConfig::default().connect("127.0.0.1", TcpStream::connect(addr).unwrap()).err().unwrap();
+            assert!(err.is_retryable(), "unexpected error: {}", err);
+            assert_eq!(ExitStatus::from_error(&err), ExitStatus::NetworkExhausted);
+            server.join().unwrap();
+        }
+
+        #[test]
+        fn test_missing_ca_file() {
+            // The CA file is read before the handshake starts, so nothing needs to answer
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+            let tls = TlsConfig::new(Some("/nonexistent/ca.pem".to_string()), None, None, None).unwrap();
+            let err = tls.connect("127.0.0.1", stream).err().unwrap();
+            assert!(matches!(err, AppError::Certificate(_)));
+            assert_eq!(err.kind(), "certificate");
+        }
+    }
+}
@@ -0,0 +1,312 @@
+// Socket level settings for the connection to the server (or proxy): timeouts,
+// address family preference, TCP options and tungstenite's WebSocket limits.
+
+use crate::config::Settings;
+use crate::error::AppError;
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::debug;
+use tungstenite::protocol::WebSocketConfig;
+
+/// Which resolved addresses to try, and in which order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Addresses in the order the resolver returned them
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(AddressFamily::Any),
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            "prefer-ipv4" => Ok(AddressFamily::PreferIpv4),
+            "prefer-ipv6" => Ok(AddressFamily::PreferIpv6),
+            other => Err(format!(
+                "Invalid address family {}: expected any, ipv4, ipv6, prefer-ipv4 or prefer-ipv6",
+                other
+            )),
+        }
+    }
+}
+
+impl AddressFamily {
+    /// Filter and order resolved addresses by this preference
+    pub fn arrange(self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            AddressFamily::Any => {}
+            AddressFamily::Ipv4 => addrs.retain(SocketAddr::is_ipv4),
+            AddressFamily::Ipv6 => addrs.retain(SocketAddr::is_ipv6),
+            // Stable sort keeps the resolver order within each family
+            AddressFamily::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            AddressFamily::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+        addrs
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransportConfig {
+    /// Limit on each TCP connect attempt
+    pub connect_timeout: Duration,
+    /// Limit on each read or write during the proxy, TLS and WebSocket handshakes
+    pub handshake_timeout: Duration,
+    /// How long closing waits for the server to answer the close frame
+    pub close_timeout: Duration,
+    pub address_family: AddressFamily,
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, `None` leaves keepalive off
+    pub keepalive: Option<Duration>,
+    /// Largest incoming message, `None` for no limit
+    pub max_message_size: Option<usize>,
+    /// Largest incoming frame, `None` for no limit
+    pub max_frame_size: Option<usize>,
+    pub write_buffer_size: usize,
+    pub max_write_buffer_size: usize,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        let websocket = WebSocketConfig::default();
+        TransportConfig {
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            close_timeout: Duration::from_secs(5),
+            address_family: AddressFamily::Any,
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            max_message_size: websocket.max_message_size,
+            max_frame_size: websocket.max_frame_size,
+            write_buffer_size: websocket.write_buffer_size,
+            max_write_buffer_size: websocket.max_write_buffer_size,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Read the transport settings, falling back to the defaults for unset variables
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let var = |name: &str| settings.var(name);
+        let defaults = TransportConfig::default();
+
+        let millis = |name: &str, default: Duration| -> Result<Duration, String> {
+            match var(name) {
+                Ok(value) => value.parse::<u64>()
+                    .map(Duration::from_millis)
+                    .map_err(|e| format!("Error parsing {}: {}", name, e)),
+                Err(_) => Ok(default),
+            }
+        };
+        // Sizes in bytes, where 0 removes the limit
+        let limit = |name: &str, default: Option<usize>| -> Result<Option<usize>, String> {
+            match var(name) {
+                Ok(value) => value.parse::<usize>()
+                    .map(|size| Some(size).filter(|size| *size > 0))
+                    .map_err(|e| format!("Error parsing {}: {}", name, e)),
+                Err(_) => Ok(default),
+            }
+        };
+
+        let keepalive = match var("PT_TCP_KEEPALIVE") {
+            Ok(value) => value.parse::<u64>()
+                .map(|secs| Some(Duration::from_secs(secs)).filter(|d| !d.is_zero()))
+                .map_err(|e| format!("Error parsing PT_TCP_KEEPALIVE: {}", e))?,
+            Err(_) => defaults.keepalive,
+        };
+
+        Ok(TransportConfig {
+            connect_timeout: millis("PT_CONNECT_TIMEOUT_MS", defaults.connect_timeout)?,
+            handshake_timeout: millis("PT_HANDSHAKE_TIMEOUT_MS", defaults.handshake_timeout)?,
+            close_timeout: millis("PT_CLOSE_TIMEOUT_MS", defaults.close_timeout)?,
+            address_family: var("PT_ADDRESS_FAMILY")
+                .map_or(Ok(defaults.address_family), |value| value.parse())?,
+            nodelay: var("PT_TCP_NODELAY")
+                .map_or(Ok(defaults.nodelay), |value| value.parse::<bool>())
+                .map_err(|e| format!("Error parsing PT_TCP_NODELAY: {}", e))?,
+            keepalive,
+            max_message_size: limit("PT_WS_MAX_MESSAGE_SIZE", defaults.max_message_size)?,
+            max_frame_size: limit("PT_WS_MAX_FRAME_SIZE", defaults.max_frame_size)?,
+            write_buffer_size: limit("PT_WS_WRITE_BUFFER_SIZE", Some(defaults.write_buffer_size))?
+                .unwrap_or(0),
+            max_write_buffer_size: limit("PT_WS_MAX_WRITE_BUFFER_SIZE", Some(defaults.max_write_buffer_size))?
+                .unwrap_or(usize::MAX),
+        })
+    }
+
+    /// Check settings which would otherwise only fail once connecting
+    pub fn validate(&self) -> Result<(), String> {
+        if self.connect_timeout.is_zero() {
+            return Err("Connect timeout cannot be zero".to_string());
+        }
+        if self.handshake_timeout.is_zero() {
+            return Err("Handshake timeout cannot be zero".to_string());
+        }
+        if self.max_write_buffer_size <= self.write_buffer_size {
+            return Err("Max write buffer size must be larger than the write buffer size".to_string());
+        }
+        Ok(())
+    }
+
+    /// Resolve `host` and connect to the first address accepting within the connect timeout
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, AppError> {
+        // IPv6 hosts come bracketed from URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let resolved: Vec<SocketAddr> = (host, port).to_socket_addrs()
+            .map_err(|source| AppError::Dns { host: host.to_string(), source })?
+            .collect();
+        let addrs = self.address_family.arrange(resolved);
+        if addrs.is_empty() {
+            return Err(AppError::Dns {
+                host: host.to_string(),
+                source: io::Error::new(io::ErrorKind::NotFound, format!("no {:?} address", self.address_family)),
+            });
+        }
+
+        let mut last_error = None;
+        for addr in &addrs {
+            debug!(%addr, "Connecting to {}", host);
+            match TcpStream::connect_timeout(addr, self.connect_timeout) {
+                Ok(stream) => {
+                    self.configure(&stream)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        let error = last_error.expect("at least one address was tried");
+        Err(match error.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => AppError::Timeout(format!(
+                "Connecting to {}:{} timed out after {} ms",
+                host, port, self.connect_timeout.as_millis()
+            )),
+            _ => AppError::from(error),
+        })
+    }
+
+    // Apply the TCP options and bound the handshakes which follow the connect
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        stream.set_read_timeout(Some(self.handshake_timeout))?;
+        stream.set_write_timeout(Some(self.handshake_timeout))
+    }
+
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_message_size(self.max_message_size)
+            .max_frame_size(self.max_frame_size)
+            .write_buffer_size(self.write_buffer_size)
+            .max_write_buffer_size(self.max_write_buffer_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_state::ConnectionLifecycle;
+    use crate::test_support::test_config;
+    use crate::websocket::WebSocketClient;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener};
+    use std::thread;
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 443),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)), 443),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 443),
+        ]
+    }
+
+    #[test]
+    fn test_parse_address_family() {
+        assert_eq!("IPv4".parse::<AddressFamily>().unwrap(), AddressFamily::Ipv4);
+        assert_eq!("prefer-ipv6".parse::<AddressFamily>().unwrap(), AddressFamily::PreferIpv6);
+        assert!("ipv5".parse::<AddressFamily>().unwrap_err().contains("Invalid address family"));
+    }
+
+    #[test]
+    fn test_address_family_arrangement() {
+        assert_eq!(AddressFamily::Any.arrange(addrs()), addrs());
+
+        let v4 = AddressFamily::Ipv4.arrange(addrs());
+        assert_eq!(v4.len(), 2);
+        assert!(v4.iter().all(SocketAddr::is_ipv4));
+
+        let preferred = AddressFamily::PreferIpv4.arrange(addrs());
+        assert_eq!(preferred[0], addrs()[1]);
+        assert_eq!(preferred[1], addrs()[3]);
+        assert_eq!(preferred[2], addrs()[0]);
+
+        assert!(AddressFamily::PreferIpv6.arrange(addrs())[0].is_ipv6());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(TransportConfig::default().validate().is_ok());
+
+        let config = TransportConfig { connect_timeout: Duration::ZERO, ..TransportConfig::default() };
+        assert!(config.validate().unwrap_err().contains("Connect timeout"));
+
+        let config = TransportConfig { max_write_buffer_size: 1024, write_buffer_size: 4096, ..TransportConfig::default() };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_connect_applies_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config = TransportConfig { nodelay: false, ..TransportConfig::default() };
+        let stream = config.connect("localhost", port).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        assert_eq!(stream.read_timeout().unwrap(), Some(config.handshake_timeout));
+    }
+
+    #[test]
+    fn test_connect_without_matching_family_is_dns_error() {
+        let config = TransportConfig { address_family: AddressFamily::Ipv6, ..TransportConfig::default() };
+        let err = config.connect("127.0.0.1", 443).unwrap_err();
+        assert!(matches!(err, AppError::Dns { .. }));
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // Server which accepts the connection but never answers the upgrade request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/v1/position_summary", listener.local_addr().unwrap());
+        let server = thread::spawn(move || listener.accept().unwrap());
+
+        let mut config = test_config(&url);
+        config.transport.handshake_timeout = Duration::from_millis(200);
+        let err = WebSocketClient::new(config, ConnectionLifecycle::new()).err().unwrap();
+        assert!(matches!(err, AppError::Timeout(_)), "unexpected error: {}", err);
+        assert!(err.to_string().contains("200 ms"));
+        assert!(err.is_retryable());
+        drop(server.join().unwrap());
+    }
+
+    #[test]
+    fn test_websocket_config() {
+        let config = TransportConfig { max_message_size: Some(1 << 20), max_frame_size: None, ..TransportConfig::default() };
+        let websocket = config.websocket_config();
+        assert_eq!(websocket.max_message_size, Some(1 << 20));
+        assert_eq!(websocket.max_frame_size, None);
+    }
+}
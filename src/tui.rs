@@ -0,0 +1,259 @@
+// Terminal UI: connection, account and market panes drawn from the client state
+// while the client runs, in place of the console output.
+
+use crate::connection_state::ConnectionState;
+use crate::console;
+use crate::shutdown::Shutdown;
+use crate::state::{field, ClientState, SharedState, BALANCE_AMOUNT_FIELDS, BALANCE_ID_FIELDS, POSITION_ID_FIELDS, POSITION_SIZE_FIELDS};
+
+use chrono::{DateTime, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListState, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::error;
+
+// How often the screen is redrawn when no key is pressed
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+// Price levels shown on each side of the book ladder
+const LADDER_DEPTH: usize = 10;
+
+/// Terminal taken over by the dashboard, given back when dropped
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    /// Draw `state` on the terminal until dropped, leaving console output out meanwhile.
+    /// Quitting from the keyboard requests `shutdown`.
+    pub fn start(state: SharedState, shutdown: Shutdown) -> io::Result<Self> {
+        let terminal = ratatui::try_init()?;
+        console::set_quiet(true);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::Builder::new().name("tui".to_string()).spawn(move || {
+            if let Err(e) = run(terminal, &state, &shutdown, &stopped) {
+                error!(event = "tui_failed", "Terminal UI failed: {}", e);
+            }
+        });
+        match thread {
+            Ok(thread) => Ok(Tui { stop, thread: Some(thread) }),
+            Err(e) => {
+                restore();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        restore();
+    }
+}
+
+fn restore() {
+    ratatui::restore();
+    console::set_quiet(false);
+}
+
+fn run(mut terminal: DefaultTerminal, state: &SharedState, shutdown: &Shutdown, stop: &AtomicBool) -> io::Result<()> {
+    let mut view = View::default();
+    while !stop.load(Ordering::Relaxed) {
+        terminal.draw(|frame| view.draw(frame, &state.lock(), shutdown.is_requested()))?;
+        if event::poll(REDRAW_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                view.handle_key(key, shutdown);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What the user picked on screen
+#[derive(Default)]
+struct View {
+    /// Instrument whose book is shown, as an index into the received books
+    instruments: ListState,
+}
+
+impl View {
+    fn handle_key(&mut self, key: KeyEvent, shutdown: &Shutdown) {
+        if key.kind != KeyEventKind::Press {
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => shutdown.request("quit from the terminal UI"),
+            // The terminal is in raw mode, so Ctrl+C arrives as a key rather than a signal
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => shutdown.request("Ctrl+C"),
+            KeyCode::Up | KeyCode::Char('k') => self.instruments.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => self.instruments.select_next(),
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, state: &ClientState, shutting_down: bool) {
+        let [status, middle, bottom, help] = Layout::vertical([
+            Constraint::Length(3), Constraint::Min(LADDER_DEPTH as u16 * 2 + 3), Constraint::Length(12), Constraint::Length(1),
+        ]).areas(frame.area());
+        let [account, market] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(middle);
+        let [balances, positions] = Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(account);
+        let [instruments, ladder] = Layout::horizontal([Constraint::Length(28), Constraint::Min(30)]).areas(market);
+        let [trades, events] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(bottom);
+
+        frame.render_widget(status_line(state, shutting_down), status);
+        frame.render_widget(amounts_table("Balances", ["Currency", "Balance"], state.balances.as_ref().map(|b| &b.items),
+            &BALANCE_ID_FIELDS, &BALANCE_AMOUNT_FIELDS), balances);
+        frame.render_widget(amounts_table("Positions", ["Instrument", "Size"], state.positions.as_ref().map(|p| &p.items),
+            &POSITION_ID_FIELDS, &POSITION_SIZE_FIELDS), positions);
+
+        // Keep the selection on a received book
+        match self.instruments.selected() {
+            _ if state.books.is_empty() => self.instruments.select(None),
+            None => self.instruments.select(Some(0)),
+            Some(selected) if selected >= state.books.len() => self.instruments.select(Some(state.books.len() - 1)),
+            Some(_) => {}
+        }
+        let symbols = List::new(state.books.keys().map(String::as_str))
+            .block(Block::bordered().title("Instruments"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(symbols, instruments, &mut self.instruments);
+        let selected = self.instruments.selected().and_then(|index| state.books.values().nth(index));
+        frame.render_widget(ladder_table(selected), ladder);
+
+        let trade_rows = state.trades.iter().rev().map(|trade| {
+            let color = match trade.side.as_deref() {
+                Some("buy") => Color::Green,
+                Some("sell") => Color::Red,
+                _ => Color::Reset,
+            };
+            Row::new([time(trade.received_at), trade.symbol.clone(), trade.side.clone().unwrap_or_default(),
+                trade.price.clone(), trade.quantity.clone()]).style(Style::new().fg(color))
+        });
+        let widths = [Constraint::Length(8), Constraint::Fill(2), Constraint::Length(4), Constraint::Fill(1), Constraint::Fill(1)];
+        frame.render_widget(Table::new(trade_rows, widths)
+            .header(Row::new(["Time", "Instrument", "Side", "Price", "Quantity"]).style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(Block::bordered().title("Recent trades")), trades);
+
+        let event_lines: Vec<Line> = state.events.iter().rev()
+            .map(|event| Line::from(format!("{} {}", time(event.at), event.message)))
+            .collect();
+        frame.render_widget(Paragraph::new(event_lines).block(Block::bordered().title("Events")), events);
+
+        frame.render_widget(Paragraph::new("↑/↓ select instrument   q quit").style(Style::new().fg(Color::DarkGray)), help);
+    }
+}
+
+fn time(at: DateTime<Utc>) -> String {
+    at.format("%H:%M:%S").to_string()
+}
+
+fn status_line(state: &ClientState, shutting_down: bool) -> Paragraph<'static> {
+    let color = match state.connection {
+        ConnectionState::Open => Color::Green,
+        ConnectionState::Degraded | ConnectionState::Connecting | ConnectionState::Authenticating | ConnectionState::Reconnecting => Color::Yellow,
+        _ => Color::Red,
+    };
+    let last_message = state.last_message_at
+        .map_or("none".to_string(), |at| format!("{:.1}s ago", (Utc::now() - at).num_milliseconds() as f64 / 1000.0));
+    let mut spans = vec![
+        Span::styled(state.connection.as_str().to_uppercase(), Style::new().fg(color).add_modifier(Modifier::BOLD)),
+        Span::raw(format!(" since {}  conn {}  reconnects {}  messages {}  last message {}  {}",
+            time(state.connection_since), state.conn_id, state.reconnects, state.messages_received, last_message, state.server_url)),
+    ];
+    if shutting_down {
+        spans.push(Span::styled("  shutting down", Style::new().fg(Color::Yellow)));
+    }
+    Paragraph::new(Line::from(spans)).block(Block::bordered().title("Connection"))
+}
+
+// Identifier and amount of each entry, in the order received
+fn amounts_table<'a>(title: &'a str, header: [&'a str; 2], items: Option<&Value>, id_fields: &[&str], amount_fields: &[&str]) -> Table<'a> {
+    let rows: Vec<Row> = items.and_then(Value::as_array).into_iter().flatten()
+        .filter_map(|item| Some(Row::new([field(item, id_fields)?, field(item, amount_fields).unwrap_or_default()])))
+        .collect();
+    Table::new(rows, [Constraint::Fill(1), Constraint::Fill(1)])
+        .header(Row::new(header).style(Style::new().add_modifier(Modifier::BOLD)))
+        .block(Block::bordered().title(title))
+}
+
+// Asks above bids, best prices in the middle
+fn ladder_table(book: Option<&crate::state::OrderBook>) -> Table<'static> {
+    let Some(book) = book else {
+        return Table::new(Vec::<Row>::new(), [Constraint::Fill(1)]).block(Block::bordered().title("Book (none received)"));
+    };
+    let asks = book.sell.iter().take(LADDER_DEPTH).rev()
+        .map(|level| Row::new([String::new(), level.price.clone(), level.quantity.clone()]).style(Style::new().fg(Color::Red)));
+    let bids = book.buy.iter().take(LADDER_DEPTH)
+        .map(|level| Row::new([level.quantity.clone(), level.price.clone(), String::new()]).style(Style::new().fg(Color::Green)));
+    Table::new(asks.chain(bids).collect::<Vec<_>>(), [Constraint::Fill(1); 3])
+        .header(Row::new(["Bid quantity", "Price", "Ask quantity"]).style(Style::new().add_modifier(Modifier::BOLD)))
+        .block(Block::bordered().title(format!("Book {} at {}", book.symbol, time(book.received_at))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn screen(view: &mut View, state: &SharedState) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(140, 45)).unwrap();
+        terminal.draw(|frame| view.draw(frame, &state.lock(), false)).unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer.content().chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_panes_show_state_and_follow_selection() {
+        let state = SharedState::new();
+        for (symbol, bid) in [("BTC-USD", "42000.5"), ("ETH-USD", "2000.25")] {
+            state.record_message(&format!(r#"{{"mbp_snapshot":{{"symbol":"{}","buy":[{{"price":"{}","quantity":"1.5"}}],
+                "sell":[{{"price":"99999","quantity":"0.7"}}]}}}}"#, symbol, bid));
+        }
+        state.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"-0.25"}],
+            "balances":[{"currency":"USD","cash_balance":"1000"}]}}"#);
+        state.record_message(r#"{"trade":{"symbol":"ETH-USD","price":"2001","quantity":"3","side":"sell"}}"#);
+        state.record_event("Alert quiet firing");
+
+        let mut view = View::default();
+        let text = screen(&mut view, &state);
+        for expected in ["CLOSED", "messages 4", "USD", "1000", "-0.25", "Book BTC-USD", "42000.5", "99999", "2001", "Alert quiet firing"] {
+            assert!(text.contains(expected), "{} missing from\n{}", expected, text);
+        }
+        assert!(!text.contains("2000.25"));
+
+        let shutdown = Shutdown::new();
+        view.handle_key(KeyEvent::from(KeyCode::Down), &shutdown);
+        assert!(screen(&mut view, &state).contains("2000.25"));
+        view.handle_key(KeyEvent::from(KeyCode::Down), &shutdown);
+        assert!(screen(&mut view, &state).contains("Book ETH-USD"));
+        assert!(!shutdown.is_requested());
+        view.handle_key(KeyEvent::from(KeyCode::Char('q')), &shutdown);
+        assert_eq!(shutdown.reason().as_deref(), Some("quit from the terminal UI"));
+    }
+
+    #[test]
+    fn test_empty_state_draws() {
+        let text = screen(&mut View::default(), &SharedState::new());
+        assert!(text.contains("Book (none received)"));
+        assert!(text.contains("last message none"));
+    }
+}
@@ -3,22 +3,25 @@ extern crate chrono;
 use chrono::Utc;
 use jwtk::{sign,HeaderAndClaims};
 use jwtk::ecdsa::EcdsaPrivateKey;
-use log::info;
+use tracing::info;
 use serde_json::{Map, Value};
 use std::time::Duration;
 use crate::error::AppError;
 
+/// How long an access token is valid for after it was generated
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(18000);
+
 pub fn generate_access_token(api_key: &str, pkey: &str) -> Result<String, AppError> {
 
     info!("Loading private key");
     let key: EcdsaPrivateKey = EcdsaPrivateKey::from_pem(pkey.as_bytes())
-        .map_err(|e| AppError::Authentication(format!("Failed to load private key: {}", e)))?;
+        .map_err(|e| AppError::authentication("Failed to load private key", e))?;
 
     let mut claims: HeaderAndClaims<Map<String, Value>> = HeaderAndClaims::new_dynamic();
 
     claims
         .set_iat_now()
-        .set_exp_from_now(Duration::from_secs(18000))
+        .set_exp_from_now(TOKEN_LIFETIME)
         .insert("client", "api".to_owned())
         .insert("sub", api_key.to_owned())
         .insert("nonce",  Utc::now().timestamp())
@@ -28,7 +31,7 @@ pub fn generate_access_token(api_key: &str, pkey: &str) -> Result<String, AppErr
     info!("Signing JWT token");
 
     let token = sign(&mut claims, &key)
-        .map_err(|e| AppError::Authentication(format!("Failed to sign JWT: {}", e)))?;
+        .map_err(|e| AppError::authentication("Failed to sign JWT", e))?;
 
     info!("JWT signed successfully");
     Ok(token)
@@ -56,8 +59,9 @@ INVALID_KEY_DATA
         assert!(result.is_err());
 
         match result {
-            Err(AppError::Authentication(msg)) => {
-                assert!(msg.contains("Failed to load private key"));
+            Err(AppError::Authentication { context, source }) => {
+                assert_eq!(context, "Failed to load private key");
+                assert!(!source.to_string().is_empty());
             },
             _ => panic!("Expected Authentication error"),
         }
@@ -69,8 +73,9 @@ INVALID_KEY_DATA
         assert!(result.is_err());
 
         match result {
-            Err(AppError::Authentication(msg)) => {
-                assert!(msg.contains("Failed to load private key"));
+            Err(AppError::Authentication { context, source }) => {
+                assert_eq!(context, "Failed to load private key");
+                assert!(!source.to_string().is_empty());
             },
             _ => panic!("Expected Authentication error"),
         }
@@ -83,8 +88,9 @@ INVALID_KEY_DATA
         assert!(result.is_err());
 
         match result {
-            Err(AppError::Authentication(msg)) => {
-                assert!(msg.contains("Failed to load private key"));
+            Err(AppError::Authentication { context, source }) => {
+                assert_eq!(context, "Failed to load private key");
+                assert!(!source.to_string().is_empty());
             },
             _ => panic!("Expected Authentication error"),
         }
@@ -0,0 +1,632 @@
+// Webhook notifications: selected connection and account events are posted as
+// signed JSON to HTTP endpoints, retried from a queue while an endpoint fails.
+
+use crate::config::Settings;
+use crate::connection_state::{ConnectionState, StateChange};
+use crate::error::AppError;
+use crate::proxy::ProxyConfig;
+use crate::state::{amounts, BALANCE_AMOUNT_FIELDS, BALANCE_ID_FIELDS, POSITION_ID_FIELDS, POSITION_SIZE_FIELDS};
+use crate::tls::TlsConfig;
+use crate::transport::TransportConfig;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use tungstenite::stream::MaybeTlsStream;
+use url::Url;
+
+/// Events which can be notified, as named in `PT_WEBHOOK_EVENTS` and the `event` field
+pub const EVENTS: [&str; 6] = ["disconnect", "reconnect", "auth_failure", "position_change", "balance_threshold", "alert"];
+
+// Notifications waiting for delivery, the oldest are dropped beyond that
+const MAX_QUEUE: usize = 1000;
+
+// Longest pause between attempts to reach a failing endpoint
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Where notifications go and which events trigger them
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookConfig {
+    pub urls: Vec<Url>,
+    /// Key for the HMAC-SHA256 signature of each body, unsigned when unset
+    pub secret: Option<String>,
+    pub events: HashSet<String>,
+    /// Smallest change of a position's size worth notifying
+    pub position_change: Option<f64>,
+    /// Balances to watch per currency, notified when they cross the amount either way
+    pub balance_thresholds: Vec<(String, f64)>,
+    /// Attempts per notification before it is given up
+    pub max_attempts: u32,
+    /// First pause after a failed attempt, doubled while failures continue
+    pub retry_backoff: Duration,
+    /// Proxy, trusted CAs and socket settings of the client's own connection
+    pub proxy: Option<ProxyConfig>,
+    pub tls: TlsConfig,
+    pub transport: TransportConfig,
+}
+
+impl WebhookConfig {
+    /// Read the webhook settings, `None` unless `PT_WEBHOOK_URL` is set
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let list = |value: String| value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect::<Vec<_>>();
+
+        let Some(urls) = settings.value("PT_WEBHOOK_URL") else {
+            return Ok(None);
+        };
+        let urls = list(urls).iter()
+            .map(|url| match Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => Ok(url),
+                _ => Err(format!("Invalid PT_WEBHOOK_URL '{}', expected an http:// or https:// URL", url)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let events: HashSet<String> = match settings.value("PT_WEBHOOK_EVENTS") {
+            Some(events) => list(events).into_iter().collect(),
+            None => EVENTS.iter().map(|event| event.to_string()).collect(),
+        };
+        if let Some(unknown) = events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+            return Err(format!("Unknown event '{}' in PT_WEBHOOK_EVENTS, expected some of: {}", unknown, EVENTS.join(", ")));
+        }
+
+        let position_change = settings.value("PT_WEBHOOK_POSITION_CHANGE")
+            .map(|v| v.trim().parse::<f64>().ok().filter(|change| *change > 0.0)
+                .ok_or_else(|| format!("Invalid PT_WEBHOOK_POSITION_CHANGE '{}', expected a positive number", v)))
+            .transpose()?;
+        let balance_thresholds = settings.value("PT_WEBHOOK_BALANCE_THRESHOLDS").map_or(Ok(Vec::new()), |thresholds| {
+            list(thresholds).iter()
+                .map(|threshold| threshold.split_once(':')
+                    .and_then(|(currency, amount)| Some((currency.trim().to_string(), amount.trim().parse::<f64>().ok()?)))
+                    .ok_or_else(|| format!("Invalid threshold '{}' in PT_WEBHOOK_BALANCE_THRESHOLDS, expected CURRENCY:AMOUNT", threshold)))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let config = WebhookConfig {
+            urls,
+            secret: settings.value("PT_WEBHOOK_SECRET"),
+            events,
+            position_change,
+            balance_thresholds,
+            max_attempts: settings.number("PT_WEBHOOK_MAX_ATTEMPTS", 5)? as u32,
+            retry_backoff: Duration::from_millis(settings.number("PT_WEBHOOK_RETRY_MS", 2000)?),
+            proxy: ProxyConfig::from_settings(settings)?,
+            // Pins and the client certificate are for the Power.Trade server, not the endpoints
+            tls: TlsConfig { ca_file: TlsConfig::from_settings(settings)?.ca_file, ..TlsConfig::default() },
+            transport: TransportConfig::from_settings(settings)?,
+        };
+        if config.max_attempts == 0 || config.retry_backoff.is_zero() {
+            return Err("PT_WEBHOOK_MAX_ATTEMPTS and PT_WEBHOOK_RETRY_MS must be positive".to_string());
+        }
+        Ok(Some(config))
+    }
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message` under `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Signature header value for a body sent at `timestamp` (Unix seconds)
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mac = hmac_sha256(secret.as_bytes(), format!("{}.{}", timestamp, body).as_bytes());
+    mac.iter().fold("sha256=".to_string(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+// Last known account figures, to notice changes between position summaries
+#[derive(Default)]
+struct Account {
+    positions: Option<HashMap<String, f64>>,
+    balances: HashMap<String, f64>,
+    /// When the connection was lost, until it is open again
+    disconnected_at: Option<DateTime<Utc>>,
+}
+
+// A notification on its way to one endpoint
+struct Delivery {
+    url: Url,
+    event: String,
+    body: String,
+    attempts: u32,
+    next_at: Instant,
+}
+
+enum Command {
+    Deliver(Delivery),
+    /// Deliver with these settings from now on, including what is queued
+    Configure(Box<Dispatcher>),
+    /// Try everything queued once more, answering with the number left undelivered
+    Flush(Sender<usize>),
+}
+
+/// Notices webhook events and queues their notifications, cheap to clone
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<Mutex<Arc<WebhookConfig>>>,
+    environment: Option<String>,
+    account: Arc<Mutex<Account>>,
+    deliveries: Sender<Command>,
+}
+
+impl Webhooks {
+    /// Start the delivery thread
+    pub fn start(config: WebhookConfig, environment: Option<String>) -> io::Result<Self> {
+        let (deliveries, received) = channel();
+        let dispatcher = Dispatcher::new(&config);
+        thread::Builder::new().name("webhooks".to_string()).spawn(move || dispatcher.run(received))?;
+        info!(event = "webhooks_started", urls = config.urls.len(), events = ?config.events,
+            "Notifying {} webhook endpoints", config.urls.len());
+        Ok(Webhooks { config: Arc::new(Mutex::new(Arc::new(config))), environment, account: Arc::default(), deliveries })
+    }
+
+    /// Notify with `config` from now on, returns whether it differed from the one in effect.
+    /// Queued notifications keep their endpoint and are retried with the new settings.
+    pub fn reconfigure(&self, config: WebhookConfig) -> bool {
+        let mut current = self.config.lock().unwrap();
+        if **current == config {
+            return false;
+        }
+        let _ = self.deliveries.send(Command::Configure(Box::new(Dispatcher::new(&config))));
+        info!(event = "webhooks_reconfigured", urls = config.urls.len(), events = ?config.events,
+            "Notifying {} webhook endpoints", config.urls.len());
+        *current = Arc::new(config);
+        true
+    }
+
+    /// Configuration in effect
+    pub fn config(&self) -> Arc<WebhookConfig> {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Follow the connection lifecycle, see `ConnectionLifecycle::on_change`
+    pub fn connection_changed(&self, change: &StateChange) {
+        use ConnectionState::*;
+        let mut account = self.account.lock().unwrap();
+        match (change.from, change.to) {
+            (Open | Degraded, Reconnecting | Failed) => {
+                account.disconnected_at = Some(change.at);
+                self.notify("disconnect", json!({ "conn_id": change.conn_id, "reason": change.reason, "state": change.to.as_str() }));
+            }
+            (_, Open) => {
+                if let Some(since) = account.disconnected_at.take() {
+                    self.notify("reconnect", json!({ "conn_id": change.conn_id, "disconnected_secs": (change.at - since).num_seconds() }));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Notice failed authentication in the error ending a run
+    pub fn run_failed(&self, error: &AppError) {
+        if matches!(error, AppError::Authentication { .. } | AppError::AuthRejected { .. }) {
+            self.notify("auth_failure", json!({ "error_kind": error.kind(), "error": error.to_string() }));
+        }
+    }
+
+    /// Compare the positions and balances of a position summary with the previous one
+    pub fn record_message(&self, text: &str) {
+        let Ok(Value::Object(message)) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        let Some(summary) = message.get("position_summary") else {
+            return;
+        };
+        let config = self.config();
+        let mut account = self.account.lock().unwrap();
+
+        if let Some(positions) = summary.get("positions").and_then(Value::as_array) {
+            let current = amounts(positions, &POSITION_ID_FIELDS, &POSITION_SIZE_FIELDS);
+            if let (Some(previous), Some(threshold)) = (&account.positions, config.position_change) {
+                let symbols: HashSet<&String> = previous.keys().chain(current.keys()).collect();
+                for symbol in symbols {
+                    let (before, after) = (previous.get(symbol).copied().unwrap_or(0.0), current.get(symbol).copied().unwrap_or(0.0));
+                    if (after - before).abs() >= threshold {
+                        self.notify("position_change", json!({
+                            "symbol": symbol, "previous": before, "current": after, "change": after - before, "threshold": threshold,
+                        }));
+                    }
+                }
+            }
+            account.positions = Some(current);
+        }
+
+        if let Some(balances) = summary.get("balances").and_then(Value::as_array) {
+            let current = amounts(balances, &BALANCE_ID_FIELDS, &BALANCE_AMOUNT_FIELDS);
+            for (currency, threshold) in &config.balance_thresholds {
+                let (Some(before), Some(after)) = (account.balances.get(currency), current.get(currency)) else {
+                    continue;
+                };
+                if (*before < *threshold) != (*after < *threshold) {
+                    self.notify("balance_threshold", json!({
+                        "currency": currency, "threshold": threshold, "previous": before, "current": after,
+                        "direction": if *after < *threshold { "below" } else { "above" },
+                    }));
+                }
+            }
+            account.balances.extend(current);
+        }
+    }
+
+    /// Pass on an alert rule firing or resolving, see `alerts::Alerts`
+    pub fn alert(&self, alert: Value) {
+        self.notify("alert", alert);
+    }
+
+    /// Deliver what is still queued, waiting up to `timeout`
+    pub fn flush(&self, timeout: Duration) -> io::Result<()> {
+        let (reply, left) = channel();
+        self.deliveries.send(Command::Flush(reply)).map_err(|_| io::Error::other("webhook thread has stopped"))?;
+        match left.recv_timeout(timeout) {
+            Ok(0) => Ok(()),
+            Ok(left) => Err(io::Error::other(format!("{} notifications could not be delivered", left))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out delivering queued notifications")),
+        }
+    }
+
+    fn notify(&self, event: &str, details: Value) {
+        let config = self.config();
+        if !config.events.contains(event) {
+            return;
+        }
+        info!(event = "webhook_event", webhook_event = event, "Webhook event {}: {}", event, details);
+        let body = json!({
+            "event": event,
+            "at": Utc::now(),
+            "client": format!("client-rust-ws/{}", env!("CARGO_PKG_VERSION")),
+            "environment": self.environment,
+            "details": details,
+        })
+        .to_string();
+        for url in &config.urls {
+            let delivery = Delivery { url: url.clone(), event: event.to_string(), body: body.clone(), attempts: 0, next_at: Instant::now() };
+            let _ = self.deliveries.send(Command::Deliver(delivery));
+        }
+    }
+}
+
+// Delivery thread: posts queued notifications, retrying failed ones with backoff
+struct Dispatcher {
+    secret: Option<String>,
+    max_attempts: u32,
+    retry_backoff: Duration,
+    proxy: Option<ProxyConfig>,
+    tls: TlsConfig,
+    transport: TransportConfig,
+}
+
+impl Dispatcher {
+    fn new(config: &WebhookConfig) -> Self {
+        Dispatcher {
+            secret: config.secret.clone(),
+            max_attempts: config.max_attempts,
+            retry_backoff: config.retry_backoff,
+            proxy: config.proxy.clone(),
+            tls: config.tls.clone(),
+            transport: config.transport.clone(),
+        }
+    }
+
+    fn run(mut self, commands: Receiver<Command>) {
+        let mut queue: VecDeque<Delivery> = VecDeque::new();
+        loop {
+            let command = match queue.iter().map(|delivery| delivery.next_at).min() {
+                None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(at) => commands.recv_timeout(at.saturating_duration_since(Instant::now())),
+            };
+            match command {
+                Ok(Command::Deliver(delivery)) => {
+                    if queue.len() >= MAX_QUEUE {
+                        if let Some(dropped) = queue.pop_front() {
+                            warn!(event = "webhook_dropped", webhook_event = %dropped.event, "Webhook queue full, dropping {} notification", dropped.event);
+                        }
+                    }
+                    queue.push_back(delivery);
+                }
+                Ok(Command::Configure(dispatcher)) => self = *dispatcher,
+                Ok(Command::Flush(reply)) => {
+                    for delivery in queue.iter_mut() {
+                        delivery.next_at = Instant::now();
+                    }
+                    queue = self.attempt_due(queue);
+                    let _ = reply.send(queue.len());
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            queue = self.attempt_due(queue);
+        }
+    }
+
+    // Attempt every due delivery, keeping those to retry
+    fn attempt_due(&self, queue: VecDeque<Delivery>) -> VecDeque<Delivery> {
+        let now = Instant::now();
+        queue.into_iter().filter_map(|mut delivery| {
+            if delivery.next_at > now {
+                return Some(delivery);
+            }
+            delivery.attempts += 1;
+            match self.post(&delivery) {
+                Ok(()) => {
+                    info!(event = "webhook_delivered", webhook_event = %delivery.event, url = %delivery.url, attempts = delivery.attempts,
+                        "Delivered {} notification to {}", delivery.event, delivery.url);
+                    None
+                }
+                Err(e) if delivery.attempts >= self.max_attempts => {
+                    error!(event = "webhook_abandoned", webhook_event = %delivery.event, url = %delivery.url, attempts = delivery.attempts,
+                        "Giving up on {} notification to {} after {} attempts: {}", delivery.event, delivery.url, delivery.attempts, e);
+                    None
+                }
+                Err(e) => {
+                    let backoff = (self.retry_backoff * 2u32.saturating_pow(delivery.attempts - 1)).min(MAX_RETRY_BACKOFF);
+                    warn!(event = "webhook_failed", webhook_event = %delivery.event, url = %delivery.url, attempts = delivery.attempts,
+                        "Failed to deliver {} notification to {}, retrying in {:?}: {}", delivery.event, delivery.url, backoff, e);
+                    delivery.next_at = now + backoff;
+                    Some(delivery)
+                }
+            }
+        }).collect()
+    }
+
+    // POST the body, any 2xx answer counts as delivered
+    fn post(&self, delivery: &Delivery) -> Result<(), String> {
+        let url = &delivery.url;
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let stream = match self.proxy.as_ref().filter(|proxy| proxy.applies_to(host)) {
+            Some(proxy) => proxy.connect(&self.transport, host, port),
+            None => self.transport.connect(host, port),
+        };
+        let stream = stream.map_err(|e| e.to_string())?;
+        let mut stream = match url.scheme() {
+            "https" => self.tls.connect(host, stream).map_err(|e| e.to_string())?,
+            _ => MaybeTlsStream::Plain(stream),
+        };
+
+        let timestamp = Utc::now().timestamp();
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: client-rust-ws/{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nX-Webhook-Event: {}\r\nX-Webhook-Timestamp: {}\r\nConnection: close\r\n",
+            &url[url::Position::BeforePath..url::Position::AfterQuery],
+            &url[url::Position::BeforeHost..url::Position::AfterPort],
+            env!("CARGO_PKG_VERSION"), delivery.body.len(), delivery.event, timestamp,
+        );
+        if let Some(secret) = &self.secret {
+            let _ = write!(request, "X-Webhook-Signature: {}\r\n", signature(secret, timestamp, &delivery.body));
+        }
+        request.push_str("\r\n");
+        request.push_str(&delivery.body);
+        stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+        stream.flush().map_err(|e| e.to_string())?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).map_err(|e| e.to_string())?;
+        match status_line.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok()) {
+            Some(status) if (200..300).contains(&status) => Ok(()),
+            Some(_) => Err(format!("endpoint answered {}", status_line.trim())),
+            None => Err("no HTTP response".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn config(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            urls: vec![Url::parse(url).unwrap()],
+            secret: Some("s3cret".to_string()),
+            events: EVENTS.iter().map(|event| event.to_string()).collect(),
+            position_change: Some(1.0),
+            balance_thresholds: vec![("USD".to_string(), 1000.0)],
+            max_attempts: 3,
+            retry_backoff: Duration::from_millis(20),
+            proxy: None,
+            tls: TlsConfig::default(),
+            transport: TransportConfig::default(),
+        }
+    }
+
+    // Headers, by lower case name, and body of a received request
+    type Received = (HashMap<String, String>, String);
+
+    // Local receiver answering with `statuses` in turn and passing on each request
+    fn receiver(statuses: Vec<u16>) -> (String, Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/pt?team=desk", listener.local_addr().unwrap());
+        let (requests, received) = channel();
+        thread::spawn(move || {
+            for (stream, status) in listener.incoming().flatten().zip(statuses) {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.insert(name.to_lowercase(), value.trim().to_string());
+                    } else {
+                        headers.insert("request".to_string(), line.trim().to_string());
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; headers["content-length"].parse().unwrap()];
+                reader.read_exact(&mut body).unwrap();
+                write!(&stream, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                requests.send((headers, String::from_utf8(body).unwrap())).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn change(from: ConnectionState, to: ConnectionState) -> StateChange {
+        StateChange { conn_id: 3, from, to, at: Utc::now(), reason: "read failed".to_string() }
+    }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let hex: String = mac.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        let long_key = hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(long_key[..4], [0x60, 0xe4, 0x31, 0x59]);
+    }
+
+    #[test]
+    fn test_signed_notification_is_retried_until_accepted() {
+        let (url, requests) = receiver(vec![503, 200]);
+        let webhooks = Webhooks::start(config(&url), Some("test".to_string())).unwrap();
+        webhooks.connection_changed(&change(ConnectionState::Open, ConnectionState::Reconnecting));
+
+        let (first, _) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        let (headers, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first["x-webhook-event"], "disconnect");
+        assert_eq!(headers["request"], "POST /hooks/pt?team=desk HTTP/1.1");
+        let timestamp: i64 = headers["x-webhook-timestamp"].parse().unwrap();
+        assert_eq!(headers["x-webhook-signature"], signature("s3cret", timestamp, &body));
+
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["event"], "disconnect");
+        assert_eq!(body["environment"], "test");
+        assert_eq!(body["details"]["reason"], "read failed");
+        webhooks.flush(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_notifications_go_through_the_proxy() {
+        // Answers the CONNECT, then the request tunnelled through it
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+        let (tunnels, tunnelled) = channel();
+        thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                lines.push(line.trim().to_string());
+                line.clear();
+            }
+            write!(&stream, "HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            lines.push(line.trim().to_string());
+            write!(&stream, "HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            tunnels.send(lines).unwrap();
+        });
+
+        let mut config = config("http://hooks.example.invalid/pt");
+        config.proxy = Some(ProxyConfig::parse(&proxy_url, None).unwrap());
+        let webhooks = Webhooks::start(config, None).unwrap();
+        webhooks.run_failed(&AppError::AuthRejected { status: tungstenite::http::StatusCode::UNAUTHORIZED });
+        let lines = tunnelled.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(lines[0], "CONNECT hooks.example.invalid:80 HTTP/1.1");
+        assert_eq!(lines.last().unwrap(), "POST /pt HTTP/1.1");
+        webhooks.flush(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_undeliverable_notification_is_given_up() {
+        let (url, requests) = receiver(vec![500, 500, 500]);
+        let webhooks = Webhooks::start(config(&url), None).unwrap();
+        webhooks.run_failed(&AppError::AuthRejected { status: tungstenite::http::StatusCode::UNAUTHORIZED });
+        for _ in 0..3 {
+            assert_eq!(requests.recv_timeout(Duration::from_secs(5)).unwrap().0["x-webhook-event"], "auth_failure");
+        }
+        webhooks.flush(Duration::from_secs(5)).unwrap();
+        assert!(requests.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_reconfigure_applies_to_later_notifications() {
+        let (url, requests) = receiver(vec![200]);
+        let mut config = config(&url);
+        config.events.remove("auth_failure");
+        let webhooks = Webhooks::start(config.clone(), None).unwrap();
+        assert!(!webhooks.reconfigure(config.clone()));
+        let error = AppError::AuthRejected { status: tungstenite::http::StatusCode::UNAUTHORIZED };
+        webhooks.run_failed(&error);
+
+        config.events.insert("auth_failure".to_string());
+        config.secret = Some("rotated".to_string());
+        assert!(webhooks.reconfigure(config));
+        webhooks.run_failed(&error);
+        let (headers, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(headers["x-webhook-event"], "auth_failure");
+        let timestamp: i64 = headers["x-webhook-timestamp"].parse().unwrap();
+        assert_eq!(headers["x-webhook-signature"], signature("rotated", timestamp, &body));
+        webhooks.flush(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_account_and_connection_events() {
+        let (url, requests) = receiver(vec![200; 10]);
+        let mut config = config(&url);
+        config.events.remove("disconnect");
+        let webhooks = Webhooks::start(config, None).unwrap();
+        let next_event = || {
+            let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            serde_json::from_str::<Value>(&body).unwrap()
+        };
+
+        // The first summary is the baseline
+        webhooks.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"0.5"}],
+            "balances":[{"currency":"USD","cash_balance":"1500"}]}}"#);
+        webhooks.record_message(r#"{"position_summary":{"positions":[{"symbol":"BTC-USD","size":"0.9"},{"symbol":"ETH-USD","size":"-2"}],
+            "balances":[{"currency":"USD","cash_balance":"900"}]}}"#);
+        let event = next_event();
+        assert_eq!((event["event"].as_str(), event["details"]["symbol"].as_str()), (Some("position_change"), Some("ETH-USD")));
+        assert_eq!(event["details"]["change"], -2.0);
+        let event = next_event();
+        assert_eq!((event["event"].as_str(), event["details"]["direction"].as_str()), (Some("balance_threshold"), Some("below")));
+
+        webhooks.connection_changed(&change(ConnectionState::Open, ConnectionState::Reconnecting));
+        webhooks.connection_changed(&change(ConnectionState::Authenticating, ConnectionState::Open));
+        assert_eq!(next_event()["event"], "reconnect");
+        webhooks.connection_changed(&change(ConnectionState::Degraded, ConnectionState::Open));
+        webhooks.flush(Duration::from_secs(5)).unwrap();
+        assert!(requests.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let vars = ["PT_WEBHOOK_URL", "PT_WEBHOOK_SECRET", "PT_WEBHOOK_EVENTS", "PT_WEBHOOK_POSITION_CHANGE",
+            "PT_WEBHOOK_BALANCE_THRESHOLDS", "PT_WEBHOOK_MAX_ATTEMPTS", "PT_WEBHOOK_RETRY_MS"];
+        for name in vars {
+            std::env::remove_var(name);
+        }
+        assert_eq!(WebhookConfig::from_settings(&Settings::from_env()), Ok(None));
+
+        std::env::set_var("PT_WEBHOOK_URL", "https://alerts.example.com/hook, http://localhost:9000/");
+        std::env::set_var("PT_WEBHOOK_EVENTS", "disconnect,auth_failure");
+        std::env::set_var("PT_WEBHOOK_BALANCE_THRESHOLDS", "USD:1000, BTC:0.5");
+        let config = WebhookConfig::from_settings(&Settings::from_env()).unwrap().unwrap();
+        assert_eq!(config.urls.len(), 2);
+        assert_eq!(config.events.len(), 2);
+        assert_eq!(config.balance_thresholds[1], ("BTC".to_string(), 0.5));
+        assert_eq!(config.max_attempts, 5);
+
+        std::env::set_var("PT_WEBHOOK_EVENTS", "disconnect,fire");
+        assert!(WebhookConfig::from_settings(&Settings::from_env()).unwrap_err().contains("Unknown event 'fire'"));
+        std::env::set_var("PT_WEBHOOK_EVENTS", "");
+        std::env::set_var("PT_WEBHOOK_POSITION_CHANGE", "-1");
+        assert!(WebhookConfig::from_settings(&Settings::from_env()).unwrap_err().contains("PT_WEBHOOK_POSITION_CHANGE"));
+        std::env::set_var("PT_WEBHOOK_URL", "ftp://example.com");
+        assert!(WebhookConfig::from_settings(&Settings::from_env()).unwrap_err().contains("Invalid PT_WEBHOOK_URL"));
+        for name in vars {
+            std::env::remove_var(name);
+        }
+    }
+}
@@ -1,26 +1,80 @@
 use crate::config::Config;
+use crate::connection_state::{ConnectionLifecycle, ConnectionState};
 use crate::error::AppError;
-use crate::utils::generate_access_token;
+use crate::metrics::LatencySummary;
+use crate::ping::PingTracker;
+use crate::utils::{generate_access_token, TOKEN_LIFETIME};
 
-use log::info;
-use tungstenite::{client::IntoClientRequest, connect, http::HeaderValue, WebSocket, stream::MaybeTlsStream, Message};
+use chrono::{DateTime, Utc};
+use tracing::{debug, error, info, info_span, warn, Span};
+use tungstenite::{client::{client_with_config, IntoClientRequest}, http::{header::USER_AGENT, HeaderMap, HeaderName, HeaderValue, StatusCode}, HandshakeError, WebSocket, stream::MaybeTlsStream, Message};
+use tungstenite::protocol::frame::{coding::CloseCode, CloseFrame};
 use url::Url;
 use std::net::TcpStream;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::thread::sleep;
 
+// Longest a read waits for a message before returning control to the caller
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Source of ids for each connection made by this process, used to correlate log records
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// A read timing out on the poll interval rather than failing
+fn is_poll_timeout(error: &std::io::Error) -> bool {
+    matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// HTTP response to the WebSocket upgrade request, kept for inspection after connecting
+#[derive(Clone, Debug)]
+pub struct HandshakeResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// `X-Correlation-Id` sent with the upgrade request
+    pub correlation_id: String,
+    /// When the access token sent with the upgrade request expires
+    pub token_expires_at: DateTime<Utc>,
+}
+
+impl HandshakeResponse {
+    /// Value of response header `name`, if present and valid text
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    /// Rate limit related headers, e.g. `X-RateLimit-Remaining` or `Retry-After`
+    pub fn rate_limit_hints(&self) -> Vec<(&str, &str)> {
+        self.headers.iter()
+            .filter(|(name, _)| name.as_str().contains("ratelimit") || name.as_str() == "retry-after")
+            .filter_map(|(name, value)| value.to_str().ok().map(|value| (name.as_str(), value)))
+            .collect()
+    }
+}
+
 pub struct WebSocketClient {
     socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    handshake: HandshakeResponse,
     config: Config,
+    connection_id: u64,
+    span: Span,
+    pings: PingTracker,
+    lifecycle: ConnectionLifecycle,
+    reconnects: u32,
 }
 
 impl WebSocketClient {
-    pub fn new(config: Config) -> Result<Self, AppError> {
+    /// Connect to the server. Listeners registered on `lifecycle` beforehand
+    /// observe the initial connection attempt as well as later transitions.
+    pub fn new(config: Config, mut lifecycle: ConnectionLifecycle) -> Result<Self, AppError> {
         // Validate configuration before connecting
         Self::validate_config(&config)?;
         
-        let socket = Self::connect(&config)?;
-        Ok(WebSocketClient { socket, config })
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let span = Self::connection_span(&config, connection_id);
+        let (socket, handshake) = span.in_scope(|| Self::connect(&config, connection_id, &mut lifecycle))?;
+        let pings = PingTracker::new(Duration::from_millis(config.ping_warn_ms));
+        Ok(WebSocketClient { socket, handshake, config, connection_id, span, pings, lifecycle, reconnects: 0 })
     }
     
     // Validate the configuration
@@ -44,61 +98,257 @@ impl WebSocketClient {
         }
         
         // Validate other configuration parameters
-        if config.epoch_count == 0 {
+        if config.limits.epochs == Some(0) {
             return Err(AppError::Config("Epoch count must be positive".to_string()));
         }
 
+        if config.limits.duration.is_some_and(|duration| duration.is_zero()) {
+            return Err(AppError::Config("Run duration cannot be zero".to_string()));
+        }
+
         if config.sleep_duration == 0 {
             return Err(AppError::Config("Sleep duration cannot be zero".to_string()));
         }
 
+        config.transport.validate().map_err(AppError::Config)?;
+
         Ok(())
     }
     
-    fn connect(config: &Config) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, AppError> {
-        info!("Connecting to {}", config.server_url);
-        
+    // Span covering one connection, from the connect attempt until it is replaced
+    fn connection_span(config: &Config, connection_id: u64) -> Span {
+        info_span!("connection", conn_id = connection_id, url = %config.server_url)
+    }
+
+    fn connect(
+        config: &Config,
+        connection_id: u64,
+        lifecycle: &mut ConnectionLifecycle,
+    ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, HandshakeResponse), AppError> {
+        info!(event = "connecting", "Connecting to {}", config.server_url);
+        lifecycle.advance(connection_id, ConnectionState::Connecting, &format!("connecting to {}", config.server_url));
+
+        let result = Self::handshake(config, connection_id, lifecycle);
+        match &result {
+            Ok(_) => lifecycle.advance(connection_id, ConnectionState::Open, "handshake complete"),
+            Err(e) => lifecycle.advance(connection_id, ConnectionState::Failed, &e.to_string()),
+        }
+        result
+    }
+
+    fn handshake(
+        config: &Config,
+        connection_id: u64,
+        lifecycle: &mut ConnectionLifecycle,
+    ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, HandshakeResponse), AppError> {
         let url = Url::parse(&config.server_url)
             .map_err(|e| AppError::Config(format!("Invalid server URL: {}", e)))?;
-            
+
+        lifecycle.advance(connection_id, ConnectionState::Authenticating, "sending access token");
+
         // Generate authentication token
-        let token = generate_access_token(&config.api_key, &config.api_secret)?;
+        let token = info_span!("authenticate")
+            .in_scope(|| generate_access_token(&config.api_key, &config.api_secret))?;
+        let token_expires_at = Utc::now() + chrono::Duration::from_std(TOKEN_LIFETIME).unwrap_or_default();
         info!("Token generated successfully");
         
         // Create request with authentication header
-        let mut request = url.as_str().into_client_request()
-            .map_err(|e| AppError::Connection(format!("Failed to create request: {}", e)))?;
+        let mut request = url.as_str().into_client_request()?;
             
         request.headers_mut().append(
             "X-Power-Trade", 
             HeaderValue::from_str(&token)
-                .map_err(|e| AppError::Authentication(format!("Invalid token: {}", e)))?
+                .map_err(|e| AppError::authentication("Invalid token", e))?
         );
+
+        // Identify the client and this connection; values were validated with the configuration
+        let correlation_id = format!("{}-{}", config.correlation_id, connection_id);
+        let invalid = |e| AppError::Config(format!("Invalid handshake header: {}", e));
+        let headers = request.headers_mut();
+        headers.insert(USER_AGENT, HeaderValue::from_str(&config.user_agent).map_err(invalid)?);
+        headers.insert("X-Correlation-Id", HeaderValue::from_str(&correlation_id).map_err(invalid)?);
+        for (name, value) in &config.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| AppError::Config(format!("Invalid handshake header: {}", e)))?;
+            headers.append(name, HeaderValue::from_str(value).map_err(invalid)?);
+        }
         
         // Connect to WebSocket server
         info!("Connecting to Power.Trade server: {}", config.server_url);
-        let (socket, response) = connect(request)
-            .map_err(|e| AppError::Connection(format!("Connection failed: {}", e)))?;
+        let (socket, response) = Self::open_stream(&url, config)
+            .and_then(|stream| {
+                // Handle to the socket, which the TLS and WebSocket layers take ownership of
+                let raw = stream.try_clone()?;
+                let stream = match url.scheme() {
+                    "wss" => config.tls.connect(url.host_str().unwrap_or_default(), stream)?,
+                    _ => MaybeTlsStream::Plain(stream),
+                };
+                let websocket_config = config.transport.websocket_config();
+                let connected = client_with_config(request, stream, Some(websocket_config)).map_err(|e| match e {
+                    HandshakeError::Failure(e) => AppError::from(e),
+                    HandshakeError::Interrupted(_) => AppError::Timeout(format!(
+                        "WebSocket handshake timed out after {} ms",
+                        config.transport.handshake_timeout.as_millis()
+                    )),
+                })?;
+                // Replace the handshake timeouts: reads return regularly so the
+                // run loop can notice a shutdown request, writes block
+                raw.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+                raw.set_write_timeout(None)?;
+                Ok(connected)
+            })
+            .map_err(|err| {
+                error!(event = "connect_failed", error_kind = err.kind(), "{}", err);
+                err
+            })?;
             
-        info!("Connected to server: HTTP {}", response.status());
+        let handshake = HandshakeResponse {
+            status: response.status(),
+            headers: response.headers().clone(),
+            correlation_id,
+            token_expires_at,
+        };
+        info!(event = "connected", correlation_id = %handshake.correlation_id,
+              server = handshake.header("server").unwrap_or("unknown"),
+              rate_limits = ?handshake.rate_limit_hints(),
+              "Connected to server: HTTP {}", handshake.status);
+        debug!(headers = ?handshake.headers, "Handshake response headers");
         
-        Ok(socket)
+        Ok((socket, handshake))
     }
     
-    pub fn read_message(&mut self) -> Result<Message, AppError> {
-        self.socket.read().map_err(AppError::from)
+    // Open the TCP connection to the server, or a tunnel to it through the
+    // configured proxy, which TLS and the WebSocket handshake then run over.
+    // DNS failures are kept distinct from connection failures.
+    fn open_stream(url: &Url, config: &Config) -> Result<TcpStream, AppError> {
+        let host = url.host_str()
+            .ok_or_else(|| AppError::Config(format!("Server URL has no host: {}", url)))?;
+        let port = url.port_or_known_default()
+            .ok_or_else(|| AppError::Config(format!("Server URL has no port: {}", url)))?;
+
+        match config.proxy.as_ref().filter(|proxy| proxy.applies_to(host)) {
+            Some(proxy) => proxy.connect(&config.transport, host, port),
+            None => config.transport.connect(host, port),
+        }
+    }
+    
+    /// Read the next message, or `None` if none arrived within the poll interval
+    pub fn read_message(&mut self) -> Result<Option<Message>, AppError> {
+        match self.socket.read() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(tungstenite::Error::Io(e)) if is_poll_timeout(&e) => Ok(None),
+            Err(e) => Err(AppError::from(e)),
+        }
     }
     
     pub fn write_message(&mut self, msg: Message) -> Result<(), AppError> {
         self.socket.write(msg).map_err(AppError::from)
     }
+
+    /// Write `msg` and flush it to the server at once
+    pub fn send_message(&mut self, msg: Message) -> Result<(), AppError> {
+        self.socket.send(msg).map_err(AppError::from)
+    }
     
-    pub fn reconnect(&mut self) -> Result<(), AppError> {
-        info!("Attempting to reconnect...");
-        self.socket = Self::connect(&self.config)?;
+    pub fn reconnect(&mut self, reason: &str) -> Result<(), AppError> {
+        self.lifecycle.advance(self.connection_id, ConnectionState::Reconnecting, reason);
+
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let span = Self::connection_span(&self.config, connection_id);
+        (self.socket, self.handshake) = span.in_scope(|| {
+            info!(event = "reconnecting", previous_conn_id = self.connection_id, "Attempting to reconnect...");
+            Self::connect(&self.config, connection_id, &mut self.lifecycle)
+        })?;
+        self.span.in_scope(|| {
+            info!(event = "ping_rtt", rtt = %self.pings.summary(), lost = self.pings.lost(),
+                  unanswered = self.pings.outstanding(), "Ping round-trip times for closed connection");
+        });
+        self.pings.reset();
+        self.reconnects += 1;
+        self.connection_id = connection_id;
+        self.span = span;
         Ok(())
     }
+
+    /// Close the connection, sending a close frame with `reason` and waiting up to
+    /// the configured close timeout for the server to answer it.
+    /// Returns whether the server completed the close handshake.
+    pub fn close(&mut self, reason: &str) -> bool {
+        let _entered = self.span.clone().entered();
+        if !matches!(self.state(), ConnectionState::Open | ConnectionState::Degraded) {
+            // Nothing usable to close, e.g. after a failed reconnect
+            self.lifecycle.advance(self.connection_id, ConnectionState::Closed, reason);
+            return false;
+        }
+        self.lifecycle.advance(self.connection_id, ConnectionState::Closing, reason);
+        let frame = CloseFrame { code: CloseCode::Normal, reason: reason.to_string().into() };
+        let acknowledged = match self.socket.close(Some(frame)).and_then(|_| self.socket.flush()) {
+            Ok(()) => self.await_close(self.config.transport.close_timeout),
+            Err(e) => {
+                info!("Error while closing connection: {}", e);
+                false
+            }
+        };
+
+        if acknowledged {
+            info!(event = "close_acknowledged", "Server acknowledged close");
+        } else {
+            warn!(event = "close_unacknowledged", timeout_ms = self.config.transport.close_timeout.as_millis() as u64,
+                  "Server did not complete the close handshake");
+        }
+        self.lifecycle.advance(self.connection_id, ConnectionState::Closed, reason);
+        acknowledged
+    }
+
+    // Read until the server answers our close frame, discarding anything sent before it
+    fn await_close(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match self.socket.read() {
+                // Includes the server's close frame, the next read then reports the closed connection
+                Ok(_) => continue,
+                Err(tungstenite::Error::ConnectionClosed) => return true,
+                Err(tungstenite::Error::Io(e)) if is_poll_timeout(&e) => continue,
+                Err(e) => {
+                    info!("Connection ended during close handshake: {}", e);
+                    return false;
+                }
+            }
+        }
+        false
+    }
+
+    /// Span of the current connection, used as parent for per-message spans
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Number of successful reconnects made by this client
+    pub fn reconnect_count(&self) -> u32 {
+        self.reconnects
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.lifecycle.state()
+    }
+
+    /// When the connection entered its current state
+    pub fn state_since(&self) -> DateTime<Utc> {
+        self.lifecycle.since()
+    }
     
+    /// Replace the configuration. Connection settings take effect on the next
+    /// (re)connect, ping settings immediately.
+    pub fn apply_config(&mut self, config: Config) {
+        self.pings.set_warn_threshold(Duration::from_millis(config.ping_warn_ms));
+        self.config = config;
+    }
+
+    /// Response to the handshake of the current connection
+    pub fn handshake_response(&self) -> &HandshakeResponse {
+        &self.handshake
+    }
+
     // Add a method to get configuration information
     pub fn get_config_info(&self) -> String {
         format!(
@@ -109,14 +359,60 @@ impl WebSocketClient {
         )
     }
     
+    /// Send a ping carrying a sequence number and timestamp, returning the sequence number
+    pub fn send_ping(&mut self) -> Result<u64, AppError> {
+        let (seq, payload) = self.pings.next_ping();
+        self.socket.send(Message::Ping(payload.into()))?;
+        Ok(seq)
+    }
+
+    /// Match a received pong to its ping, returning the round-trip time
+    pub fn handle_pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        let _entered = self.span.enter();
+        let rtt = self.pings.record_pong(payload)?;
+
+        // Latency above the warning threshold marks the connection degraded until it recovers
+        let threshold = Duration::from_millis(self.config.ping_warn_ms);
+        match self.lifecycle.state() {
+            ConnectionState::Open if rtt > threshold => self.lifecycle.advance(
+                self.connection_id,
+                ConnectionState::Degraded,
+                &format!("ping round-trip {:?} above {:?}", rtt, threshold),
+            ),
+            ConnectionState::Degraded if rtt <= threshold => self.lifecycle.advance(
+                self.connection_id,
+                ConnectionState::Open,
+                &format!("ping round-trip {:?} recovered", rtt),
+            ),
+            _ => {}
+        }
+        Some(rtt)
+    }
+
+    /// Whether a ping sent within the ping interval waits for its pong
+    pub fn awaiting_pong(&self) -> bool {
+        self.pings.awaiting_pong(Duration::from_secs(self.config.ping_interval))
+    }
+
+    /// Whether the configured ping interval has elapsed since the last ping
+    pub fn ping_due(&self) -> bool {
+        self.pings.is_due(Duration::from_secs(self.config.ping_interval))
+    }
+
+    /// Round-trip time statistics for the current connection
+    pub fn rtt_summary(&self) -> LatencySummary {
+        self.pings.summary()
+    }
+
     // Add a method that uses both write_message and reconnect
     pub fn send_ping_with_retry(&mut self, max_attempts: u32) -> Result<(), AppError> {
+        let _entered = self.span.clone().entered();
         let mut attempts = 0;
 
         while attempts < max_attempts {
-            match self.write_message(Message::Ping(vec![1, 2, 3].into())) {
-                Ok(_) => {
-                    info!("Ping sent successfully");
+            match self.send_ping() {
+                Ok(seq) => {
+                    info!(seq, "Ping sent successfully");
                     return Ok(());
                 },
                 Err(e) => {
@@ -124,8 +420,9 @@ impl WebSocketClient {
                     attempts += 1;
                     
                     if attempts < max_attempts {
-                        match self.reconnect() {
+                        match self.reconnect(&format!("ping failed: {}", e)) {
                             Ok(_) => info!("Reconnected successfully, retrying ping"),
+                            Err(e) if !e.is_retryable() => return Err(e),
                             Err(e) => {
                                 info!("Failed to reconnect: {}", e);
                                 sleep(Duration::from_secs(1));
@@ -143,6 +440,9 @@ impl WebSocketClient {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::limits::RunLimits;
+    use crate::tls::TlsConfig;
+    use crate::transport::TransportConfig;
     
     // Helper function to create a test configuration
     fn create_test_config() -> Config {
@@ -150,9 +450,17 @@ mod tests {
             server_url: "wss://test.example.com/ws".to_string(),
             api_key: "test_key".to_string(),
             api_secret: "test_secret".to_string(),
-            epoch_count: 10,
+            limits: RunLimits { epochs: Some(10), ..Default::default() },
             sleep_duration: 5,
             max_retries: 3,
+            ping_interval: 30,
+            ping_warn_ms: 500,
+            proxy: None,
+            tls: TlsConfig::default(),
+            transport: TransportConfig::default(),
+            user_agent: Config::default_user_agent(),
+            headers: Vec::new(),
+            correlation_id: "test-run".to_string(),
         }
     }
     
@@ -230,7 +538,7 @@ mod tests {
     #[test]
     fn test_validate_config_invalid_epoch_count() {
         let mut config = create_test_config();
-        config.epoch_count = 0;
+        config.limits.epochs = Some(0);
         
         let result = WebSocketClient::validate_config(&config);
         assert!(result.is_err());
@@ -277,4 +585,161 @@ mod tests {
         assert!(info.contains(&config.api_key));
         assert!(info.contains(&config.max_retries.to_string()));
     }
+
+    #[test]
+    fn test_lifecycle_transitions_on_connect_and_close() {
+        use crate::test_support::{spawn_ws_server, test_config};
+
+        let (url, server) = spawn_ws_server(|mut socket, _| {
+            // Echo the client's close frame back
+            while socket.read().is_ok() {}
+        });
+
+        let mut lifecycle = ConnectionLifecycle::new();
+        let changes = lifecycle.subscribe();
+        let mut client = WebSocketClient::new(test_config(&url), lifecycle).unwrap();
+        assert_eq!(client.state(), ConnectionState::Open);
+
+        assert!(client.close("test finished"));
+        server.join().unwrap();
+
+        let states: Vec<ConnectionState> = changes.try_iter().map(|c| c.to).collect();
+        assert_eq!(states, vec![
+            ConnectionState::Connecting,
+            ConnectionState::Authenticating,
+            ConnectionState::Open,
+            ConnectionState::Closing,
+            ConnectionState::Closed,
+        ]);
+    }
+
+    #[test]
+    fn test_lifecycle_failed_when_server_unreachable() {
+        use crate::test_support::{closed_port_url, test_config};
+
+        let mut lifecycle = ConnectionLifecycle::new();
+        let changes = lifecycle.subscribe();
+        let result = WebSocketClient::new(test_config(&closed_port_url()), lifecycle);
+
+        let err = result.err().unwrap();
+        assert_eq!(err.kind(), "io");
+        assert!(err.is_retryable());
+
+        let last = changes.try_iter().last().unwrap();
+        assert_eq!(last.to, ConnectionState::Failed);
+        assert!(last.reason.contains("IO error"));
+    }
+
+    #[test]
+    fn test_http_401_handshake_is_auth_rejected() {
+        use crate::test_support::test_config;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // Server refusing the upgrade the way Power.Trade rejects bad credentials
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf).unwrap();
+            stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n").unwrap();
+        });
+
+        let result = WebSocketClient::new(test_config(&url), ConnectionLifecycle::new());
+        server.join().unwrap();
+
+        let err = result.err().unwrap();
+        assert!(matches!(err, AppError::AuthRejected { .. }));
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_invalid_key_is_not_retryable() {
+        use crate::test_support::{closed_port_url, test_config};
+
+        let mut config = test_config(&closed_port_url());
+        config.api_secret = "not a pem".to_string();
+
+        let err = WebSocketClient::new(config, ConnectionLifecycle::new()).err().unwrap();
+        assert_eq!(err.kind(), "authentication");
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_handshake_headers_sent_and_response_kept() {
+        use crate::test_support::{spawn_ws_server_with_headers, test_config};
+
+        let response_headers = [("Server", "pt-gateway/4.2"), ("X-RateLimit-Remaining", "17")];
+        let (url, server) = spawn_ws_server_with_headers(&response_headers, |mut socket, request| {
+            while socket.read().is_ok() {}
+            request
+        });
+
+        let mut config = test_config(&url);
+        config.headers = vec![("X-Desk".to_string(), "rates".to_string())];
+        let mut client = WebSocketClient::new(config, ConnectionLifecycle::new()).unwrap();
+
+        let handshake = client.handshake_response().clone();
+        assert_eq!(handshake.status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(handshake.header("server"), Some("pt-gateway/4.2"));
+        assert_eq!(handshake.rate_limit_hints(), vec![("x-ratelimit-remaining", "17")]);
+        assert!(handshake.correlation_id.starts_with("test-run-"));
+
+        client.close("test finished");
+        let request = server.join().unwrap();
+        let sent = |name: &str| request.headers().get(name).unwrap().to_str().unwrap().to_string();
+        assert!(sent("user-agent").starts_with("client-rust-ws/"));
+        assert_eq!(sent("x-correlation-id"), handshake.correlation_id);
+        assert_eq!(sent("x-desk"), "rates");
+        assert!(request.headers().contains_key("x-power-trade"));
+    }
+
+    #[test]
+    fn test_close_sends_reason_and_gives_up_after_timeout() {
+        use crate::test_support::{spawn_ws_server, test_config};
+        use std::sync::mpsc;
+
+        // Server which reads the close frame but never answers it
+        let (done, wait) = mpsc::channel::<()>();
+        let (url, server) = spawn_ws_server(move |mut socket, _| {
+            let frame = match socket.read() {
+                Ok(Message::Close(frame)) => frame,
+                other => panic!("expected close frame, got {:?}", other),
+            };
+            let _ = wait.recv();
+            frame
+        });
+
+        let mut config = test_config(&url);
+        config.transport.close_timeout = Duration::from_millis(300);
+        let mut client = WebSocketClient::new(config, ConnectionLifecycle::new()).unwrap();
+
+        let started = Instant::now();
+        assert!(!client.close("client shutting down (SIGTERM)"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(client.state(), ConnectionState::Closed);
+
+        done.send(()).unwrap();
+        let frame = server.join().unwrap().unwrap();
+        assert_eq!(frame.code, CloseCode::Normal);
+        assert_eq!(frame.reason.as_str(), "client shutting down (SIGTERM)");
+    }
+
+    #[test]
+    fn test_read_returns_none_when_idle() {
+        use crate::test_support::{spawn_ws_server, test_config};
+
+        let (url, server) = spawn_ws_server(|mut socket, _| {
+            socket.send(Message::text("hello")).unwrap();
+            while socket.read().is_ok() {}
+        });
+
+        let mut client = WebSocketClient::new(test_config(&url), ConnectionLifecycle::new()).unwrap();
+        assert_eq!(client.read_message().unwrap(), Some(Message::text("hello")));
+        assert_eq!(client.read_message().unwrap(), None);
+
+        assert!(client.close("test finished"));
+        server.join().unwrap();
+    }
 }